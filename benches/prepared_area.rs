@@ -0,0 +1,41 @@
+//! Point in polygon lookups on a polygon with lots of vertices: the exact `contains` of `geo` against the
+//! prepared geometry of the index. Run with `cargo bench --bench prepared_area`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use geo::algorithm::contains::Contains;
+
+// The binary has no library to import it from. Its tests are left out of the bench, leaving their imports unused.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/geo_finder/prepared_area.rs"]
+mod prepared_area;
+
+use prepared_area::fixtures::{prepare, sample_points, spiky_polygon};
+
+const VERTICES: usize = 200_000;
+const POINTS: usize = 2_000;
+
+fn point_in_polygon(c: &mut Criterion) {
+    let polygon = spiky_polygon(VERTICES);
+    let points = sample_points(POINTS);
+    let prepared = prepare(&polygon);
+    assert_eq!(
+        points.iter().filter(|point| polygon.contains(*point)).count(),
+        points.iter().filter(|point| prepared.contains(point)).count()
+    );
+
+    let mut group = c.benchmark_group("point_in_polygon");
+    group.sample_size(10);
+    group.bench_function("exact", |b| {
+        b.iter(|| points.iter().filter(|point| polygon.contains(black_box(*point))).count())
+    });
+    group.bench_function("prepared", |b| {
+        b.iter(|| points.iter().filter(|point| prepared.contains(black_box(point))).count())
+    });
+    group.bench_function("prepare", |b| {
+        b.iter_batched(|| polygon.clone(), |polygon| prepare(&polygon), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, point_in_polygon);
+criterion_main!(benches);
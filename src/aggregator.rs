@@ -0,0 +1,188 @@
+use super::file_processor::{FileProcessorError, JoinOptions};
+use super::geo_finder::{self, PropertyValue};
+use super::output_writer;
+
+use std::io;
+
+/// Stats of the values of a column in the points of a feature. Values which are not numbers are left out.
+#[derive(Debug, Clone, PartialEq)]
+struct ColumnStats {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl ColumnStats {
+    fn new() -> ColumnStats {
+        ColumnStats { count: 0, sum: 0.0, min: f64::INFINITY, max: f64::NEG_INFINITY }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Sum, mean, min and max. Null without values.
+    fn values(&self) -> [PropertyValue; 4] {
+        if self.count == 0 {
+            return [PropertyValue::Null, PropertyValue::Null, PropertyValue::Null, PropertyValue::Null];
+        }
+        [
+            PropertyValue::Float(self.sum),
+            PropertyValue::Float(self.sum / self.count as f64),
+            PropertyValue::Float(self.min),
+            PropertyValue::Float(self.max),
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FeatureStats {
+    points: u64,
+    columns: Vec<ColumnStats>,
+}
+
+/**
+ * Accumulates the points matched by each feature of the index, and the stats of the numbers of
+ * `JoinOptions::aggregate_columns` in them. Written as a row per feature, also for the ones without points.
+ */
+pub struct Aggregator<'a> {
+    features: Vec<FeatureStats>,
+    output: &'a mut (dyn io::Write + Send),
+}
+
+impl<'a> Aggregator<'a> {
+    pub fn new(
+        geo_finder: &dyn geo_finder::GeoFinder,
+        options: &JoinOptions,
+        output: &'a mut (dyn io::Write + Send),
+    ) -> Aggregator<'a> {
+        let empty = FeatureStats {
+            points: 0,
+            columns: vec![ColumnStats::new(); options.aggregate_columns.len()],
+        };
+        Aggregator { features: vec![empty; geo_finder.feature_count()], output }
+    }
+
+    /// A point in the `features` (`FindResult::feature`), with the `values` of the aggregated columns.
+    pub fn add(&mut self, features: &[usize], values: &[Option<f64>]) {
+        for &feature in features {
+            if feature >= self.features.len() {
+                let empty = FeatureStats { points: 0, columns: vec![ColumnStats::new(); values.len()] };
+                self.features.resize(feature + 1, empty);
+            }
+
+            let stats = &mut self.features[feature];
+            stats.points += 1;
+            for (column, value) in stats.columns.iter_mut().zip(values) {
+                if let Some(value) = value.filter(|value| value.is_finite()) {
+                    column.add(value);
+                }
+            }
+        }
+    }
+
+    /**
+     * The joined properties of each feature, its number of points and the sum, mean, min and max of each
+     * aggregated column. In the order of the files the index was generated from.
+     */
+    pub fn write(self, geo_finder: &dyn geo_finder::GeoFinder, options: &JoinOptions) -> Result<(), FileProcessorError> {
+        let mut columns: Vec<String> = options.properties.iter().map(|property| String::from(*property)).collect();
+        columns.push("point_count".to_owned());
+        for column in &options.aggregate_columns {
+            columns.extend(["sum", "mean", "min", "max"].iter().map(|stat| format!("{}_{}", column, stat)));
+        }
+
+        let mut features: Vec<_> =
+            geo_finder.indexed_features().collect::<Result<_, _>>().map_err(FileProcessorError::Index)?;
+        features.sort_by_key(|feature| feature.id);
+
+        let stats = &self.features;
+        let rows = features.into_iter().map(|feature| {
+            let mut values: Vec<PropertyValue> = options
+                .properties
+                .iter()
+                .map(|property| feature.props.get(*property).cloned().unwrap_or(PropertyValue::Null))
+                .collect();
+
+            let feature_stats = stats.get(feature.id);
+            values.push(PropertyValue::Integer(feature_stats.map_or(0, |stats| stats.points) as i64));
+            for index in 0..options.aggregate_columns.len() {
+                let column_stats = feature_stats.and_then(|stats| stats.columns.get(index)).cloned();
+                values.extend(column_stats.unwrap_or_else(ColumnStats::new).values().iter().cloned());
+            }
+            (move || feature.geometry().map_err(FileProcessorError::Index), values)
+        });
+
+        output_writer::write_feature_rows(options.output_format, options, &columns, rows, self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_processor::MatchMode;
+
+    fn aggregate_options<'a>(properties: Vec<&'a str>, aggregate_columns: Vec<&'a str>) -> JoinOptions<'a> {
+        JoinOptions {
+            delimiter: b',',
+            properties,
+            match_mode: MatchMode::All,
+            aggregate: true,
+            aggregate_columns,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_should_write_every_feature_in_id_order() {
+        let finder = geo_finder::PolygonFinder::new_from_string(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "a"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}},
+                {"type": "Feature", "properties": {"name": "b"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[2, 0], [3, 0], [3, 1], [2, 1], [2, 0]]]}},
+                {"type": "Feature", "properties": {"name": "c"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[4, 0], [5, 0], [5, 1], [4, 1], [4, 0]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let options = aggregate_options(vec!["name"], vec!["amount"]);
+
+        let mut output = Vec::new();
+        let mut aggregator = Aggregator::new(&finder, &options, &mut output);
+        aggregator.add(&[2], &[Some(5.0)]);
+        aggregator.add(&[2, 0], &[Some(1.0)]);
+        aggregator.add(&[2], &[None]);
+        aggregator.add(&[], &[Some(100.0)]);
+        aggregator.write(&finder, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "name,point_count,amount_sum,amount_mean,amount_min,amount_max\n",
+                "a,1,1.0,1.0,1.0,1.0\n",
+                "b,0,,,,\n",
+                "c,3,6.0,3.0,1.0,5.0\n",
+            )
+        );
+    }
+
+    #[test]
+    fn it_should_summarize_the_numbers_only() {
+        let mut stats = ColumnStats::new();
+        assert_eq!(stats.values(), [PropertyValue::Null, PropertyValue::Null, PropertyValue::Null, PropertyValue::Null]);
+
+        for value in &[3.0, 1.0, 8.0] {
+            stats.add(*value);
+        }
+
+        assert_eq!(
+            stats.values(),
+            [PropertyValue::Float(12.0), PropertyValue::Float(4.0), PropertyValue::Float(1.0), PropertyValue::Float(8.0)]
+        );
+    }
+}
@@ -1,4 +1,8 @@
 use indicatif::{ProgressBar, ProgressStyle};
+use std::io;
+
+/// Separates the texts of a JSON text sequence (RFC 7464), like the GeoJSON ones (RFC 8142).
+const RECORD_SEPARATOR: u8 = 0x1e;
 
 fn create_progress_bar_template(
     quiet_mode: bool, 
@@ -33,21 +37,65 @@ fn create_progress_bar_template(
     bar
 }
 pub fn create_progress_bar_bytes(quiet_mode: bool, msg: &str, length: Option<u64>) -> ProgressBar {
-    return create_progress_bar_template(
+    create_progress_bar_template(
         quiet_mode, 
         msg, 
         length,
         "[{elapsed_precise}] {msg} {spinner:.green} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} eta: {eta}",
         "[{elapsed_precise}] {msg} {spinner:.green}"
-    );
+    )
 }
 
 pub fn create_progress_bar_count(quiet_mode: bool, msg: &str, length: Option<u64>) -> ProgressBar {
-    return create_progress_bar_template(
+    create_progress_bar_template(
         quiet_mode, 
         msg, 
         length,
         "[{elapsed_precise}] {msg} {spinner:.green} [{wide_bar:.cyan/blue}] {pos}/{len} eta: {eta}",
         "[{elapsed_precise}] {msg} {spinner:.green}"
-    ); 
-}
\ No newline at end of file
+    )
+}
+/**
+ * Advances the progress bar with the bytes read.
+ */
+pub struct ProgressReader<R> {
+    inner: R,
+    progress_bar: ProgressBar,
+}
+
+impl<R: io::Read> ProgressReader<R> {
+    pub fn new(inner: R, progress_bar: ProgressBar) -> ProgressReader<R> {
+        ProgressReader { inner, progress_bar }
+    }
+}
+
+impl<R: io::Read> io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.progress_bar.inc(count as u64);
+        Ok(count)
+    }
+}
+
+/**
+ * Skips the whitespace and record separators before the next text of a JSON text sequence (RFC 7464). False
+ * at the end of the input.
+ */
+pub fn skip_json_separators<R: io::BufRead>(reader: &mut R) -> io::Result<bool> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(false);
+        }
+
+        let skipped = buffer
+            .iter()
+            .take_while(|&&byte| byte == RECORD_SEPARATOR || byte.is_ascii_whitespace())
+            .count();
+        let found = skipped < buffer.len();
+        reader.consume(skipped);
+        if found {
+            return Ok(true);
+        }
+    }
+}
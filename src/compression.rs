@@ -0,0 +1,221 @@
+use std::io;
+use std::path;
+use std::str::FromStr;
+
+/// How a stream is compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    pub const VALUES: [&'static str; 5] = ["none", "gzip", "zstd", "bzip2", "xz"];
+
+    /// Of a `.gz`, `.zst`, `.bz2` or `.xz` file. `None` for any other extension.
+    pub fn from_path(path: &path::Path) -> Option<Compression> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" => Some(Compression::Bzip2),
+            "xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    /// Of a stream starting with `bytes`, by its magic number.
+    pub fn from_magic(bytes: &[u8]) -> Compression {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if bytes.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Added to the name of the files compressed like this.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+            Compression::Bzip2 => Some("bz2"),
+            Compression::Xz => Some("xz"),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Compression, String> {
+        match value {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            "xz" => Ok(Compression::Xz),
+            other => Err(format!("Invalid compression: {}", other)),
+        }
+    }
+}
+
+/**
+ * Reads `reader` decompressed. The compression is detected by the magic number when not given. Concatenated
+ * streams (like the ones of `cat a.gz b.gz`) are read as one.
+ */
+pub fn decompress<'r, R: io::BufRead + 'r>(
+    mut reader: R,
+    compression: Option<Compression>,
+) -> io::Result<(Compression, Box<dyn io::Read + 'r>)> {
+    let compression = match compression {
+        Some(compression) => compression,
+        None => Compression::from_magic(reader.fill_buf()?),
+    };
+
+    let reader: Box<dyn io::Read + 'r> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+    };
+    Ok((compression, reader))
+}
+
+/**
+ * Writes compressed to the inner writer. `finish` must be called to write the end of the stream.
+ */
+pub enum CompressedWriter<W: io::Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: io::Write> CompressedWriter<W> {
+    /// With the default level of each compression.
+    pub fn new(writer: W, compression: Compression) -> io::Result<CompressedWriter<W>> {
+        let writer = match compression {
+            Compression::None => CompressedWriter::Plain(writer),
+            Compression::Gzip => CompressedWriter::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            Compression::Bzip2 => CompressedWriter::Bzip2(bzip2::write::BzEncoder::new(writer, bzip2::Compression::default())),
+            Compression::Xz => CompressedWriter::Xz(xz2::write::XzEncoder::new(writer, 6)),
+        };
+        Ok(writer)
+    }
+
+    /// Ends the compressed stream and flushes the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = match self {
+            CompressedWriter::Plain(writer) => writer,
+            CompressedWriter::Gzip(encoder) => encoder.finish()?,
+            CompressedWriter::Zstd(encoder) => encoder.finish()?,
+            CompressedWriter::Bzip2(encoder) => encoder.finish()?,
+            CompressedWriter::Xz(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn inner(&mut self) -> &mut dyn io::Write {
+        match self {
+            CompressedWriter::Plain(writer) => writer,
+            CompressedWriter::Gzip(encoder) => encoder,
+            CompressedWriter::Zstd(encoder) => encoder,
+            CompressedWriter::Bzip2(encoder) => encoder,
+            CompressedWriter::Xz(encoder) => encoder,
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn it_should_detect_the_compression_of_what_it_writes() {
+        let text = "id,lat,lon\n1,19.43,-99.13\n".repeat(100);
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd, Compression::Bzip2, Compression::Xz] {
+            let mut writer = CompressedWriter::new(Vec::new(), compression).unwrap();
+            writer.write_all(text.as_bytes()).unwrap();
+            let compressed = writer.finish().unwrap();
+
+            let (detected, mut reader) = decompress(compressed.as_slice(), None).unwrap();
+            let mut decompressed = String::new();
+            reader.read_to_string(&mut decompressed).unwrap();
+
+            assert_eq!(detected, compression);
+            assert_eq!(decompressed, text);
+        }
+    }
+
+    #[test]
+    fn it_should_read_concatenated_streams() {
+        let mut compressed = Vec::new();
+        for part in &["a,b\n", "c,d\n"] {
+            let mut writer = CompressedWriter::new(Vec::new(), Compression::Gzip).unwrap();
+            writer.write_all(part.as_bytes()).unwrap();
+            compressed.extend(writer.finish().unwrap());
+        }
+
+        let (_, mut reader) = decompress(compressed.as_slice(), Some(Compression::Gzip)).unwrap();
+        let mut decompressed = String::new();
+        reader.read_to_string(&mut decompressed).unwrap();
+
+        assert_eq!(decompressed, "a,b\nc,d\n");
+    }
+
+    #[test]
+    fn it_should_know_the_extensions() {
+        let compressions: Vec<_> = ["points.csv.gz", "points.tsv.ZST", "points.bz2", "points.ndjson.xz", "points.csv", "points"]
+            .iter()
+            .map(|name| Compression::from_path(path::Path::new(name)))
+            .collect();
+
+        assert_eq!(
+            compressions,
+            vec![
+                Some(Compression::Gzip),
+                Some(Compression::Zstd),
+                Some(Compression::Bzip2),
+                Some(Compression::Xz),
+                None,
+                None
+            ]
+        );
+    }
+}
@@ -1,19 +1,102 @@
+// The `Fail` derive of the errors expands to impls inside a const block.
+#![allow(non_local_definitions)]
+
+use super::aggregator;
 use super::cli_utils;
+use super::compression;
 use super::geo_finder;
+use super::json_input;
+use super::output_writer;
+use super::parquet_input;
+use super::partition_writer;
 
-use csv;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
+use std::str::FromStr;
 use std::time;
 
+use indicatif::ProgressBar;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 use log::{info, warn};
+use rayon::prelude::*;
 
 use failure::Fail;
 // use std::error::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ProcessStats {
+    /// Rows read, also the ones which could not be parsed.
     pub total_lines: u32,
+    /// Rows not joined, for any of the reasons below.
     pub error_lines: u32,
+    pub matched_lines: u32,
+    /// Valid coordinates, out of every feature.
+    pub not_found_lines: u32,
+    pub invalid_coordinates_lines: u32,
+    /// Matched a feature without a joined property (with `MissingPropertyPolicy::Error`).
+    pub missing_property_lines: u32,
+    /// Could not be parsed, like invalid CSV or JSON.
+    pub parse_error_lines: u32,
+    /// Points in each feature (`FindResult::feature`) with any.
+    pub feature_hits: HashMap<usize, u64>,
+    /// [min_x, min_y, max_x, max_y] of the points with valid coordinates.
+    pub bbox: Option<[f64; 4]>,
+    pub elapsed_secs: f64,
+}
+
+impl ProcessStats {
+    /// A record read, matching the `features`, or not joined because of `error`.
+    fn add_record(&mut self, coordinates: Option<(f64, f64)>, features: &[usize], error: Option<RecordError>) {
+        self.total_lines += 1;
+        if let Some((latitude, longitude)) = coordinates {
+            self.bbox = Some(match self.bbox {
+                Some([min_x, min_y, max_x, max_y]) => {
+                    [min_x.min(longitude), min_y.min(latitude), max_x.max(longitude), max_y.max(latitude)]
+                }
+                None => [longitude, latitude, longitude, latitude],
+            });
+        }
+        for feature in features {
+            *self.feature_hits.entry(*feature).or_insert(0) += 1;
+        }
+
+        match error {
+            None => self.matched_lines += 1,
+            Some(error) => {
+                self.error_lines += 1;
+                match error {
+                    RecordError::InvalidCoordinates => self.invalid_coordinates_lines += 1,
+                    RecordError::NotFound => self.not_found_lines += 1,
+                    RecordError::MissingProperty => self.missing_property_lines += 1,
+                }
+            }
+        }
+    }
+
+    /// A row which could not be parsed.
+    fn add_parse_error(&mut self) {
+        self.total_lines += 1;
+        self.error_lines += 1;
+        self.parse_error_lines += 1;
+    }
+
+    /// The `count` features with the most points, with their points. The first ones in the index when tied.
+    pub fn top_features(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut hits: Vec<(usize, u64)> = self.feature_hits.iter().map(|(feature, points)| (*feature, *points)).collect();
+        hits.sort_by(|(feature_a, points_a), (feature_b, points_b)| points_b.cmp(points_a).then(feature_a.cmp(feature_b)));
+        hits.truncate(count);
+        hits
+    }
+}
+
+/// Why a record can't be joined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RecordError {
+    InvalidCoordinates,
+    NotFound,
+    MissingProperty,
 }
 
 #[allow(dead_code)]
@@ -25,42 +108,1031 @@ pub enum FileProcessorError {
     Io(io::Error),
     #[fail(display = "Csv error: {}", _0)]
     Csv(csv::Error),
+    #[fail(display = "Unable to start the worker threads: {}", _0)]
+    ThreadPool(rayon::ThreadPoolBuildError),
+    #[fail(display = "Unable to find the {} column. Header: {}", _0, _1)]
+    ColumnNotFound(String, String),
+    /// With the number of the record (1 based, not counting the header), which is not its line in the input.
+    #[fail(display = "Property '{}' not found in the feature matched by record {}", _0, _1)]
+    MissingProperty(String, usize),
+    #[fail(display = "Invalid JSON input: {}", _0)]
+    Json(serde_json::Error),
+    #[fail(display = "{} input can't be written as {}, choose another output format", _0, _1)]
+    UnsupportedOutput(String, String),
+    #[fail(display = "Parquet error: {}", _0)]
+    Parquet(parquet::errors::ParquetError),
+    #[fail(display = "Arrow error: {}", _0)]
+    Arrow(arrow_schema::ArrowError),
+    #[fail(display = "Parquet input must be a file, not stdin")]
+    ParquetFromStream,
+    #[fail(display = "Parquet input can't be {} compressed, its columns are compressed by themselves", _0)]
+    CompressedParquet(String),
+    #[fail(display = "{}", _0)]
+    Index(geo_finder::IndexFileError),
 }
 
+impl FileProcessorError {
+    /// Whether the reader of the output went away (as `head` does): there is no point in writing more.
+    fn is_broken_pipe(&self) -> bool {
+        let io_error = match self {
+            FileProcessorError::Io(err) => Some(err),
+            FileProcessorError::Csv(err) => match err.kind() {
+                csv::ErrorKind::Io(err) => Some(err),
+                _ => None,
+            },
+            _ => None,
+        };
+        io_error.map(|err| err.kind() == io::ErrorKind::BrokenPipe).unwrap_or(false)
+    }
+}
+
+impl From<serde_json::Error> for FileProcessorError {
+    fn from(err: serde_json::Error) -> FileProcessorError {
+        match err.classify() {
+            serde_json::error::Category::Io => FileProcessorError::Io(err.into()),
+            _ => FileProcessorError::Json(err),
+        }
+    }
+}
+
+/// Records read (and looked up in parallel) at once.
+const CHUNK_SIZE: usize = 8192;
+
+/// Which of the features containing a point are joined to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// The first feature found (the fastest).
+    First,
+    /// Every feature containing the point.
+    All,
+    /// The feature with the smallest area.
+    Smallest,
+    /// The feature with the largest area.
+    Largest,
+}
+
+impl MatchMode {
+    pub const VALUES: [&'static str; 4] = ["first", "all", "smallest", "largest"];
+}
+
+impl FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<MatchMode, String> {
+        match value {
+            "first" => Ok(MatchMode::First),
+            "all" => Ok(MatchMode::All),
+            "smallest" => Ok(MatchMode::Smallest),
+            "largest" => Ok(MatchMode::Largest),
+            other => Err(format!("Invalid match mode: {}", other)),
+        }
+    }
+}
+
+/// How the input records are read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// Delimited text, with the coordinates in two columns.
+    Csv,
+    /// A JSON object per line.
+    NdJson,
+    /// GeoJSON features: a FeatureCollection, or a sequence of features (one per line or RFC 8142).
+    GeoJson,
+    /// Apache Parquet, with the coordinates in two columns.
+    Parquet,
+}
+
+impl InputFormat {
+    pub const VALUES: [&'static str; 4] = ["csv", "ndjson", "geojson", "parquet"];
+
+    /// The output format when none is given: the same records.
+    pub fn default_output_format(self) -> output_writer::OutputFormat {
+        match self {
+            InputFormat::Csv => output_writer::OutputFormat::Csv,
+            InputFormat::NdJson => output_writer::OutputFormat::NdJson,
+            InputFormat::GeoJson => output_writer::OutputFormat::GeoJson,
+            InputFormat::Parquet => output_writer::OutputFormat::Parquet,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<InputFormat, String> {
+        match value {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::NdJson),
+            "geojson" => Ok(InputFormat::GeoJson),
+            "parquet" => Ok(InputFormat::Parquet),
+            other => Err(format!("Invalid input format: {}", other)),
+        }
+    }
+}
+
+/// Where the input is read from.
+pub enum JoinInput<'i> {
+    File(fs::File),
+    /// Like stdin. Can't be Parquet, which is read from the end.
+    Stream(&'i mut dyn io::Read),
+}
+
+/// How to find a coordinate column in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnSelector<'a> {
+    /// 0 based column number.
+    Index(usize),
+    /// Column name in the header.
+    Name(&'a str),
+    /// Look for a commonly used name in the header.
+    Auto,
+}
+
+pub const LATITUDE_NAMES: [&str; 3] = ["lat", "latitude", "y"];
+pub const LONGITUDE_NAMES: [&str; 5] = ["lon", "lng", "long", "longitude", "x"];
+
+/// What to do when a matched feature does not have a requested property.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingPropertyPolicy {
+    /// Leave the value empty.
+    Empty,
+    /// Write the record as an error, without rows for any of its matches.
+    Error,
+    /// Stop processing.
+    Fail,
+}
+
+impl MissingPropertyPolicy {
+    pub const VALUES: [&'static str; 3] = ["empty", "error", "fail"];
+}
+
+impl FromStr for MissingPropertyPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<MissingPropertyPolicy, String> {
+        match value {
+            "empty" => Ok(MissingPropertyPolicy::Empty),
+            "error" => Ok(MissingPropertyPolicy::Error),
+            "fail" => Ok(MissingPropertyPolicy::Fail),
+            other => Err(format!("Invalid missing property policy: {}", other)),
+        }
+    }
+}
+
+/// What to do with the records which can't be joined: without valid coordinates, not in any feature or
+/// missing a property.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmatchedPolicy {
+    /// Write them with the others, as error rows.
+    Keep,
+    /// Write them to the rejects output, with the reason.
+    Reject,
+    /// Leave them out.
+    Drop,
+}
+
+/// How arrays and objects in the properties are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NestedValuePolicy {
+    /// As JSON text.
+    Json,
+    /// As an empty value.
+    Empty,
+}
+
+impl NestedValuePolicy {
+    pub const VALUES: [&'static str; 2] = ["json", "empty"];
+}
+
+impl FromStr for NestedValuePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<NestedValuePolicy, String> {
+        match value {
+            "json" => Ok(NestedValuePolicy::Json),
+            "empty" => Ok(NestedValuePolicy::Empty),
+            other => Err(format!("Invalid nested value policy: {}", other)),
+        }
+    }
+}
+
+pub struct JoinOptions<'a> {
+    pub delimiter: u8,
+    pub latitude: ColumnSelector<'a>,
+    pub longitude: ColumnSelector<'a>,
+    pub properties: Vec<&'a str>,
+    pub no_header: bool,
+    pub match_mode: MatchMode,
+    /// With `MatchMode::All`, join the values of every match into one row using this separator,
+    /// instead of writing one row per match.
+    pub match_separator: Option<&'a str>,
+    /// When a point is not inside any feature, join the nearest one within this distance (in meters).
+    /// Also adds the `match_type` and `distance_m` columns to the output.
+    pub max_distance: Option<f64>,
+    /// Threads doing the lookups (0 uses every core).
+    pub threads: usize,
+    pub missing_property: MissingPropertyPolicy,
+    /// Text written for null properties.
+    pub null_value: &'a str,
+    pub nested_values: NestedValuePolicy,
+    pub input_format: InputFormat,
+    /// JSON pointers to the coordinates of the JSON records. By default, the coordinates of the Point features
+    /// or a member with a commonly used name.
+    pub latitude_pointer: Option<&'a str>,
+    pub longitude_pointer: Option<&'a str>,
+    pub output_format: output_writer::OutputFormat,
+    /// How the input is compressed. Detected by its magic number when `None`.
+    pub input_compression: Option<compression::Compression>,
+    pub unmatched: UnmatchedPolicy,
+    /// Write a file per value of a property of the matched features instead of a single output.
+    pub partition: Option<partition_writer::PartitionOptions<'a>>,
+    /// Instead of the records, write a row per feature with the number of points in it (see `aggregator`).
+    pub aggregate: bool,
+    /// Numeric input columns summarized in each feature: the names of the columns, or pointers for JSON.
+    pub aggregate_columns: Vec<&'a str>,
+}
+
+/// The defaults of the command line options.
+impl<'a> Default for JoinOptions<'a> {
+    fn default() -> JoinOptions<'a> {
+        JoinOptions {
+            delimiter: b'\t',
+            latitude: ColumnSelector::Auto,
+            longitude: ColumnSelector::Auto,
+            properties: Vec::new(),
+            no_header: false,
+            match_mode: MatchMode::First,
+            match_separator: None,
+            max_distance: None,
+            threads: 1,
+            missing_property: MissingPropertyPolicy::Error,
+            null_value: "",
+            nested_values: NestedValuePolicy::Json,
+            input_format: InputFormat::Csv,
+            latitude_pointer: None,
+            longitude_pointer: None,
+            output_format: output_writer::OutputFormat::Csv,
+            input_compression: None,
+            unmatched: UnmatchedPolicy::Keep,
+            partition: None,
+            aggregate: false,
+            aggregate_columns: Vec::new(),
+        }
+    }
+}
+
+/**
+ * Resolves the column number of a selector. Names are looked up in the header, ignoring case.
+ */
+fn resolve_column(
+    selector: &ColumnSelector,
+    header: Option<&csv::StringRecord>,
+    auto_names: &[&str],
+    column_description: &str,
+) -> Result<usize, FileProcessorError> {
+    let find_in_header = |name: &str| {
+        header.and_then(|header| header.iter().position(|column| column.trim().eq_ignore_ascii_case(name)))
+    };
+
+    let position = match selector {
+        ColumnSelector::Index(idx) => return Ok(*idx),
+        ColumnSelector::Name(name) => find_in_header(name),
+        ColumnSelector::Auto => auto_names.iter().filter_map(|name| find_in_header(name)).next(),
+    };
+
+    position.ok_or_else(|| {
+        let header_description = match header {
+            Some(header) => format!("{:?}", header.iter().collect::<Vec<_>>()),
+            None => "<none>".to_owned(),
+        };
+        let description = match selector {
+            ColumnSelector::Name(name) => format!("{} ('{}')", column_description, name),
+            _ => format!("{} (tried {:?})", column_description, auto_names),
+        };
+        FileProcessorError::ColumnNotFound(description, header_description)
+    })
+}
+
+/**
+ * The column numbers of the aggregated columns, found by name in the header.
+ */
+fn aggregate_column_idxs(options: &JoinOptions, header: Option<&csv::StringRecord>) -> Result<Vec<usize>, FileProcessorError> {
+    options
+        .aggregate_columns
+        .iter()
+        .map(|column| resolve_column(&ColumnSelector::Name(column), header, &[], "aggregated"))
+        .collect()
+}
+
+fn find_matches(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    latitude: f64,
+    longitude: f64,
+    match_mode: MatchMode,
+    max_distance: Option<f64>,
+) -> Result<Vec<geo_finder::FindResult<'_>>, FileProcessorError> {
+    let matches: Vec<_> = match match_mode {
+        MatchMode::First => geo_finder
+            .find(latitude, longitude)
+            .map_err(FileProcessorError::Index)?
+            .into_iter()
+            .map(|result| *result)
+            .collect(),
+        MatchMode::All => geo_finder.find_all(latitude, longitude).map_err(FileProcessorError::Index)?,
+        MatchMode::Smallest => geo_finder
+            .find_all(latitude, longitude)
+            .map_err(FileProcessorError::Index)?
+            .into_iter()
+            .min_by(|a, b| a.area.total_cmp(&b.area))
+            .into_iter()
+            .collect(),
+        MatchMode::Largest => geo_finder
+            .find_all(latitude, longitude)
+            .map_err(FileProcessorError::Index)?
+            .into_iter()
+            .max_by(|a, b| a.area.total_cmp(&b.area))
+            .into_iter()
+            .collect(),
+    };
+
+    match (matches.is_empty(), max_distance) {
+        (true, Some(max_distance)) => Ok(geo_finder
+            .find_nearest(latitude, longitude, max_distance)
+            .map_err(FileProcessorError::Index)?
+            .into_iter()
+            .map(|result| *result)
+            .collect()),
+        _ => Ok(matches),
+    }
+}
+
+/**
+ * Text of a property value for the output.
+ */
+#[inline]
+pub fn render_value<'v>(value: &'v geo_finder::PropertyValue, options: &JoinOptions<'v>) -> Cow<'v, str> {
+    use geo_finder::PropertyValue;
+
+    match value {
+        PropertyValue::String(value) => Cow::Borrowed(value),
+        PropertyValue::Null => Cow::Borrowed(options.null_value),
+        PropertyValue::Bool(value) => Cow::Owned(value.to_string()),
+        PropertyValue::Integer(value) => Cow::Owned(value.to_string()),
+        PropertyValue::UInteger(value) => Cow::Owned(value.to_string()),
+        // Same format as the JSON numbers.
+        PropertyValue::Float(value) => match serde_json::Number::from_f64(*value) {
+            Some(number) => Cow::Owned(number.to_string()),
+            None => Cow::Borrowed(""),
+        },
+        PropertyValue::Json(json) => match options.nested_values {
+            NestedValuePolicy::Json => Cow::Borrowed(json),
+            NestedValuePolicy::Empty => Cow::Borrowed(""),
+        },
+    }
+}
+
+/// A value joined to an input record.
+pub enum JoinedValue<'v> {
+    /// Nothing joined: the record is an error or the matched feature does not have the property.
+    Empty,
+    Text(Cow<'v, str>),
+    Property(&'v geo_finder::PropertyValue),
+    /// In meters.
+    Distance(f64),
+}
+
+/**
+ * An input record, to which the joined columns are appended.
+ */
+pub trait JoinRow: Clone + Send + Sync {
+    fn push_joined(&mut self, column: &str, value: JoinedValue, options: &JoinOptions);
+}
+
+impl JoinRow for csv::StringRecord {
+    fn push_joined(&mut self, _column: &str, value: JoinedValue, options: &JoinOptions) {
+        match value {
+            JoinedValue::Empty => self.push_field(""),
+            JoinedValue::Text(text) => self.push_field(&text),
+            JoinedValue::Property(value) => self.push_field(&render_value(value, options)),
+            JoinedValue::Distance(distance) => self.push_field(&format!("{:.1}", distance)),
+        }
+    }
+}
+
+/**
+ * The columns appended to the input records.
+ */
+fn joined_columns(options: &JoinOptions) -> Vec<String> {
+    let mut columns: Vec<String> = options.properties.iter().map(|property| String::from(*property)).collect();
+    if options.max_distance.is_some() {
+        columns.push("match_type".to_owned());
+        columns.push("distance_m".to_owned());
+    }
+    columns.push("status".to_owned());
+    columns.push("error_message".to_owned());
+    columns
+}
+
+/**
+ * Appends the joined columns. Fails with the name of the first property missing in a match, unless the
+ * policy is to leave it empty.
+ */
 #[inline]
-fn record_size(record: &csv::StringRecord) -> u64 {
-    use std::convert::TryInto;
-    let size: usize = record.iter().map(|e| e.len()).sum();
-    return size.try_into().unwrap();;
+fn fill_success_row<'p, R: JoinRow>(
+    options: &JoinOptions<'p>,
+    matches: &[geo_finder::FindResult],
+    new_record: &mut R,
+) -> Result<(), &'p str> {
+    let separator = options.match_separator.unwrap_or_default();
+    for prop in &options.properties {
+        let values: Result<Vec<JoinedValue>, &str> = matches
+            .iter()
+            .map(|find_result| match find_result.props.get(*prop) {
+                Some(value) => Ok(JoinedValue::Property(value)),
+                None if options.missing_property == MissingPropertyPolicy::Empty => Ok(JoinedValue::Empty),
+                None => Err(*prop),
+            })
+            .collect();
+        let mut values = values?;
+
+        let value = match values.len() {
+            1 => values.remove(0),
+            // Every match in one row.
+            _ => {
+                let texts: Vec<Cow<str>> = values
+                    .iter()
+                    .map(|value| match value {
+                        JoinedValue::Property(value) => render_value(value, options),
+                        _ => Cow::Borrowed(""),
+                    })
+                    .collect();
+                JoinedValue::Text(Cow::Owned(texts.join(separator)))
+            }
+        };
+        new_record.push_joined(prop, value, options);
+    }
+
+    if options.max_distance.is_some() {
+        // Several matches only happen when the point is inside all of them.
+        let find_result = &matches[0];
+        new_record.push_joined("match_type", JoinedValue::Text(Cow::Borrowed(find_result.match_type.as_str())), options);
+        new_record.push_joined("distance_m", JoinedValue::Distance(find_result.distance), options);
+    }
+
+    new_record.push_joined("status", JoinedValue::Text(Cow::Borrowed("success")), options);
+    new_record.push_joined("error_message", JoinedValue::Empty, options);
+    Ok(())
 }
 
 #[inline]
-fn fill_error_row(
-    properties: &Vec<&str>,
+fn fill_error_row<R: JoinRow>(
+    options: &JoinOptions,
     err_message: &str,
-    new_record: &mut csv::StringRecord,
+    new_record: &mut R,
 ) {
-    for _ in 0..properties.len() {
-        new_record.push_field("");
+    for prop in &options.properties {
+        new_record.push_joined(prop, JoinedValue::Empty, options);
+    }
+    if options.max_distance.is_some() {
+        new_record.push_joined("match_type", JoinedValue::Empty, options);
+        new_record.push_joined("distance_m", JoinedValue::Empty, options);
+    }
+    new_record.push_joined("status", JoinedValue::Text(Cow::Borrowed("error")), options);
+    new_record.push_joined("error_message", JoinedValue::Text(Cow::Borrowed(err_message)), options);
+}
+
+
+/// Output rows for one input record.
+struct JoinedRecord<R> {
+    new_records: Vec<R>,
+    /// Rows of the records which can't be joined, for the rejects output.
+    rejects: Vec<R>,
+    /// When partitioning, the partition value of each new record. None for the error rows.
+    partitions: Vec<Option<String>>,
+    /// (latitude, longitude), when they are valid.
+    coordinates: Option<(f64, f64)>,
+    /// Matched by the point.
+    features: Vec<usize>,
+    error: Option<RecordError>,
+    missing_property: Option<String>,
+}
+
+impl<R: JoinRow> JoinedRecord<R> {
+    /**
+     * The row of a record which can't be joined, as told by the unmatched policy: an error row, or the record and
+     * the reason for the rejects.
+     */
+    fn push_error(&mut self, options: &JoinOptions, record: &R, error: RecordError, err_message: &str) {
+        self.error = Some(error);
+        match options.unmatched {
+            UnmatchedPolicy::Keep => {
+                let mut new_record = record.clone();
+                fill_error_row(options, err_message, &mut new_record);
+                self.new_records.push(new_record);
+                if options.partition.is_some() {
+                    self.partitions.push(None);
+                }
+            }
+            UnmatchedPolicy::Reject => {
+                let mut reject = record.clone();
+                reject.push_joined("reason", JoinedValue::Text(Cow::Borrowed(err_message)), options);
+                self.rejects.push(reject);
+            }
+            UnmatchedPolicy::Drop => {}
+        }
+    }
+}
+
+/// The value of the partition property in the first of the matches. Empty without it.
+fn partition_value(options: &JoinOptions, property: &str, matches: &[geo_finder::FindResult]) -> String {
+    match matches[0].props.get(property) {
+        Some(value) => render_value(value, options).into_owned(),
+        None => String::new(),
+    }
+}
+
+/**
+ * The (latitude, longitude) of a record when both are finite numbers in range: latitudes in [-90, 90] and
+ * longitudes in [-180, 180].
+ */
+fn valid_coordinates(coordinates: (Option<f64>, Option<f64>)) -> Option<(f64, f64)> {
+    let (latitude, longitude) = (coordinates.0?, coordinates.1?);
+    match (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) {
+        true => Some((latitude, longitude)),
+        false => None,
+    }
+}
+
+fn join_record<R: JoinRow>(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    options: &JoinOptions,
+    record: &R,
+    coordinates: (Option<f64>, Option<f64>),
+) -> Result<JoinedRecord<R>, FileProcessorError> {
+    let (latitude_opt, longitude_opt) = coordinates;
+    let mut joined = JoinedRecord {
+        new_records: Vec::with_capacity(1),
+        rejects: Vec::new(),
+        partitions: Vec::new(),
+        coordinates: valid_coordinates(coordinates),
+        features: Vec::new(),
+        error: None,
+        missing_property: None,
+    };
+
+    match joined.coordinates {
+        Some((latitude, longitude)) => {
+            let matches = find_matches(
+                geo_finder,
+                latitude,
+                longitude,
+                options.match_mode,
+                options.max_distance,
+            )?;
+
+            if matches.is_empty() {
+                joined.push_error(
+                    options,
+                    record,
+                    RecordError::NotFound,
+                    &format!("COORDINATES_NOT_FOUND: {:?}", (latitude, longitude)),
+                );
+            } else {
+                joined.features = matches.iter().map(|find_result| find_result.feature).collect();
+                // One row per match, or every match in a single row.
+                let rows_matches: Vec<&[geo_finder::FindResult]> =
+                    match options.match_mode == MatchMode::All && options.match_separator.is_none() {
+                        true => matches.chunks(1).collect(),
+                        false => vec![&matches],
+                    };
+
+                for row_matches in rows_matches {
+                    let mut new_record = record.clone();
+                    match fill_success_row(options, row_matches, &mut new_record) {
+                        Ok(()) => {
+                            joined.new_records.push(new_record);
+                            if let Some(partition) = &options.partition {
+                                joined.partitions.push(Some(partition_value(options, partition.property, row_matches)));
+                            }
+                        }
+                        Err(property) => {
+                            // The whole record is an error, not only the row of this match.
+                            joined.new_records.clear();
+                            joined.partitions.clear();
+                            joined.missing_property = Some(property.to_owned());
+                            joined.push_error(
+                                options,
+                                record,
+                                RecordError::MissingProperty,
+                                &format!("MISSING_PROPERTY: {}", property),
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        None => {
+            joined.push_error(
+                options,
+                record,
+                RecordError::InvalidCoordinates,
+                &format!("INVALID_COORDINATES: {:?}", (latitude_opt, longitude_opt)),
+            );
+        }
+    }
+
+    Ok(joined)
+}
+
+/// A record read from the input.
+pub struct InputRecord<R> {
+    pub record: R,
+    /// (latitude, longitude), when they are numbers.
+    pub coordinates: (Option<f64>, Option<f64>),
+    /// Bytes read, for the progress bar. 0 when the reader counts them.
+    pub size: u64,
+    /// Of the aggregated columns, when they are numbers.
+    pub values: Vec<Option<f64>>,
+}
+
+type BoxedRecordWriter<'j, R> = Box<dyn output_writer::RecordWriter<R> + 'j>;
+
+/// Where the joined (and error) rows go.
+enum RecordsOutput<'j, R> {
+    Single(BoxedRecordWriter<'j, R>),
+    Partitioned(partition_writer::PartitionWriter<'j, R>),
+}
+
+impl<'j, R> RecordsOutput<'j, R> {
+    /// A new record of a joined record, whose partition value is `partition`.
+    fn write_record(
+        &mut self,
+        record: &R,
+        partition: Option<&str>,
+        coordinates: Option<(f64, f64)>,
+    ) -> Result<(), FileProcessorError> {
+        match self {
+            RecordsOutput::Single(record_writer) => record_writer.write_record(record, coordinates),
+            RecordsOutput::Partitioned(partition_writer) => partition_writer.write_record(partition, record, coordinates),
+        }
     }
-    new_record.push_field("error"); // Status
-    new_record.push_field(err_message); // Error message.
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        match self {
+            RecordsOutput::Single(record_writer) => record_writer.finish(),
+            RecordsOutput::Partitioned(partition_writer) => partition_writer.finish(),
+        }
+    }
+}
+
+/// Where the joined records go.
+enum JoinOutput<'j, R> {
+    Records {
+        writer: RecordsOutput<'j, R>,
+        /// For the records which can't be joined, with the `UnmatchedPolicy::Reject` policy.
+        rejects: Option<BoxedRecordWriter<'j, R>>,
+    },
+    Aggregate(aggregator::Aggregator<'j>),
+}
+
+/**
+ * Aggregates the records, or writes them with the writers made by `record_writer` for an output (the partitions
+ * too) and the columns appended to the records in it.
+ */
+fn join_output<'j, 'r: 'j, R: 'j, F>(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    options: &JoinOptions,
+    output_file: &'j mut (dyn io::Write + Send),
+    rejects_file: Option<&'j mut (dyn io::Write + Send + 'r)>,
+    record_writer: F,
+) -> Result<JoinOutput<'j, R>, FileProcessorError>
+where
+    F: Fn(Box<dyn io::Write + Send + 'j>, &[String]) -> Result<BoxedRecordWriter<'j, R>, FileProcessorError> + 'j,
+{
+    if options.aggregate {
+        output_writer::check_feature_rows_format(options.output_format)?;
+        return Ok(JoinOutput::Aggregate(aggregator::Aggregator::new(geo_finder, options, output_file)));
+    }
+
+    let rejects = match (options.unmatched, rejects_file) {
+        (UnmatchedPolicy::Reject, Some(rejects_file)) => {
+            Some(record_writer(Box::new(rejects_file), &["reason".to_owned()])?)
+        }
+        _ => None,
+    };
+    let writer = match &options.partition {
+        Some(partition) => RecordsOutput::Partitioned(partition_writer::PartitionWriter::new(
+            partition,
+            options,
+            joined_columns(options),
+            Box::new(record_writer),
+        )?),
+        None => RecordsOutput::Single(record_writer(Box::new(output_file), &joined_columns(options))?),
+    };
+    Ok(JoinOutput::Records { writer, rejects })
+}
+
+/**
+ * Joins the records pushed to it. They are looked up in parallel, a chunk at a time, and written back in the
+ * same order.
+ */
+struct Joiner<'j, R> {
+    geo_finder: &'j dyn geo_finder::GeoFinder,
+    options: &'j JoinOptions<'j>,
+    pool: rayon::ThreadPool,
+    output: JoinOutput<'j, R>,
+    progress_bar: ProgressBar,
+    /// Read and not joined yet. Or why they could not be read.
+    chunk: Vec<Result<InputRecord<R>, String>>,
+    stats: ProcessStats,
+    /// The output was closed by its reader.
+    closed: bool,
+    start_instant: time::Instant,
 }
 
+impl<'j, R: JoinRow> Joiner<'j, R> {
+    fn new(
+        geo_finder: &'j dyn geo_finder::GeoFinder,
+        options: &'j JoinOptions<'j>,
+        output: JoinOutput<'j, R>,
+        progress_bar: ProgressBar,
+    ) -> Result<Joiner<'j, R>, FileProcessorError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+            .map_err(FileProcessorError::ThreadPool)?;
+
+        Ok(Joiner {
+            geo_finder,
+            options,
+            pool,
+            output,
+            progress_bar,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            stats: ProcessStats::default(),
+            closed: false,
+            start_instant: time::Instant::now(),
+        })
+    }
+
+    /**
+     * False when the output can't be written anymore, so there is no point in reading more.
+     */
+    fn push(&mut self, input: Result<InputRecord<R>, String>) -> Result<bool, FileProcessorError> {
+        self.chunk.push(input);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.join_chunk()?;
+        }
+        Ok(!self.closed)
+    }
+
+    fn join_chunk(&mut self) -> Result<(), FileProcessorError> {
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        match self.output {
+            JoinOutput::Records { .. } => self.write_chunk(chunk),
+            JoinOutput::Aggregate(_) => self.aggregate_chunk(chunk),
+        }
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<Result<InputRecord<R>, String>>) -> Result<(), FileProcessorError> {
+        let (geo_finder, options) = (self.geo_finder, self.options);
+        let (record_writer, rejects_writer) = match &mut self.output {
+            JoinOutput::Records { writer, rejects } => (writer, rejects),
+            JoinOutput::Aggregate(_) => unreachable!(),
+        };
+
+        let joined_chunk: Result<Vec<Option<JoinedRecord<R>>>, _> = self.pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|input| {
+                    input
+                        .as_ref()
+                        .ok()
+                        .map(|input| join_record(geo_finder, options, &input.record, input.coordinates))
+                        .transpose()
+                })
+                .collect()
+        });
+        let joined_chunk = match joined_chunk {
+            Ok(joined_chunk) => joined_chunk,
+            Err(err) => {
+                self.progress_bar.finish();
+                return Err(err);
+            }
+        };
+
+        for (input, joined) in chunk.iter().zip(joined_chunk) {
+            if self.closed {
+                break;
+            }
+            match (input, joined) {
+                (Ok(input), Some(joined)) => {
+                    self.stats.add_record(joined.coordinates, &joined.features, joined.error);
+
+                    if let (Some(property), MissingPropertyPolicy::Fail) = (&joined.missing_property, options.missing_property) {
+                        record_writer.finish().ok();
+                        if let Some(rejects_writer) = rejects_writer {
+                            rejects_writer.finish().ok();
+                        }
+                        self.progress_bar.finish();
+                        return Err(FileProcessorError::MissingProperty(property.clone(), self.stats.total_lines as usize));
+                    }
+
+                    let mut write_result = joined
+                        .new_records
+                        .iter()
+                        .enumerate()
+                        .try_for_each(|(idx, new_record)| {
+                            let partition = joined.partitions.get(idx).and_then(Option::as_deref);
+                            record_writer.write_record(new_record, partition, joined.coordinates)
+                        });
+                    if let (Ok(()), Some(rejects_writer)) = (&write_result, rejects_writer.as_mut()) {
+                        write_result = joined
+                            .rejects
+                            .iter()
+                            .try_for_each(|reject| rejects_writer.write_record(reject, joined.coordinates));
+                    }
+
+                    self.progress_bar.inc(input.size);
+
+                    match write_result {
+                        Ok(()) => {}
+                        Err(ref err) if err.is_broken_pipe() => self.closed = true,
+                        Err(err) => {
+                            self.progress_bar.finish();
+                            return Err(err);
+                        }
+                    }
+                }
+                (Err(e), _) => {
+                    self.stats.add_parse_error();
+                    warn!("Unable to read line {}: {}", self.stats.total_lines, e);
+                }
+                (Ok(_), None) => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+
+    /**
+     * Adds the points to the features they are in. The records themselves are not needed anymore.
+     */
+    fn aggregate_chunk(&mut self, chunk: Vec<Result<InputRecord<R>, String>>) -> Result<(), FileProcessorError> {
+        let (geo_finder, options) = (self.geo_finder, self.options);
+        let aggregator = match &mut self.output {
+            JoinOutput::Aggregate(aggregator) => aggregator,
+            JoinOutput::Records { .. } => unreachable!(),
+        };
+
+        let features_chunk: Result<Vec<Vec<usize>>, _> = self.pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|input| match input.as_ref().ok().and_then(|input| valid_coordinates(input.coordinates)) {
+                    Some((latitude, longitude)) => {
+                        find_matches(geo_finder, latitude, longitude, options.match_mode, options.max_distance)
+                            .map(|matches| matches.iter().map(|find_result| find_result.feature).collect())
+                    }
+                    None => Ok(Vec::new()),
+                })
+                .collect()
+        });
+        let features_chunk = match features_chunk {
+            Ok(features_chunk) => features_chunk,
+            Err(err) => {
+                self.progress_bar.finish();
+                return Err(err);
+            }
+        };
+
+        for (input, features) in chunk.iter().zip(features_chunk) {
+            match input {
+                Ok(input) => {
+                    let coordinates = valid_coordinates(input.coordinates);
+                    let error = match (coordinates, features.is_empty()) {
+                        (None, _) => Some(RecordError::InvalidCoordinates),
+                        (Some(_), true) => Some(RecordError::NotFound),
+                        (Some(_), false) => None,
+                    };
+                    self.stats.add_record(coordinates, &features, error);
+                    aggregator.add(&features, &input.values);
+                    self.progress_bar.inc(input.size);
+                }
+                Err(e) => {
+                    self.stats.add_parse_error();
+                    warn!("Unable to read line {}: {}", self.stats.total_lines, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<ProcessStats, FileProcessorError> {
+        if !self.closed {
+            self.join_chunk()?;
+        }
+
+        match self.output {
+            JoinOutput::Records { writer: mut record_writer, rejects } => {
+                let finished = record_writer.finish();
+                let rejects_finished = rejects.map(|mut rejects_writer| rejects_writer.finish()).unwrap_or(Ok(()));
+                match finished.and(rejects_finished) {
+                    Err(err) if !err.is_broken_pipe() => {
+                        self.progress_bar.finish();
+                        return Err(err);
+                    }
+                    _ => {}
+                }
+            }
+            JoinOutput::Aggregate(aggregator) => {
+                if let Err(err) = aggregator.write(self.geo_finder, self.options) {
+                    self.progress_bar.finish();
+                    return Err(err);
+                }
+            }
+        }
+
+        self.progress_bar.finish();
+
+        let elapsed_secs = self.start_instant.elapsed().as_millis() as f32 / 1000.0f32;
+        info!(
+            "Processed {} rows of data in {} seconds. Avg: {} rows/sec",
+            self.stats.total_lines,
+            elapsed_secs,
+            (self.stats.total_lines as f32) / elapsed_secs
+        );
+
+        self.stats.elapsed_secs = self.start_instant.elapsed().as_secs_f64();
+        Ok(self.stats)
+    }
+}
 
 pub fn spatial_polygons_join(
-    geo_finder: geo_finder::PolygonFinder,
-    input_file: &mut io::Read,
+    geo_finder: &dyn geo_finder::GeoFinder,
+    input: JoinInput,
     file_size: Option<u64>,
-    output_file: &mut io::Write,
-    delimiter: u8,
-    latitude_idx: usize,
-    longitude_idx: usize,
-    properties: Vec<&str>,
-    no_header: bool,
+    output_file: &mut (dyn io::Write + Send),
+    rejects_file: Option<&mut (dyn io::Write + Send)>,
+    options: &JoinOptions,
 ) -> Result<ProcessStats, FileProcessorError> {
     let progress_bar = cli_utils::create_progress_bar_bytes(false, "Processing...", file_size);
 
+    if options.input_format == InputFormat::Parquet {
+        if let Some(compression) = options.input_compression.filter(|&c| c != compression::Compression::None) {
+            return Err(FileProcessorError::CompressedParquet(compression.as_str().to_owned()));
+        }
+        return match input {
+            JoinInput::File(file) => {
+                join_parquet(geo_finder, file, file_size, output_file, rejects_file, options, progress_bar)
+            }
+            JoinInput::Stream(_) => Err(FileProcessorError::ParquetFromStream),
+        };
+    }
+
+    let input_file = match input {
+        JoinInput::File(file) => Box::new(file) as Box<dyn io::Read>,
+        JoinInput::Stream(stream) => Box::new(stream),
+    };
+
+    // The progress is of the bytes read from the input, so of the compressed size when compressed.
+    let progress_reader = cli_utils::ProgressReader::new(input_file, progress_bar.clone());
+    let (compression, mut input_file) =
+        compression::decompress(io::BufReader::new(progress_reader), options.input_compression)
+            .map_err(FileProcessorError::Io)?;
+    if compression != compression::Compression::None {
+        info!("Reading {} compressed input", compression.as_str());
+    }
+
+    match options.input_format {
+        InputFormat::Csv => join_csv(geo_finder, &mut input_file, output_file, rejects_file, options, progress_bar),
+        _ => join_json(geo_finder, &mut input_file, output_file, rejects_file, options, progress_bar),
+    }
+}
+
+/**
+ * Writes the header of an output. When it was closed by its reader, the join stops at the first record written.
+ */
+fn write_header<R>(
+    record_writer: &mut dyn output_writer::RecordWriter<R>,
+    input_columns: Option<&csv::StringRecord>,
+    joined_columns: &[String],
+) -> Result<(), FileProcessorError> {
+    match record_writer.write_header(input_columns, joined_columns) {
+        Err(err) if !err.is_broken_pipe() => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn join_csv(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    input_file: &mut dyn io::Read,
+    output_file: &mut (dyn io::Write + Send),
+    rejects_file: Option<&mut (dyn io::Write + Send)>,
+    options: &JoinOptions,
+    progress_bar: ProgressBar,
+) -> Result<ProcessStats, FileProcessorError> {
+    let delimiter = options.delimiter;
+
     let mut csv_reader = csv::ReaderBuilder::new()
         .delimiter(delimiter)
         .has_headers(false) // Don't care about the headers now.
@@ -69,119 +1141,647 @@ pub fn spatial_polygons_join(
         // .double_quote(false)    // "" instead of \" to escape quotes
         .from_reader(input_file);
 
-    let mut csv_writer = csv::WriterBuilder::new()
-        .delimiter(delimiter)
-        .flexible(true)
-        .from_writer(output_file);
+    let mut records = csv_reader.records();
 
-    let mut total_lines = 0;
-    let mut error_lines = 0;
+    let has_header = !options.no_header;
+    let header = match has_header {
+        true => records.next().transpose().map_err(FileProcessorError::Csv)?,
+        false => None,
+    };
 
-    let start_instant = time::Instant::now();
+    let latitude_idx = resolve_column(&options.latitude, header.as_ref(), &LATITUDE_NAMES, "latitude")?;
+    let longitude_idx = resolve_column(&options.longitude, header.as_ref(), &LONGITUDE_NAMES, "longitude")?;
+    info!("Using columns {} (latitude) and {} (longitude). 1 based.", latitude_idx + 1, longitude_idx + 1);
+    let value_idxs = aggregate_column_idxs(options, header.as_ref())?;
 
-    let mut records = csv_reader.records();
+    let output = join_output(geo_finder, options, output_file, rejects_file, |output_file, joined_columns| {
+        let mut record_writer = output_writer::create_record_writer(options.output_format, delimiter, output_file)?;
+        // The columns we append to the ones of the input
+        write_header(record_writer.as_mut(), header.as_ref(), joined_columns)?;
+        Ok(record_writer)
+    })?;
+
+    let mut joiner = Joiner::new(geo_finder, options, output, progress_bar)?;
+    for record_result in records {
+        let input = record_result
+            .map(|record| {
+                let number = |idx: usize| record.get(idx).and_then(|v| v.parse::<f64>().ok());
+                InputRecord {
+                    coordinates: (number(latitude_idx), number(longitude_idx)),
+                    size: 0,
+                    values: value_idxs.iter().map(|idx| number(*idx)).collect(),
+                    record,
+                }
+            })
+            .map_err(|e| e.to_string());
+        if !joiner.push(input)? {
+            break;
+        }
+    }
+
+    joiner.finish()
+}
+
+fn join_json(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    input_file: &mut dyn io::Read,
+    output_file: &mut (dyn io::Write + Send),
+    rejects_file: Option<&mut (dyn io::Write + Send)>,
+    options: &JoinOptions,
+    progress_bar: ProgressBar,
+) -> Result<ProcessStats, FileProcessorError> {
+    let output = join_output(geo_finder, options, output_file, rejects_file, |output_file, joined_columns| {
+        let mut record_writer = output_writer::create_json_record_writer(options.output_format, output_file)?;
+        write_header(record_writer.as_mut(), None, joined_columns)?;
+        Ok(record_writer)
+    })?;
+
+    let reader = io::BufReader::new(input_file);
+    let mut joiner = Joiner::new(geo_finder, options, output, progress_bar)?;
+    json_input::read_records(reader, options.input_format, |record| {
+        let input = record.map(|record| InputRecord {
+            coordinates: record.coordinates(options.latitude_pointer, options.longitude_pointer),
+            size: 0,
+            values: options.aggregate_columns.iter().map(|pointer| record.number(pointer)).collect(),
+            record,
+        });
+        joiner.push(input)
+    })?;
+
+    joiner.finish()
+}
+
+fn join_parquet(
+    geo_finder: &dyn geo_finder::GeoFinder,
+    input_file: fs::File,
+    file_size: Option<u64>,
+    output_file: &mut (dyn io::Write + Send),
+    rejects_file: Option<&mut (dyn io::Write + Send)>,
+    options: &JoinOptions,
+    progress_bar: ProgressBar,
+) -> Result<ProcessStats, FileProcessorError> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(input_file).map_err(FileProcessorError::Parquet)?;
+    let metadata = builder.metadata().clone();
+    let schema = builder.schema().clone();
+    let reader = builder.with_batch_size(CHUNK_SIZE).build().map_err(FileProcessorError::Parquet)?;
 
-    let has_header = !no_header;
-    if has_header {
-        // If the file has a header, process it first and append the columns we want
-        if let Some(Ok(header)) = records.next() {
-            let mut new_header: Vec<String> = header.iter().map(String::from).collect();
+    let header = parquet_input::header(&schema);
+    let latitude_idx = resolve_column(&options.latitude, Some(&header), &LATITUDE_NAMES, "latitude")?;
+    let longitude_idx = resolve_column(&options.longitude, Some(&header), &LONGITUDE_NAMES, "longitude")?;
+    info!("Using columns {} (latitude) and {} (longitude). 1 based.", latitude_idx + 1, longitude_idx + 1);
+    let value_idxs = aggregate_column_idxs(options, Some(&header))?;
 
-            for property in properties.iter() {
-                new_header.push(String::from(*property));
+    // The same row groups, so the output is not kept in memory for longer than the input.
+    let row_group_size = metadata.row_groups().iter().map(|row_group| row_group.num_rows()).max().unwrap_or(0);
+    let output = join_output(geo_finder, options, output_file, rejects_file, |output_file, joined_columns| {
+        output_writer::create_parquet_record_writer(
+            options.output_format,
+            &schema,
+            joined_columns,
+            row_group_size.max(1) as usize,
+            output_file,
+        )
+    })?;
+
+    // The size of a row, on average.
+    let row_count = metadata.file_metadata().num_rows().max(1) as u64;
+    let record_size = file_size.unwrap_or_default() / row_count;
+
+    let mut joiner = Joiner::new(geo_finder, options, output, progress_bar)?;
+    'batches: for batch in reader {
+        let batch = batch.map_err(FileProcessorError::Arrow)?;
+        for input in parquet_input::records(batch, latitude_idx, longitude_idx, &value_idxs, record_size)? {
+            if !joiner.push(Ok(input))? {
+                break 'batches;
             }
+        }
+    }
+
+    joiner.finish()
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    const SQUARE_GEOJSON_STR: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"name": "left"},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}}
+    ]}"#;
+
+    fn header(columns: &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(columns.to_vec())
+    }
+
+    fn join_options<'a>(
+        input_format: InputFormat,
+        output_format: output_writer::OutputFormat,
+        properties: Vec<&'a str>,
+    ) -> JoinOptions<'a> {
+        JoinOptions { delimiter: b',', properties, input_format, output_format, ..Default::default() }
+    }
 
-            new_header.push("status".to_owned());
-            new_header.push("error_message".to_owned());
+    #[test]
+    fn it_should_resolve_a_column_by_index_without_header() {
+        let idx = resolve_column(&ColumnSelector::Index(3), None, &LATITUDE_NAMES, "latitude").unwrap();
 
-            csv_writer.write_record(new_header).ok();
+        assert_eq!(idx, 3);
+    }
+
+    #[test]
+    fn it_should_resolve_a_column_by_name() {
+        let header = header(&["id", "Lat_GPS", "lng_gps"]);
+
+        let idx = resolve_column(&ColumnSelector::Name("lat_gps"), Some(&header), &LATITUDE_NAMES, "latitude");
+
+        assert_eq!(idx.unwrap(), 1);
+    }
+
+    #[test]
+    fn it_should_auto_detect_the_coordinate_columns() {
+        let header = header(&["id", "Longitude", " Latitude "]);
+
+        let latitude_idx = resolve_column(&ColumnSelector::Auto, Some(&header), &LATITUDE_NAMES, "latitude");
+        let longitude_idx = resolve_column(&ColumnSelector::Auto, Some(&header), &LONGITUDE_NAMES, "longitude");
+
+        assert_eq!(latitude_idx.unwrap(), 2);
+        assert_eq!(longitude_idx.unwrap(), 1);
+    }
+
+    #[test]
+    fn it_should_fail_listing_the_header_when_a_column_is_missing() {
+        let header = header(&["id", "lat"]);
+
+        let result = resolve_column(&ColumnSelector::Auto, Some(&header), &LONGITUDE_NAMES, "longitude");
+
+        match result {
+            Err(FileProcessorError::ColumnNotFound(_, header_description)) => {
+                assert_eq!(header_description, r#"["id", "lat"]"#);
+            }
+            _ => panic!("Wrong Error"),
         }
     }
 
-    for (line_number, record_result) in csv_reader.records().enumerate() {
-        total_lines += 1;
+    #[test]
+    fn it_should_only_accept_finite_coordinates_in_range() {
+        assert_eq!(valid_coordinates((Some(-90.0), Some(180.0))), Some((-90.0, 180.0)));
+        assert_eq!(valid_coordinates((Some(90.0), Some(-180.0))), Some((90.0, -180.0)));
+
+        assert_eq!(valid_coordinates((Some(f64::NAN), Some(0.0))), None);
+        assert_eq!(valid_coordinates((Some(0.0), Some(f64::NAN))), None);
+        assert_eq!(valid_coordinates((Some(f64::INFINITY), Some(0.0))), None);
+        assert_eq!(valid_coordinates((Some(0.0), Some(f64::NEG_INFINITY))), None);
+        assert_eq!(valid_coordinates((Some(91.0), Some(0.0))), None);
+        assert_eq!(valid_coordinates((Some(-91.0), Some(0.0))), None);
+        assert_eq!(valid_coordinates((Some(0.0), Some(181.0))), None);
+        assert_eq!(valid_coordinates((Some(0.0), Some(-181.0))), None);
+        assert_eq!(valid_coordinates((None, Some(0.0))), None);
+    }
+
+    #[test]
+    fn it_should_aggregate_the_points_of_every_feature() {
+        let finder = geo_finder::PolygonFinder::new_from_string(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "left"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}},
+                {"type": "Feature", "properties": {"name": "right"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[2, 0], [3, 0], [3, 1], [2, 1], [2, 0]]]}},
+                {"type": "Feature", "properties": {"name": "empty"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[4, 0], [5, 0], [5, 1], [4, 1], [4, 0]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let mut input = "id,lat,lon,amount\n1,0.5,0.5,10\n2,0.2,0.7,x\n3,0.5,0.4,4\n4,0.5,2.5,1.5\n5,9,9,100\n".as_bytes();
+
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.aggregate = true;
+        options.aggregate_columns = vec!["amount"];
+        let mut output = Vec::new();
+        let stats = spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
+
+        assert_eq!((stats.total_lines, stats.error_lines), (5, 1));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "name,point_count,amount_sum,amount_mean,amount_min,amount_max\n",
+                "left,3,14.0,7.0,4.0,10.0\n",
+                "right,1,1.5,1.5,1.5,1.5\n",
+                "empty,0,,,,\n",
+            )
+        );
+
+        options.output_format = output_writer::OutputFormat::GeoJson;
+        let mut input = "lat,lon,amount\n0.5,2.5,2\n".as_bytes();
+        let mut output = Vec::new();
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
+
+        let collection: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[1]["geometry"]["coordinates"][0][1], serde_json::json!([3.0, 0.0]));
+        assert_eq!(
+            features[1]["properties"],
+            serde_json::json!({
+                "name": "right", "point_count": 1, "amount_sum": 2.0, "amount_mean": 2.0, "amount_min": 2.0, "amount_max": 2.0
+            })
+        );
+        assert_eq!(features[2]["properties"]["point_count"], 0);
+        assert!(features[2]["properties"]["amount_sum"].is_null());
+    }
+
+    #[test]
+    fn it_should_not_aggregate_to_parquet() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let mut input = "lat,lon\n0.5,0.5\n".as_bytes();
 
-        match record_result {
-            Err(e) => {
-                warn!("Unable to read line {}: {}", line_number, e);
-                error_lines += 1;
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Parquet, vec!["name"]);
+        options.aggregate = true;
+        let mut output = Vec::new();
+        match spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options) {
+            Err(FileProcessorError::UnsupportedOutput(input, format)) => {
+                assert_eq!((input.as_str(), format.as_str()), ("Aggregated", "Parquet"))
             }
-            Ok(record) => {
+            _ => panic!("Wrong Error"),
+        }
+        assert!(output.is_empty());
+    }
 
-                let mut new_record = record.clone();
+    #[test]
+    fn it_should_join_a_compressed_input_found_by_its_magic_number() {
+        use std::io::Write;
 
-                let latitude_opt = record.get(latitude_idx).and_then(|v| v.parse::<f64>().ok());
-
-                let longitude_opt = record
-                    .get(longitude_idx)
-                    .and_then(|v| v.parse::<f64>().ok());
-
-                if latitude_opt.is_none() || longitude_opt.is_none() {
-                    error_lines += 1;
-                    fill_error_row(
-                        &properties,
-                        &format!("INVALID_COORDINATES: {:?}", (latitude_opt, longitude_opt)),
-                        &mut new_record,
-                    );
-                } else {
-                    let latitude = latitude_opt.unwrap();
-                    let longitude = longitude_opt.unwrap();
-
-                    match geo_finder.find(latitude, longitude) {
-                        Some(find_result) => {
-                            // info!("Props: {:?}", find_result.props);
-                            for prop in &properties {
-                                let value: &str = find_result.props.get(prop as &str).unwrap(); //TODO: proper error handling
-                                new_record.push_field(&value);
-                            }
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let mut input = compression::CompressedWriter::new(Vec::new(), compression::Compression::Zstd).unwrap();
+        input.write_all(b"id,lat,lon\n1,0.5,0.5\n2,5,5\n").unwrap();
+        let mut input = io::Cursor::new(input.finish().unwrap());
 
-                            new_record.push_field("success"); // Status
-                            new_record.push_field(""); // Error message
-                        }
-                        None => {
-                            error_lines += 1;
-                            fill_error_row(
-                                &properties,
-                                &format!("COORDINATES_NOT_FOUND: {:?}", (latitude, longitude)),
-                                &mut new_record,
-                            )
-                        }
-                    }
-                }
+        let options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        let mut output = Vec::new();
+        let stats = spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
 
-                // new_record.push_field("\n");
-                // warn!("New record {:?}", new_record);
-                let write_result = csv_writer.write_record(&new_record);
+        assert_eq!((stats.total_lines, stats.error_lines), (2, 1));
+        assert_eq!(
+            String::from_utf8(output).unwrap().lines().next(),
+            Some("id,lat,lon,name,status,error_message")
+        );
+    }
 
-                progress_bar.inc(record_size(&record));
+    #[test]
+    fn it_should_write_the_unmatched_records_to_the_rejects() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let input = "id,lat,lon\n1,0.5,0.5\n2,5,5\n3,,x\n";
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.unmatched = UnmatchedPolicy::Reject;
 
-                if write_result.is_err() {
-                    break ;
-                    // warn!("Error writing row: {}", write_result.err().unwrap())
-                }
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        let stats =
+            spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, Some(&mut rejects), &options)
+                .unwrap();
+
+        assert_eq!((stats.total_lines, stats.error_lines), (3, 2));
+        assert_eq!(String::from_utf8(output).unwrap(), "id,lat,lon,name,status,error_message\n1,0.5,0.5,left,success,\n");
+        assert_eq!(
+            String::from_utf8(rejects).unwrap(),
+            "id,lat,lon,reason\n2,5,5,\"COORDINATES_NOT_FOUND: (5.0, 5.0)\"\n3,,x,\"INVALID_COORDINATES: (None, None)\"\n"
+        );
+    }
+
+    #[test]
+    fn it_should_drop_the_unmatched_records_but_count_them() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let input = "id,lat,lon\n1,0.5,0.5\n2,5,5\n3,,x\n";
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.unmatched = UnmatchedPolicy::Drop;
+
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        let stats =
+            spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, Some(&mut rejects), &options)
+                .unwrap();
+
+        assert_eq!(
+            (stats.total_lines, stats.not_found_lines, stats.invalid_coordinates_lines, stats.error_lines),
+            (3, 1, 1, 2)
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), "id,lat,lon,name,status,error_message\n1,0.5,0.5,left,success,\n");
+        assert!(rejects.is_empty());
+    }
+
+    #[test]
+    fn it_should_write_the_rejects_in_the_json_output_format_with_a_reason_member() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let input = "{\"id\": 1, \"lat\": 0.5, \"lon\": 0.5}\n{\"id\": 2, \"lat\": 5, \"lon\": 5}\n";
+        let mut options = join_options(InputFormat::NdJson, output_writer::OutputFormat::NdJson, vec!["name"]);
+        options.unmatched = UnmatchedPolicy::Reject;
+
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, Some(&mut rejects), &options)
+            .unwrap();
+
+        let output: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let reject: serde_json::Value = serde_json::from_slice(&rejects).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!({"id": 1, "lat": 0.5, "lon": 0.5, "name": "left", "status": "success", "error_message": null})
+        );
+        assert_eq!(reject, serde_json::json!({"id": 2, "lat": 5, "lon": 5, "reason": "COORDINATES_NOT_FOUND: (5.0, 5.0)"}));
+
+        // Point features, with the reason in the properties.
+        let input = "id,lat,lon\n2,5,5\n";
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::GeoJsonSeq, vec!["name"]);
+        options.unmatched = UnmatchedPolicy::Reject;
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, Some(&mut rejects), &options)
+            .unwrap();
+
+        let reject: serde_json::Value = serde_json::from_slice(&rejects).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(reject["geometry"]["coordinates"], serde_json::json!([5.0, 5.0]));
+        assert_eq!(
+            reject["properties"],
+            serde_json::json!({"id": "2", "lat": "5", "lon": "5", "reason": "COORDINATES_NOT_FOUND: (5.0, 5.0)"})
+        );
+    }
+
+    #[test]
+    fn it_should_write_the_rejects_instead_of_an_unmatched_partition() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let mut input = "id,lat,lon\n1,0.5,0.5\n2,9,9\n".as_bytes();
+        let directory = std::env::temp_dir().join(format!("fsj-partitioned-rejects-{}", std::process::id()));
+
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.unmatched = UnmatchedPolicy::Reject;
+        options.partition = Some(partition_writer::PartitionOptions {
+            property: "name",
+            directory: &directory,
+            compression: compression::Compression::None,
+            max_open_files: 4,
+        });
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, Some(&mut rejects), &options).unwrap();
+
+        let files: Vec<_> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        let left = fs::read_to_string(directory.join("left.csv")).unwrap();
+        fs::remove_dir_all(&directory).ok();
+
+        assert_eq!(files, vec!["left.csv"]);
+        assert_eq!(left, "id,lat,lon,name,status,error_message\n1,0.5,0.5,left,success,\n");
+        assert_eq!(String::from_utf8(rejects).unwrap(), "id,lat,lon,reason\n2,9,9,\"COORDINATES_NOT_FOUND: (9.0, 9.0)\"\n");
+    }
+
+    #[test]
+    fn it_should_reject_the_whole_record_when_one_of_its_matches_misses_a_property() {
+        let finder = geo_finder::PolygonFinder::new_from_string(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "big"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [4, 0], [4, 4], [0, 4], [0, 0]]]}},
+                {"type": "Feature", "properties": {"code": 7},
+                 "geometry": {"type": "Polygon", "coordinates": [[[1, 1], [2, 1], [2, 2], [1, 2], [1, 1]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let input = "id,lat,lon\n1,1.5,1.5\n2,3,3\n";
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.match_mode = MatchMode::All;
+
+        options.unmatched = UnmatchedPolicy::Reject;
+        let (mut output, mut rejects) = (Vec::new(), Vec::new());
+        let stats =
+            spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, Some(&mut rejects), &options)
+                .unwrap();
+
+        assert_eq!((stats.matched_lines, stats.missing_property_lines, stats.error_lines), (1, 1, 1));
+        assert_eq!(String::from_utf8(output).unwrap(), "id,lat,lon,name,status,error_message\n2,3,3,big,success,\n");
+        assert_eq!(String::from_utf8(rejects).unwrap(), "id,lat,lon,reason\n1,1.5,1.5,MISSING_PROPERTY: name\n");
+
+        options.unmatched = UnmatchedPolicy::Keep;
+        let mut output = Vec::new();
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, None, &options).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "id,lat,lon,name,status,error_message\n1,1.5,1.5,,error,MISSING_PROPERTY: name\n2,3,3,big,success,\n"
+        );
+
+        options.missing_property = MissingPropertyPolicy::Fail;
+        let mut output = Vec::new();
+        match spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, None, &options) {
+            Err(FileProcessorError::MissingProperty(property, record)) => assert_eq!((property.as_str(), record), ("name", 1)),
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_write_the_records_in_input_order_with_several_threads() {
+        let finder = geo_finder::PolygonFinder::new_from_string(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "left"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}},
+                {"type": "Feature", "properties": {"name": "right"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[2, 0], [3, 0], [3, 1], [2, 1], [2, 0]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let record_count = 2 * CHUNK_SIZE + 100;
+        // Around the ends of the chunks, which count the rows which could not be read too.
+        let unreadable = [0, CHUNK_SIZE - 1, CHUNK_SIZE, 2 * CHUNK_SIZE];
+        let name = |id: usize| ["left", "right", ""][id % 3];
+        let mut input = String::new();
+        for id in 0..record_count {
+            match (unreadable.contains(&id), id % 3) {
+                (true, _) => input.push_str("{\"id\": \n"),
+                (false, 0) => input.push_str(&format!("{{\"id\": {}, \"lat\": 0.5, \"lon\": 0.5}}\n", id)),
+                (false, 1) => input.push_str(&format!("{{\"id\": {}, \"lat\": 0.5, \"lon\": 2.5}}\n", id)),
+                (false, _) => input.push_str(&format!("{{\"id\": {}, \"lat\": 9, \"lon\": 9}}\n", id)),
             }
-        };
+        }
+
+        let mut options = join_options(InputFormat::NdJson, output_writer::OutputFormat::NdJson, vec!["name"]);
+        options.threads = 4;
+        let mut output = Vec::new();
+        let stats =
+            spatial_polygons_join(&finder, JoinInput::Stream(&mut input.as_bytes()), None, &mut output, None, &options).unwrap();
+
+        assert_eq!((stats.total_lines as usize, stats.parse_error_lines as usize), (record_count, unreadable.len()));
+        let joined: Vec<(u64, String)> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let object: serde_json::Value = serde_json::from_str(line).unwrap();
+                (object["id"].as_u64().unwrap(), object["name"].as_str().unwrap_or_default().to_owned())
+            })
+            .collect();
+        let expected: Vec<(u64, String)> = (0..record_count)
+            .filter(|id| !unreadable.contains(id))
+            .map(|id| (id as u64, name(id).to_owned()))
+            .collect();
+        assert!(joined == expected, "the records are not in input order");
+    }
+
+    #[test]
+    fn it_should_count_the_rows_by_outcome() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let mut input = concat!(
+            "{\"lat\": 0.5, \"lon\": 0.5}\n{\"lat\": 0.2, \"lon\": 0.7}\n{\"lat\": 5, \"lon\": 5}\n",
+            "{\"lat\": \"north\", \"lon\": 1}\n{\"lat\": 1,\n"
+        )
+        .as_bytes();
+
+        let options = join_options(InputFormat::NdJson, output_writer::OutputFormat::NdJson, vec!["name"]);
+        let mut output = Vec::new();
+        let stats = spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
+
+        assert_eq!(
+            (stats.total_lines, stats.matched_lines, stats.not_found_lines, stats.invalid_coordinates_lines),
+            (5, 2, 1, 1)
+        );
+        assert_eq!((stats.parse_error_lines, stats.error_lines), (1, 3));
+        assert_eq!(stats.bbox, Some([0.5, 0.2, 5.0, 5.0]));
+        assert_eq!(stats.top_features(10), vec![(0, 2)]);
+
+        let mut input = "lat,lon\n0.5,0.5\nNaN,0.5\n0.5,inf\n91,0.5\n0.5,-181\n".as_bytes();
+        let options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        let mut output = Vec::new();
+        let stats = spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
+
+        assert_eq!((stats.matched_lines, stats.invalid_coordinates_lines), (1, 4));
+        assert_eq!(stats.bbox, Some([0.5, 0.5, 0.5, 0.5]));
+    }
+
+    /// An output failing every write with the error.
+    struct FailingOutput(io::ErrorKind);
+
+    impl io::Write for FailingOutput {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(self.0))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::from(self.0))
+        }
+    }
+
+    #[test]
+    fn it_should_stop_on_a_closed_output_and_fail_on_other_write_errors() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+
+        let mut input = "lat,lon\n0.5,0.5\n".as_bytes();
+        let mut output = FailingOutput(io::ErrorKind::BrokenPipe);
+        assert!(spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).is_ok());
+
+        let mut input = "lat,lon\n0.5,0.5\n".as_bytes();
+        let mut output = FailingOutput(io::ErrorKind::Other);
+        match spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options) {
+            Err(FileProcessorError::Csv(_)) | Err(FileProcessorError::Io(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
     }
 
-    #[allow(unused_must_use)] {
-        csv_writer
-            .flush();
+    #[test]
+    fn it_should_fail_when_the_header_can_not_be_read() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+
+        let mut input = &b"id,\xff,lat,lon\n1,a,0.5,0.5\n"[..];
+        let mut output = Vec::new();
+        match spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options) {
+            Err(FileProcessorError::Csv(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
     }
 
-    progress_bar.finish();
+    #[test]
+    fn it_should_write_a_file_per_partition_value() {
+        let finder = geo_finder::PolygonFinder::new_from_string(
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"name": "left", "side": "L"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}},
+                {"type": "Feature", "properties": {"name": "right", "side": "l"},
+                 "geometry": {"type": "Polygon", "coordinates": [[[2, 0], [3, 0], [3, 1], [2, 1], [2, 0]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let mut input = "id,lat,lon\n1,0.5,0.5\n2,0.5,2.5\n3,0.2,0.7\n4,9,9\n".as_bytes();
+        let directory = std::env::temp_dir().join(format!("fsj-partitioned-{}", std::process::id()));
+
+        let mut options = join_options(InputFormat::Csv, output_writer::OutputFormat::Csv, vec!["name"]);
+        options.partition = Some(partition_writer::PartitionOptions {
+            property: "side",
+            directory: &directory,
+            compression: compression::Compression::None,
+            max_open_files: 1,
+        });
+        let mut output = Vec::new();
+        spatial_polygons_join(&finder, JoinInput::Stream(&mut input), None, &mut output, None, &options).unwrap();
 
-    let end_instant = time::Instant::now();
-    let elapsed_secs = (end_instant - start_instant).as_millis() as f32 / 1000.0f32;
-    info!(
-        "Processed {} rows of data in {} seconds. Avg: {} rows/sec",
-        total_lines,
-        elapsed_secs,
-        (total_lines as f32) / elapsed_secs
-    );
+        let read = |name: &str| fs::read_to_string(directory.join(name)).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(read("L.csv"), "id,lat,lon,name,status,error_message\n1,0.5,0.5,left,success,\n3,0.2,0.7,left,success,\n");
+        // Not in the file of "L", even where names ignore case.
+        assert_eq!(read("l-2.csv"), "id,lat,lon,name,status,error_message\n2,0.5,2.5,right,success,\n");
+        assert_eq!(
+            read("_unmatched.csv"),
+            "id,lat,lon,name,status,error_message\n4,9,9,,error,\"COORDINATES_NOT_FOUND: (9.0, 9.0)\"\n"
+        );
+        fs::remove_dir_all(&directory).ok();
+    }
 
-    return Ok(ProcessStats {
-        total_lines,
-        error_lines,
-    });
+    #[test]
+    fn it_should_join_a_parquet_file_keeping_the_column_types() {
+        let finder = geo_finder::PolygonFinder::new_from_string(SQUARE_GEOJSON_STR).unwrap();
+        let input_path = std::env::temp_dir().join(format!("fsj-points-{}.parquet", std::process::id()));
+        let output_path = std::env::temp_dir().join(format!("fsj-joined-{}.parquet", std::process::id()));
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("lat", DataType::Float64, true),
+            Field::new("lon", DataType::Utf8, true),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from(vec![1, 2, 3])),
+            Arc::new(Float64Array::from(vec![Some(0.5), Some(5.0), None])),
+            Arc::new(StringArray::from(vec!["0.5", "5", "0.5"])),
+        ];
+        let mut writer = ArrowWriter::try_new(fs::File::create(&input_path).unwrap(), schema.clone(), None).unwrap();
+        writer.write(&RecordBatch::try_new(schema, columns).unwrap()).unwrap();
+        writer.close().unwrap();
+
+        let options = join_options(InputFormat::Parquet, output_writer::OutputFormat::Parquet, vec!["name"]);
+        let mut output_file = fs::File::create(&output_path).unwrap();
+        let input = JoinInput::File(fs::File::open(&input_path).unwrap());
+        let stats = spatial_polygons_join(&finder, input, None, &mut output_file, None, &options).unwrap();
+
+        let batches: Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(&output_path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        fs::remove_file(&input_path).ok();
+        fs::remove_file(&output_path).ok();
+
+        assert_eq!((stats.total_lines, stats.error_lines), (3, 2));
+        let batch = &batches[0];
+        let columns: Vec<_> =
+            batch.schema().fields().iter().map(|field| (field.name().clone(), field.data_type().clone())).collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("id".to_owned(), DataType::Int64),
+                ("lat".to_owned(), DataType::Float64),
+                ("lon".to_owned(), DataType::Utf8),
+                ("name".to_owned(), DataType::Utf8),
+                ("status".to_owned(), DataType::Utf8),
+                ("error_message".to_owned(), DataType::Utf8),
+            ]
+        );
+        let text = |idx: usize| batch.column(idx).as_any().downcast_ref::<StringArray>().unwrap().clone();
+        assert_eq!(text(3).iter().collect::<Vec<_>>(), vec![Some("left"), None, None]);
+        assert_eq!(text(4).iter().collect::<Vec<_>>(), vec![Some("success"), Some("error"), Some("error")]);
+        assert!(batch.column(1).is_null(2));
+    }
 }
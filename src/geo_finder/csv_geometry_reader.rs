@@ -0,0 +1,230 @@
+use geojson::{Feature, Geometry, PolygonType, Position, Value};
+use wkt::Wkt;
+
+use log::warn;
+use std::io;
+use std::str::FromStr;
+
+use super::polygon_finder::{has_positions, PolygonFinderError};
+use super::wkb;
+use super::JsonObject;
+
+/**
+ * Reads the features of a CSV file with a header, one row at a time. The geometry is in `geometry_column`
+ * (found ignoring case), as WKT (also PostGIS EWKT) or hex encoded WKB. The other columns are the properties,
+ * as text (null when empty). Rows with an empty geometry are skipped.
+ */
+pub fn read_features<R, F>(
+    reader: R,
+    delimiter: u8,
+    geometry_column: &str,
+    on_feature: F,
+) -> Result<(), PolygonFinderError>
+where
+    R: io::Read,
+    F: FnMut(Feature) -> Result<(), PolygonFinderError>,
+{
+    let mut on_feature = on_feature;
+    let mut csv_reader = csv::ReaderBuilder::new().delimiter(delimiter).from_reader(reader);
+
+    let header = csv_reader.headers()?.clone();
+    let geometry_index = header
+        .iter()
+        .position(|column| column.trim().eq_ignore_ascii_case(geometry_column))
+        .ok_or_else(|| {
+            let columns = header.iter().collect::<Vec<_>>().join(", ");
+            PolygonFinderError::ColumnNotFound(geometry_column.to_owned(), columns)
+        })?;
+
+    let mut record = csv::StringRecord::new();
+    let mut empty_geometries = 0;
+    while csv_reader.read_record(&mut record)? {
+        let line = record.position().map(|position| position.line()).unwrap_or_default();
+        let in_record = |err| PolygonFinderError::InRecord(line, Box::new(err));
+
+        let geometry = match read_geometry(&record[geometry_index]).map_err(in_record)?.filter(has_positions) {
+            Some(geometry) => geometry,
+            None => {
+                empty_geometries += 1;
+                continue;
+            }
+        };
+        let properties: JsonObject = header
+            .iter()
+            .zip(record.iter())
+            .enumerate()
+            .filter(|(index, _)| *index != geometry_index)
+            .map(|(_, (name, value))| {
+                let value = match value.is_empty() {
+                    true => serde_json::Value::Null,
+                    false => serde_json::Value::from(value),
+                };
+                (name.to_owned(), value)
+            })
+            .collect();
+
+        let feature = Feature {
+            bbox: None,
+            geometry: Some(geometry),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        };
+        on_feature(feature).map_err(in_record)?;
+    }
+
+    if empty_geometries > 0 {
+        warn!("Skipped {} rows with an empty geometry", empty_geometries);
+    }
+    Ok(())
+}
+
+/**
+ * A WKT or hex encoded WKB geometry. `None` when empty.
+ */
+fn read_geometry(text: &str) -> Result<Option<Geometry>, PolygonFinderError> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    if text.len().is_multiple_of(2) && text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        let bytes = (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        return Ok(Some(Geometry::new(wkb::read_geometry(&bytes)?)));
+    }
+
+    // The SRID of an EWKT is not needed.
+    let text = match text.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("SRID=") => text.split_once(';').map(|(_, wkt)| wkt).unwrap_or_default(),
+        _ => text,
+    };
+    let wkt = Wkt::<f64>::from_str(text).map_err(|err| PolygonFinderError::InvalidWkt(err.to_owned()))?;
+    Ok(wkt_value(wkt.item).map(Geometry::new))
+}
+
+fn wkt_position(coord: wkt::types::Coord<f64>) -> Position {
+    vec![coord.x, coord.y]
+}
+
+fn wkt_positions(line_string: wkt::types::LineString<f64>) -> Vec<Position> {
+    line_string.0.into_iter().map(wkt_position).collect()
+}
+
+fn wkt_rings(polygon: wkt::types::Polygon<f64>) -> PolygonType {
+    polygon.0.into_iter().map(wkt_positions).collect()
+}
+
+/**
+ * The GeoJSON value of a WKT geometry. `None` when empty.
+ */
+fn wkt_value(geometry: wkt::Geometry<f64>) -> Option<Value> {
+    let value = match geometry {
+        wkt::Geometry::Point(point) => Value::Point(wkt_position(point.0?)),
+        wkt::Geometry::LineString(line_string) => Value::LineString(wkt_positions(line_string)),
+        wkt::Geometry::Polygon(polygon) => Value::Polygon(wkt_rings(polygon)),
+        wkt::Geometry::MultiPoint(multi_point) => {
+            Value::MultiPoint(multi_point.0.into_iter().filter_map(|point| point.0).map(wkt_position).collect())
+        }
+        wkt::Geometry::MultiLineString(multi_line_string) => {
+            Value::MultiLineString(multi_line_string.0.into_iter().map(wkt_positions).collect())
+        }
+        wkt::Geometry::MultiPolygon(multi_polygon) => {
+            Value::MultiPolygon(multi_polygon.0.into_iter().map(wkt_rings).collect())
+        }
+        wkt::Geometry::GeometryCollection(collection) => Value::GeometryCollection(
+            collection.0.into_iter().filter_map(wkt_value).map(Geometry::new).collect(),
+        ),
+    };
+
+    match &value {
+        Value::Polygon(rings) if rings.is_empty() => None,
+        Value::MultiPolygon(polygons) if polygons.is_empty() => None,
+        _ => Some(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(csv: &str, geometry_column: &str) -> Result<Vec<Feature>, PolygonFinderError> {
+        let mut features = Vec::new();
+        read_features(csv.as_bytes(), b',', geometry_column, |feature| {
+            features.push(feature);
+            Ok(())
+        })?;
+        Ok(features)
+    }
+
+    #[test]
+    fn it_should_read_wkt_and_hex_wkb_geometries() {
+        let csv = concat!(
+            "cve_mun,WKT,nom_mun\n",
+            "06001,\"POLYGON ((0 0, 10 0, 10 10, 0 10, 0 0), (4 4, 6 4, 6 6, 4 4))\",Armería\n",
+            "06002,\"SRID=4326;MULTIPOLYGON (((0 0, 1 0, 1 1, 0 0)), ((2 2, 3 2, 3 3, 2 2)))\",\n",
+            "06003,0101000000b81e85eb51c858c0ae47e17a146e3340,Colima\n",
+        );
+
+        let features = read(csv, "wkt").unwrap();
+
+        assert_eq!(features.len(), 3);
+        match &features[0].geometry.as_ref().unwrap().value {
+            Value::Polygon(rings) => assert_eq!(rings.iter().map(Vec::len).collect::<Vec<_>>(), vec![5, 4]),
+            _ => panic!("Wrong geometry"),
+        }
+        match &features[1].geometry.as_ref().unwrap().value {
+            Value::MultiPolygon(polygons) => assert_eq!(polygons.len(), 2),
+            _ => panic!("Wrong geometry"),
+        }
+        assert_eq!(features[2].geometry.as_ref().unwrap().value, Value::Point(vec![-99.13, 19.43]));
+
+        let properties = features[0].properties.as_ref().unwrap();
+        assert_eq!(properties.keys().collect::<Vec<_>>(), vec!["cve_mun", "nom_mun"]);
+        assert_eq!(properties["cve_mun"], "06001");
+        assert_eq!(properties["nom_mun"], "Armería");
+        assert!(features[1].properties.as_ref().unwrap()["nom_mun"].is_null());
+    }
+
+    #[test]
+    fn it_should_skip_the_rows_with_an_empty_geometry() {
+        let csv = concat!(
+            "cve_mun,wkt,nom_mun\n",
+            "06001,,Armería\n",
+            "06002,POINT (1 1),Colima\n",
+            "06003,POLYGON EMPTY,Comala\n",
+            "06004,POINT EMPTY,Coquimatlán\n",
+        );
+
+        let features = read(csv, "wkt").unwrap();
+
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].properties.as_ref().unwrap()["nom_mun"], "Colima");
+    }
+
+    #[test]
+    fn it_should_fail_without_the_geometry_column() {
+        match read("cve_mun,geom\n06001,POINT (1 1)\n", "wkt") {
+            Err(PolygonFinderError::ColumnNotFound(column, columns)) => {
+                assert_eq!((column.as_str(), columns.as_str()), ("wkt", "cve_mun, geom"));
+            }
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_tell_the_line_of_an_invalid_geometry() {
+        match read("cve_mun,wkt\n06001,POINT (1 1)\n06002,\"POLYGON ((0 0, 1\"\n", "wkt") {
+            Err(PolygonFinderError::InRecord(line, error)) => {
+                assert_eq!(line, 3);
+                match *error {
+                    PolygonFinderError::InvalidWkt(_) => {}
+                    _ => panic!("Wrong Error"),
+                }
+            }
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
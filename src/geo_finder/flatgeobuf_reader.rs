@@ -0,0 +1,455 @@
+use flatgeobuf::{ColumnType, GeometryType};
+use geojson::{Feature, Geometry, PolygonType, Position, Value};
+
+use std::convert::TryInto;
+use std::io;
+use std::io::prelude::*;
+use log::warn;
+use std::path::Path;
+
+use super::polygon_finder::{has_positions, PolygonFinderError};
+use super::projection::Projection;
+use super::JsonObject;
+
+/// "fgb", the major version, "fgb" and the patch version.
+const MAGIC_SIZE: usize = 8;
+
+/// Each node of the packed R-tree: a box and an offset.
+const INDEX_NODE_ITEM_SIZE: u64 = 40;
+/// Sanity limit for the size of a header or a feature.
+const MAX_TABLE_SIZE: usize = 1 << 30;
+
+/**
+ * Reads the features of a FlatGeobuf file, one at a time. The spatial index of the file is skipped: the
+ * features are indexed again. Features without a geometry, or with an empty one, are skipped, and projected
+ * coordinates are converted to longitude and latitude.
+ *
+ * The header and the features are verified flatbuffers read with the `flatgeobuf` crate. The framing (the
+ * magic bytes, the size prefixes and the index) is read here instead of with its `FgbReader`, which trusts the
+ * sizes of the file: it allocates any feature size and panics with an index node size below 2.
+ */
+pub fn read_features<R, F>(path: &Path, reader: R, on_feature: F) -> Result<(), PolygonFinderError>
+where
+    R: io::Read,
+    F: FnMut(Feature) -> Result<(), PolygonFinderError>,
+{
+    let mut reader = io::BufReader::new(reader);
+    let mut on_feature = on_feature;
+
+    let mut magic = [0; MAGIC_SIZE];
+    reader.read_exact(&mut magic).map_err(|_| invalid("not a FlatGeobuf file"))?;
+    if &magic[..3] != b"fgb" || &magic[4..7] != b"fgb" || magic[3] != flatgeobuf::VERSION {
+        return Err(invalid(&format!("not a FlatGeobuf file (version {})", flatgeobuf::VERSION)));
+    }
+
+    let mut buffer = Vec::new();
+    if !read_table(&mut reader, &mut buffer)? {
+        return Err(invalid("the header is truncated"));
+    }
+    let header = flatgeobuf::size_prefixed_root_as_header(&buffer).map_err(|err| invalid(&err.to_string()))?;
+
+    let layer = header
+        .name()
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+    let geometry_type = header.geometry_type();
+    if ![GeometryType::Unknown, GeometryType::Point, GeometryType::Polygon, GeometryType::MultiPolygon]
+        .contains(&geometry_type)
+    {
+        let name = geometry_type.variant_name().unwrap_or("Unknown");
+        return Err(PolygonFinderError::NotPolygonal(layer, name.to_owned()));
+    }
+    let projection = match header.crs().and_then(|crs| crs.wkt()) {
+        Some(wkt) => Projection::from_wkt(wkt)?,
+        None => None,
+    };
+    // The columns are per feature instead when there are none in the header.
+    let header_columns = columns(header.columns().into_iter().flatten());
+
+    let index_size = index_size(header.features_count(), header.index_node_size());
+    let skipped = io::copy(&mut (&mut reader).take(index_size), &mut io::sink()).map_err(PolygonFinderError::Io)?;
+    if skipped != index_size {
+        return Err(invalid("the spatial index is truncated"));
+    }
+
+    let mut empty_geometries = 0;
+    while read_table(&mut reader, &mut buffer)? {
+        let feature = flatgeobuf::size_prefixed_root_as_feature(&buffer).map_err(|err| invalid(&err.to_string()))?;
+        let feature_columns = columns(feature.columns().into_iter().flatten());
+        let columns = match feature_columns.is_empty() {
+            true => &header_columns,
+            false => &feature_columns,
+        };
+
+        let mut geometry = match feature.geometry() {
+            Some(geometry) => Geometry::new(read_geometry(geometry, geometry_type)?),
+            None => {
+                empty_geometries += 1;
+                continue;
+            }
+        };
+        if !has_positions(&geometry) {
+            empty_geometries += 1;
+            continue;
+        }
+        if let Some(projection) = &projection {
+            projection.reproject(&mut geometry.value)?;
+        }
+        let properties = read_properties(feature.properties().map(|bytes| bytes.bytes()).unwrap_or_default(), columns)?;
+
+        on_feature(Feature {
+            bbox: None,
+            geometry: Some(geometry),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        })?;
+    }
+
+    if empty_geometries > 0 {
+        warn!("Skipped {} features without a geometry in {}", empty_geometries, path.display());
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> PolygonFinderError {
+    PolygonFinderError::InvalidFlatGeobuf(message.to_owned())
+}
+
+/**
+ * Reads a table with its size prefix into the buffer. False at the end of the file.
+ */
+fn read_table<R: io::Read>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<bool, PolygonFinderError> {
+    let mut size = [0; 4];
+    match reader.read(&mut size[..1]).map_err(PolygonFinderError::Io)? {
+        0 => return Ok(false),
+        _ => reader.read_exact(&mut size[1..]).map_err(|_| invalid("a feature is truncated"))?,
+    }
+
+    let table_size = u32::from_le_bytes(size) as usize;
+    if table_size > MAX_TABLE_SIZE {
+        return Err(invalid("a feature is too big"));
+    }
+    buffer.clear();
+    buffer.extend_from_slice(&size);
+    buffer.resize(size.len() + table_size, 0);
+    reader.read_exact(&mut buffer[size.len()..]).map_err(|_| invalid("a feature is truncated"))?;
+    Ok(true)
+}
+
+/**
+ * The size in bytes of the packed Hilbert R-tree after the header.
+ */
+fn index_size(features_count: u64, node_size: u16) -> u64 {
+    if node_size < 2 || features_count == 0 {
+        return 0;
+    }
+
+    let node_size = node_size as u64;
+    let mut level_nodes = features_count;
+    let mut nodes = features_count;
+    loop {
+        level_nodes = level_nodes.div_ceil(node_size);
+        nodes = nodes.saturating_add(level_nodes);
+        if level_nodes == 1 {
+            return nodes.saturating_mul(INDEX_NODE_ITEM_SIZE);
+        }
+    }
+}
+
+/// The names and types of the columns of the header or of a feature.
+fn columns<'a>(columns: impl IntoIterator<Item = flatgeobuf::Column<'a>>) -> Vec<(String, ColumnType)> {
+    columns.into_iter().map(|column| (column.name().to_owned(), column.type_())).collect()
+}
+
+/**
+ * A polygon (its rings end at `ends`, or a single ring without them), a multipolygon (its parts are polygons)
+ * or a point.
+ */
+fn read_geometry(
+    geometry: flatgeobuf::Geometry,
+    header_geometry_type: GeometryType,
+) -> Result<Value, PolygonFinderError> {
+    let geometry_type = match header_geometry_type {
+        GeometryType::Unknown => geometry.type_(),
+        geometry_type => geometry_type,
+    };
+
+    let xy: Vec<f64> = geometry.xy().iter().flatten().collect();
+    match geometry_type {
+        // Without coordinates when empty.
+        GeometryType::Point => Ok(Value::Point(xy.get(..2).unwrap_or_default().to_vec())),
+        GeometryType::Polygon => {
+            let mut ends: Vec<usize> = geometry.ends().iter().flatten().map(|end| end as usize).collect();
+            if ends.is_empty() {
+                ends.push(xy.len() / 2);
+            }
+
+            let mut rings: PolygonType = Vec::with_capacity(ends.len());
+            let mut start = 0;
+            for end in ends {
+                if end < start || end * 2 > xy.len() {
+                    return Err(invalid("the rings of a polygon are out of bounds"));
+                }
+                rings.push(xy[start * 2..end * 2].chunks_exact(2).map(<[f64]>::to_vec).collect::<Vec<Position>>());
+                start = end;
+            }
+            Ok(Value::Polygon(rings))
+        }
+        GeometryType::MultiPolygon => {
+            let polygons = geometry
+                .parts()
+                .iter()
+                .flatten()
+                .map(|part| match read_geometry(part, GeometryType::Polygon)? {
+                    Value::Polygon(rings) => Ok(rings),
+                    _ => Err(invalid("unexpected part of a multipolygon")),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Value::MultiPolygon(polygons))
+        }
+        _ => Err(PolygonFinderError::InvalidFeature),
+    }
+}
+
+/**
+ * The properties of a feature: a column index (`u16`) and its value, for each value that is not null.
+ * The columns without value are null.
+ */
+fn read_properties(bytes: &[u8], columns: &[(String, ColumnType)]) -> Result<JsonObject, PolygonFinderError> {
+    let mut properties: JsonObject =
+        columns.iter().map(|(name, _)| (name.clone(), serde_json::Value::Null)).collect();
+
+    let mut bytes = bytes;
+    let mut take = |count: usize| match bytes.len() >= count {
+        true => {
+            let (taken, rest) = bytes.split_at(count);
+            bytes = rest;
+            Ok(taken)
+        }
+        false => Err(invalid("the properties are truncated")),
+    };
+    while let Ok(column) = take(2) {
+        let column = u16::from_le_bytes(column.try_into().unwrap()) as usize;
+        let (name, column_type) = columns.get(column).ok_or_else(|| invalid("unknown column in the properties"))?;
+        let column_type = *column_type;
+
+        macro_rules! number {
+            ($type:ty) => {
+                serde_json::Value::from(<$type>::from_le_bytes(take(std::mem::size_of::<$type>())?.try_into().unwrap()))
+            };
+        }
+        let value = match column_type {
+            ColumnType::Byte => number!(i8),
+            ColumnType::UByte => number!(u8),
+            ColumnType::Bool => serde_json::Value::Bool(take(1)?[0] != 0),
+            ColumnType::Short => number!(i16),
+            ColumnType::UShort => number!(u16),
+            ColumnType::Int => number!(i32),
+            ColumnType::UInt => number!(u32),
+            ColumnType::Long => number!(i64),
+            ColumnType::ULong => number!(u64),
+            ColumnType::Float => number!(f32),
+            ColumnType::Double => number!(f64),
+            ColumnType::String | ColumnType::Json | ColumnType::DateTime | ColumnType::Binary => {
+                let length = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                let value = take(length)?;
+                match column_type {
+                    ColumnType::Json => serde_json::from_slice(value)
+                        .unwrap_or_else(|_| serde_json::Value::from(String::from_utf8_lossy(value))),
+                    // Binary values are not properties.
+                    ColumnType::Binary => {
+                        properties.remove(name);
+                        continue;
+                    }
+                    _ => serde_json::Value::from(String::from_utf8_lossy(value)),
+                }
+            }
+            _ => return Err(invalid("unknown column type")),
+        };
+        properties.insert(name.clone(), value);
+    }
+
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo_finder::test_utils::{assert_reprojected, MEXICO_LCC};
+    use flatgeobuf::{FgbCrs, FgbWriter, FgbWriterOptions};
+    use geozero::geojson::GeoJson;
+    use geozero::{ColumnValue, GeomProcessor, GeozeroGeometry, PropertyProcessor};
+
+
+    /// A geometry without coordinates.
+    struct EmptyGeometry;
+
+    impl GeozeroGeometry for EmptyGeometry {
+        fn process_geom<P: GeomProcessor>(&self, _processor: &mut P) -> geozero::error::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A feature: a GeoJSON geometry (empty for none) and the NOMGEO, POB and CAPITAL values that are not null.
+    type TestFeature<'a> = (&'a str, Option<&'a str>, Option<i64>, Option<bool>);
+
+    /**
+     * A FlatGeobuf file of the layer "municipios" with a NOMGEO (string), POB (long), AREA (double) and CAPITAL
+     * (bool) columns, and its spatial index. The features are sorted on the index, not in the given order.
+     */
+    fn flatgeobuf(geometry_type: GeometryType, features: &[TestFeature], crs_wkt: Option<&str>) -> Vec<u8> {
+        let options = FgbWriterOptions {
+            write_index: true,
+            crs: FgbCrs { wkt: crs_wkt, ..Default::default() },
+            ..Default::default()
+        };
+        let mut writer = FgbWriter::create_with_options("municipios", geometry_type, options).unwrap();
+        writer.add_column("NOMGEO", ColumnType::String, |_, _| {});
+        writer.add_column("POB", ColumnType::Long, |_, _| {});
+        writer.add_column("AREA", ColumnType::Double, |_, _| {});
+        writer.add_column("CAPITAL", ColumnType::Bool, |_, _| {});
+
+        for feature in features {
+            match feature.0.is_empty() {
+                true => writer.add_feature_geom(EmptyGeometry, |writer| add_properties(writer, feature)).unwrap(),
+                false => writer.add_feature_geom(GeoJson(feature.0), |writer| add_properties(writer, feature)).unwrap(),
+            }
+        }
+
+        let mut file = Vec::new();
+        writer.write(&mut file).unwrap();
+        file
+    }
+
+    fn add_properties<P: PropertyProcessor>(writer: &mut P, (_, name, population, capital): &TestFeature) {
+        if let Some(name) = name {
+            writer.property(0, "NOMGEO", &ColumnValue::String(name)).unwrap();
+        }
+        if let Some(population) = population {
+            writer.property(1, "POB", &ColumnValue::Long(*population)).unwrap();
+        }
+        if let Some(capital) = capital {
+            writer.property(3, "CAPITAL", &ColumnValue::Bool(*capital)).unwrap();
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Result<Vec<Feature>, PolygonFinderError> {
+        let mut features = Vec::new();
+        read_features(Path::new("test.fgb"), bytes, |feature| {
+            features.push(feature);
+            Ok(())
+        })?;
+        Ok(features)
+    }
+
+    fn name(feature: &Feature) -> &serde_json::Value {
+        &feature.properties.as_ref().unwrap()["NOMGEO"]
+    }
+
+    #[test]
+    fn it_should_read_polygons_and_properties() {
+        let multi_polygon = r#"{"type": "MultiPolygon", "coordinates": [
+            [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]], [[[2, 2], [3, 2], [3, 3], [2, 3], [2, 2]]]
+        ]}"#;
+        let polygon = r#"{"type": "Polygon", "coordinates": [
+            [[10, 10], [20, 10], [20, 20], [10, 20], [10, 10]], [[14, 14], [16, 14], [16, 16], [14, 16], [14, 14]]
+        ]}"#;
+        let file = flatgeobuf(
+            GeometryType::MultiPolygon,
+            &[(multi_polygon, None, None, None), (polygon, Some("Colima"), Some(731391), Some(true))],
+            None,
+        );
+
+        let mut features = read(&file).unwrap();
+        features.sort_by_key(|feature| name(feature).is_string());
+
+        assert_eq!(features.len(), 2);
+        match &features[0].geometry.as_ref().unwrap().value {
+            Value::MultiPolygon(polygons) => assert_eq!(polygons.len(), 2),
+            _ => panic!("Wrong geometry"),
+        }
+        match &features[1].geometry.as_ref().unwrap().value {
+            Value::MultiPolygon(polygons) => {
+                assert_eq!(polygons.len(), 1);
+                assert_eq!(polygons[0].len(), 2);
+                assert_eq!(polygons[0][1][0], vec![14.0, 14.0]);
+            }
+            _ => panic!("Wrong geometry"),
+        }
+        let properties = features[1].properties.as_ref().unwrap();
+        assert_eq!(properties["NOMGEO"], "Colima");
+        assert_eq!(properties["POB"], 731391);
+        assert_eq!(properties["CAPITAL"], true);
+        assert!(properties["AREA"].is_null());
+        assert!(name(&features[0]).is_null());
+    }
+
+    #[test]
+    fn it_should_skip_the_features_without_a_geometry() {
+        let polygon = r#"{"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}"#;
+        let file = flatgeobuf(
+            GeometryType::Polygon,
+            &[("", Some("Armería"), None, None), (polygon, Some("Colima"), None, None)],
+            None,
+        );
+        let mut without_geometry = flatbuffers::FlatBufferBuilder::new();
+        let feature = flatgeobuf::Feature::create(&mut without_geometry, &Default::default());
+        without_geometry.finish_size_prefixed(feature, None);
+
+        let features = read(&[&file, without_geometry.finished_data()].concat()).unwrap();
+
+        assert_eq!(features.len(), 1);
+        assert_eq!(name(&features[0]), "Colima");
+    }
+
+    #[test]
+    fn it_should_fail_when_the_layer_is_not_polygonal() {
+        let file = flatgeobuf(GeometryType::LineString, &[], None);
+
+        match read(&file) {
+            Err(PolygonFinderError::NotPolygonal(layer, geometry_type)) => {
+                assert_eq!((layer.as_str(), geometry_type.as_str()), ("municipios", "LineString"));
+            }
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_reproject_projected_coordinates() {
+        let square = r#"{"type": "Polygon", "coordinates": [[
+            [2800000, 2800000], [2810000, 2800000], [2810000, 2810000], [2800000, 2810000], [2800000, 2800000]
+        ]]}"#;
+        let file = flatgeobuf(GeometryType::Polygon, &[(square, None, None, None)], Some(MEXICO_LCC));
+
+        let features = read(&file).unwrap();
+
+        assert_reprojected(&features[0]);
+    }
+
+    #[test]
+    fn it_should_fail_without_panicking_on_corrupted_bytes() {
+        let polygon = r#"{"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}"#;
+        let file = flatgeobuf(GeometryType::Polygon, &[(polygon, Some("Colima"), Some(1), None)], None);
+        assert_eq!(read(&file).unwrap().len(), 1);
+
+        for position in MAGIC_SIZE..file.len() {
+            for mask in &[0xff, 0x01, 0x80] {
+                let mut corrupted = file.clone();
+                corrupted[position] ^= mask;
+                let _ = read(&corrupted);
+            }
+            let _ = read(&file[..position]);
+        }
+    }
+
+    #[test]
+    fn it_should_compute_the_size_of_the_index() {
+        assert_eq!(index_size(0, 16), 0);
+        assert_eq!(index_size(1, 16), 2 * INDEX_NODE_ITEM_SIZE);
+        assert_eq!(index_size(100, 16), (100 + 7 + 1) * INDEX_NODE_ITEM_SIZE);
+        assert_eq!(index_size(100, 0), 0);
+        assert_eq!(index_size(u64::MAX, 2), u64::MAX);
+    }
+}
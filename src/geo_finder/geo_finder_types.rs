@@ -1,9 +1,300 @@
-use std::collections::HashMap;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 
-pub type PropertyMap = HashMap<String, String>;
+use super::index_file::{IndexFileError, IndexLayout};
+
+pub type PropertyMap = HashMap<String, PropertyValue>;
+
+/// A GeoJSON property value.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PropertyValue {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    /// Integers above `i64::MAX`.
+    UInteger(u64),
+    Float(f64),
+    String(String),
+    /// Arrays and objects, as JSON text (the index format can't hold a `serde_json::Value`).
+    Json(String),
+}
+
+impl PropertyValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PropertyValue::Null => "null",
+            PropertyValue::Bool(_) => "bool",
+            PropertyValue::Integer(_) | PropertyValue::UInteger(_) => "integer",
+            PropertyValue::Float(_) => "float",
+            PropertyValue::String(_) => "string",
+            PropertyValue::Json(_) => "json",
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Value> for PropertyValue {
+    fn from(value: serde_json::Value) -> PropertyValue {
+        match value {
+            serde_json::Value::Null => PropertyValue::Null,
+            serde_json::Value::Bool(value) => PropertyValue::Bool(value),
+            serde_json::Value::Number(value) => match (value.as_i64(), value.as_u64()) {
+                (Some(integer), _) => PropertyValue::Integer(integer),
+                (None, Some(integer)) => PropertyValue::UInteger(integer),
+                (None, None) => PropertyValue::Float(value.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(value) => PropertyValue::String(value),
+            nested => PropertyValue::Json(nested.to_string()),
+        }
+    }
+}
+
+impl From<&PropertyValue> for serde_json::Value {
+    fn from(value: &PropertyValue) -> serde_json::Value {
+        match value {
+            PropertyValue::Null => serde_json::Value::Null,
+            PropertyValue::Bool(value) => serde_json::Value::Bool(*value),
+            PropertyValue::Integer(value) => serde_json::Value::from(*value),
+            PropertyValue::UInteger(value) => serde_json::Value::from(*value),
+            // Null when not finite: JSON does not have them.
+            PropertyValue::Float(value) => serde_json::Value::from(*value),
+            PropertyValue::String(value) => serde_json::Value::String(value.clone()),
+            PropertyValue::Json(json) => {
+                serde_json::from_str(json).unwrap_or_else(|_| serde_json::Value::String(json.clone()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PropertyValue::Null => write!(f, "null"),
+            PropertyValue::Bool(value) => write!(f, "{}", value),
+            PropertyValue::Integer(value) => write!(f, "{}", value),
+            PropertyValue::UInteger(value) => write!(f, "{}", value),
+            PropertyValue::Float(value) => write!(f, "{}", value),
+            PropertyValue::String(value) => write!(f, "{}", value),
+            PropertyValue::Json(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl From<&str> for PropertyValue {
+    fn from(value: &str) -> PropertyValue {
+        PropertyValue::String(value.to_owned())
+    }
+}
+
+impl PartialEq<&str> for PropertyValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == Some(*other)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchType {
+    /// The point is inside the geometry.
+    Inside,
+    /// The point is outside every geometry, this is the nearest one.
+    Nearest,
+}
+
+impl MatchType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MatchType::Inside => "inside",
+            MatchType::Nearest => "nearest",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct FindResult<'a> {
-    pub props: &'a PropertyMap,
+    /// Identifies the feature: its position in the files the index was generated from.
+    pub feature: usize,
+    /// Borrowed from the index, or decoded from it for the memory mapped layout.
+    pub props: Cow<'a, PropertyMap>,
+    pub match_type: MatchType,
+    /// Distance in meters from the point to the geometry (0 when inside).
     pub distance: f64,
+    /// Unsigned area of the matched geometry, in squared degrees (0 for points).
+    pub area: f64,
+}
+
+/**
+ * Lookups, whatever the layout of the index. They fail when the index turns out to be corrupted: the memory
+ * mapped one is only checked as it is read.
+ */
+pub trait GeoFinder: Sync {
+    fn layout(&self) -> IndexLayout;
+
+    fn feature_count(&self) -> usize;
+
+    /// The first feature containing the point.
+    fn find(&self, latitude: f64, longitude: f64) -> Result<Option<Box<FindResult<'_>>>, IndexFileError>;
+
+    /// Every feature containing the point (useful when the layers overlap).
+    fn find_all(&self, latitude: f64, longitude: f64) -> Result<Vec<FindResult<'_>>, IndexFileError>;
+
+    /// The feature nearest to the point, within `max_distance` meters.
+    fn find_nearest(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        max_distance: f64,
+    ) -> Result<Option<Box<FindResult<'_>>>, IndexFileError>;
+
+    /// For each property missing in some feature, the number of features without it.
+    fn missing_properties<'p>(&self, properties: &[&'p str]) -> Vec<(&'p str, usize)>;
+
+    /// Describes the contents of the index.
+    fn stats(&self, max_samples: usize) -> Result<IndexStats, IndexFileError>;
+
+    /// Every feature, with its geometry, in no particular order.
+    fn indexed_features(&self) -> Box<dyn Iterator<Item = Result<IndexedFeature<'_>, IndexFileError>> + '_>;
+}
+
+/// A feature of the index, as read from its files.
+pub struct IndexedFeature<'a> {
+    /// Like `FindResult::feature`.
+    pub id: usize,
+    pub props: Cow<'a, PropertyMap>,
+    /// Cloned or decoded from the index only when asked for.
+    geometry: Box<dyn Fn() -> Result<geo_types::Geometry<f64>, IndexFileError> + 'a>,
+}
+
+impl<'a> IndexedFeature<'a> {
+    pub fn new<G>(id: usize, props: Cow<'a, PropertyMap>, geometry: G) -> IndexedFeature<'a>
+    where
+        G: Fn() -> Result<geo_types::Geometry<f64>, IndexFileError> + 'a,
+    {
+        IndexedFeature { id, props, geometry: Box::new(geometry) }
+    }
+
+    pub fn geometry(&self) -> Result<geo_types::Geometry<f64>, IndexFileError> {
+        (self.geometry)()
+    }
+}
+
+/// Summary of the contents of an index.
+#[derive(Debug, serde::Serialize)]
+pub struct IndexStats {
+    pub feature_count: usize,
+    pub polygons: usize,
+    pub multi_polygons: usize,
+    pub points: usize,
+    /// Features with a prepared geometry (lots of vertices).
+    pub prepared: usize,
+    pub vertices: usize,
+    /// Min x, min y, max x, max y.
+    pub bbox: Option<[f64; 4]>,
+    pub tree_depth: usize,
+    /// Estimated, in bytes.
+    pub memory_size: usize,
+    pub properties: Vec<PropertyStats>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PropertyStats {
+    pub name: String,
+    pub types: Vec<String>,
+    /// Distinct values, of the same type. Counted up to `MAX_DISTINCT_VALUES`.
+    pub cardinality: usize,
+    /// There are more distinct values than `cardinality`.
+    pub cardinality_capped: bool,
+    /// Features without the property.
+    pub missing: usize,
+    pub samples: Vec<String>,
+}
+
+/// What is gathered of a property while going through the features.
+#[derive(Default)]
+struct CollectedProperty {
+    types: BTreeSet<&'static str>,
+    /// By type name and text, so `1` and `"1"` are different values.
+    values: BTreeSet<(&'static str, String)>,
+    capped: bool,
+    present: usize,
+}
+
+impl PropertyStats {
+    /// The distinct values kept of each property, so big indexes don't hold all of them in memory.
+    pub const MAX_DISTINCT_VALUES: usize = 10_000;
+
+    /**
+     * Stats of every property found in the features.
+     */
+    pub fn collect<I>(features: I, max_samples: usize) -> Vec<PropertyStats>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<PropertyMap>,
+    {
+        let mut feature_count = 0;
+        let mut properties: BTreeMap<String, CollectedProperty> = BTreeMap::new();
+        for feature in features {
+            feature_count += 1;
+            for (name, value) in feature.borrow() {
+                let property = properties.entry(name.clone()).or_default();
+                property.types.insert(value.type_name());
+                let value = (value.type_name(), value.to_string());
+                if property.values.len() < PropertyStats::MAX_DISTINCT_VALUES || property.values.contains(&value) {
+                    property.values.insert(value);
+                } else {
+                    property.capped = true;
+                }
+                property.present += 1;
+            }
+        }
+
+        properties
+            .into_iter()
+            .map(|(name, property)| PropertyStats {
+                name,
+                types: property.types.into_iter().map(String::from).collect(),
+                cardinality: property.values.len(),
+                cardinality_capped: property.capped,
+                missing: feature_count - property.present,
+                samples: property.values.into_iter().take(max_samples).map(|(_, value)| value).collect(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(values: Vec<PropertyValue>) -> Vec<PropertyMap> {
+        values.into_iter().map(|value| std::iter::once(("code".to_owned(), value)).collect()).collect()
+    }
+
+    #[test]
+    fn it_should_tell_apart_values_of_different_types() {
+        let features = features(vec![PropertyValue::Integer(1), PropertyValue::from("1"), PropertyValue::Integer(1)]);
+
+        let stats = PropertyStats::collect(&features, 10);
+
+        assert_eq!(stats[0].types, vec!["integer", "string"]);
+        assert_eq!((stats[0].cardinality, stats[0].cardinality_capped), (2, false));
+        assert_eq!(stats[0].samples, vec!["1", "1"]);
+    }
+
+    #[test]
+    fn it_should_stop_counting_the_distinct_values_at_the_max() {
+        let max = PropertyStats::MAX_DISTINCT_VALUES;
+        let features = features((0..max as i64 + 5).map(PropertyValue::Integer).collect());
+
+        let stats = PropertyStats::collect(&features, 2);
+
+        assert_eq!((stats[0].cardinality, stats[0].cardinality_capped, stats[0].missing), (max, true, 0));
+        assert_eq!(stats[0].samples, vec!["0", "1"]);
+    }
 }
@@ -0,0 +1,377 @@
+use geojson::{Feature, Geometry};
+
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+
+use std::fmt;
+use std::io;
+
+use super::super::cli_utils;
+use super::polygon_finder::PolygonFinderError;
+use super::JsonObject;
+
+/**
+ * Reads the features of a GeoJSON document one at a time, so the whole document is never in memory. `on_feature`
+ * gets the features in order, and the first error it returns stops the reading.
+ *
+ * The document can be a `FeatureCollection`, a `Feature` or a geometry (a one feature collection, without
+ * properties), or a sequence of them: newline-delimited (one per line) or a GeoJSON text sequence (RFC 8142).
+ */
+pub fn read_features<R, F>(reader: R, on_feature: F) -> Result<(), PolygonFinderError>
+where
+    R: io::BufRead,
+    F: FnMut(Feature) -> Result<(), PolygonFinderError>,
+{
+    let mut on_feature = on_feature;
+    let has_texts = read_geojson_texts(reader, |text| {
+        let feature = match text {
+            GeoJsonText::Feature(serde_json::Value::Object(object)) => Feature::from_json_object(object)?,
+            GeoJsonText::Feature(_) => return Err(PolygonFinderError::FeatureCollectionNotFound),
+            GeoJsonText::Object(object) => read_feature(object)?,
+        };
+        on_feature(feature).map(|()| true)
+    })?;
+
+    match has_texts {
+        true => Ok(()),
+        false => Err(PolygonFinderError::Parse(geojson::Error::MalformedJson)),
+    }
+}
+
+/**
+ * A `Feature`, or a feature without properties for a geometry.
+ */
+fn read_feature(object: JsonObject) -> Result<Feature, PolygonFinderError> {
+    match object.get("type").and_then(serde_json::Value::as_str) {
+        Some("Feature") => Ok(Feature::from_json_object(object)?),
+        Some("Point") | Some("MultiPoint") | Some("LineString") | Some("MultiLineString") | Some("Polygon")
+        | Some("MultiPolygon") | Some("GeometryCollection") => Ok(Feature {
+            bbox: None,
+            geometry: Some(Geometry::from_json_object(object)?),
+            id: None,
+            properties: None,
+            foreign_members: None,
+        }),
+        _ => Err(PolygonFinderError::FeatureCollectionNotFound),
+    }
+}
+
+/**
+ * A JSON text of a GeoJSON document, or of a sequence of them.
+ */
+pub enum GeoJsonText {
+    /// One of the features of a FeatureCollection, usually a `Feature` object (it is not checked).
+    Feature(serde_json::Value),
+    /// Any other JSON object, with all its members.
+    Object(JsonObject),
+}
+
+/**
+ * Reads the JSON texts of a GeoJSON document one at a time: a single one, newline-delimited (one per line) or a
+ * GeoJSON text sequence (RFC 8142). `on_text` gets them in order, and stops the reading when it returns false or
+ * fails. Returns whether the document had any text.
+ *
+ * The features of a FeatureCollection are streamed so the whole document is never in memory, once it is known to
+ * be a FeatureCollection: the ones before "type" are kept, and passed at the end of the collection.
+ */
+pub fn read_geojson_texts<R, E, F>(reader: R, on_text: F) -> Result<bool, E>
+where
+    R: io::BufRead,
+    E: From<serde_json::Error>,
+    F: FnMut(GeoJsonText) -> Result<bool, E>,
+{
+    let mut reader = reader;
+    let mut on_text = on_text;
+    let mut stop = None;
+    let mut is_empty = true;
+
+    while cli_utils::skip_json_separators(&mut reader).map_err(serde_json::Error::io)? {
+        is_empty = false;
+
+        // A new deserializer for each text: it does not read past the end of the object.
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+        let result = deserializer.deserialize_map(TextVisitor { on_text: &mut on_text, stop: &mut stop });
+
+        match (result, stop.take()) {
+            (Ok(()), _) => {}
+            // Stopped by us.
+            (Err(_), Some(stop)) => return stop.map(|()| true),
+            (Err(err), None) => return Err(E::from(err)),
+        }
+    }
+    Ok(!is_empty)
+}
+
+/**
+ * Passes a text to `on_text`. Fails to stop the deserialization, keeping why in `stop`.
+ */
+fn emit<F, E, D>(on_text: &mut F, stop: &mut Option<Result<(), E>>, text: GeoJsonText) -> Result<(), D>
+where
+    F: FnMut(GeoJsonText) -> Result<bool, E>,
+    D: de::Error,
+{
+    match on_text(text) {
+        Ok(true) => Ok(()),
+        result => {
+            *stop = Some(result.map(|_| ()));
+            Err(D::custom("stopped"))
+        }
+    }
+}
+
+struct TextVisitor<'a, F, E> {
+    on_text: &'a mut F,
+    stop: &'a mut Option<Result<(), E>>,
+}
+
+impl<'de, 'a, F, E> Visitor<'de> for TextVisitor<'a, F, E>
+where
+    F: FnMut(GeoJsonText) -> Result<bool, E>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a GeoJSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let TextVisitor { on_text, stop } = self;
+        let mut object = JsonObject::new();
+        let mut has_features = false;
+
+        // The members can come in any order ("type" is usually first, but not always).
+        while let Some(key) = map.next_key::<String>()? {
+            let is_collection = object.get("type").is_some_and(|value| value == "FeatureCollection");
+            if key == "features" && is_collection {
+                map.next_value_seed(FeaturesSeed { on_text: &mut *on_text, stop: &mut *stop })?;
+                has_features = true;
+            } else {
+                object.insert(key, map.next_value()?);
+            }
+        }
+
+        let is_collection = object.get("type").is_some_and(|value| value == "FeatureCollection");
+        match (has_features, is_collection, object.remove("features")) {
+            (true, _, _) => Ok(()),
+            (false, true, Some(serde_json::Value::Array(features))) => features
+                .into_iter()
+                .try_for_each(|feature| emit(on_text, stop, GeoJsonText::Feature(feature))),
+            (false, _, features) => {
+                if let Some(features) = features {
+                    object.insert("features".to_owned(), features);
+                }
+                emit(on_text, stop, GeoJsonText::Object(object))
+            }
+        }
+    }
+}
+
+/// The features of a FeatureCollection, passed one at a time.
+struct FeaturesSeed<'a, F, E> {
+    on_text: &'a mut F,
+    stop: &'a mut Option<Result<(), E>>,
+}
+
+impl<'de, 'a, F, E> DeserializeSeed<'de> for FeaturesSeed<'a, F, E>
+where
+    F: FnMut(GeoJsonText) -> Result<bool, E>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, F, E> Visitor<'de> for FeaturesSeed<'a, F, E>
+where
+    F: FnMut(GeoJsonText) -> Result<bool, E>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of GeoJSON features")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(feature) = seq.next_element()? {
+            emit(self.on_text, self.stop, GeoJsonText::Feature(feature))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature_names(geojson_str: &str) -> Result<Vec<String>, PolygonFinderError> {
+        let mut names = Vec::new();
+        read_features(geojson_str.as_bytes(), |feature| {
+            let name = feature.properties.as_ref().and_then(|properties| properties["NAME"].as_str());
+            names.push(name.unwrap_or_default().to_owned());
+            Ok(())
+        })?;
+        Ok(names)
+    }
+
+    #[test]
+    fn it_should_read_the_features_in_order_whatever_the_member_order() {
+        let geojson_str = r#"{"name": "layer", "crs": {"type": "name", "properties": {"name": "EPSG:4326"}},
+            "features": [
+                {"type": "Feature", "properties": {"NAME": "a"}, "geometry": {"type": "Point", "coordinates": [0, 0]}},
+                {"type": "Feature", "properties": {"NAME": "b"}, "geometry": {"type": "Point", "coordinates": [1, 1]}}
+            ],
+            "type": "FeatureCollection"}"#;
+
+        assert_eq!(feature_names(geojson_str).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn it_should_stop_at_the_first_error_of_the_callback() {
+        let geojson_str = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [0, 0]}},
+            {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [1, 1]}}
+        ]}"#;
+
+        let mut count = 0;
+        let result = read_features(geojson_str.as_bytes(), |_| {
+            count += 1;
+            Err(PolygonFinderError::InvalidFeature)
+        });
+
+        assert_eq!(count, 1);
+        match result {
+            Err(PolygonFinderError::InvalidFeature) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_read_newline_delimited_features() {
+        let geojson_str = concat!(
+            r#"{"type": "Feature", "properties": {"NAME": "a"}, "geometry": {"type": "Point", "coordinates": [0, 0]}}"#,
+            "\n",
+            r#"{"type": "Feature", "properties": {"NAME": "b"}, "geometry": {"type": "Point", "coordinates": [1, 1]}}"#,
+            "\n",
+        );
+
+        assert_eq!(feature_names(geojson_str).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn it_should_read_a_geojson_text_sequence() {
+        let geojson_str = concat!(
+            "\u{1e}",
+            r#"{"type": "Feature", "properties": {"NAME": "a"}, "geometry": {"type": "Point", "coordinates": [0, 0]}}"#,
+            "\n\u{1e}",
+            r#"{"type": "FeatureCollection", "features": [
+                {"type": "Feature", "properties": {"NAME": "b"}, "geometry": {"type": "Point", "coordinates": [1, 1]}}
+            ]}"#,
+            "\n",
+        );
+
+        assert_eq!(feature_names(geojson_str).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn it_should_read_a_bare_feature_or_geometry() {
+        let feature_str = r#"{"geometry": {"type": "Point", "coordinates": [0, 0]}, "type": "Feature",
+            "properties": {"NAME": "a"}, "title": "foreign member"}"#;
+        let geometry_str = r#"{"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 0]]]}"#;
+
+        assert_eq!(feature_names(feature_str).unwrap(), vec!["a"]);
+
+        let mut features = Vec::new();
+        read_features(geometry_str.as_bytes(), |feature| {
+            features.push(feature);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(features.len(), 1);
+        assert!(features[0].properties.is_none());
+        assert!(features[0].geometry.is_some());
+    }
+
+    #[test]
+    fn it_should_fail_without_geojson_features() {
+        let geojson_strs = [
+            r#"{"type": "Topology", "objects": {}}"#,
+            r#"{"type": "FeatureCollection"}"#,
+            r#"{"features": []}"#,
+            "[]",
+        ];
+        for geojson_str in &geojson_strs {
+            match feature_names(geojson_str) {
+                Err(PolygonFinderError::FeatureCollectionNotFound) => {}
+                _ => panic!("Wrong Error"),
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_not_pass_the_features_of_a_document_without_type() {
+        let geojson_str = r#"{"features": [
+            {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [0, 0]}}
+        ]}"#;
+
+        let mut count = 0;
+        let result = read_features(geojson_str.as_bytes(), |_| {
+            count += 1;
+            Ok(())
+        });
+
+        assert_eq!(count, 0);
+        match result {
+            Err(PolygonFinderError::FeatureCollectionNotFound) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_pass_the_features_of_a_collection_and_the_other_objects() {
+        let geojson_str = concat!(
+            r#"{"features": [{"id": 1}, 2], "type": "FeatureCollection", "name": "layer"}"#,
+            "\n",
+            r#"{"type": "FeatureCollection", "features": [{"id": 3}]}"#,
+            "\n",
+            r#"{"features": [{"id": 4}], "name": "no type"}"#,
+        );
+
+        let mut texts = Vec::new();
+        let has_texts = read_geojson_texts(geojson_str.as_bytes(), |text| {
+            texts.push(match text {
+                GeoJsonText::Feature(feature) => feature,
+                GeoJsonText::Object(object) => serde_json::Value::Object(object),
+            });
+            Ok::<_, PolygonFinderError>(true)
+        })
+        .unwrap();
+
+        assert!(has_texts);
+        assert_eq!(
+            texts,
+            vec![
+                serde_json::json!({"id": 1}),
+                serde_json::json!(2),
+                serde_json::json!({"id": 3}),
+                serde_json::json!({"features": [{"id": 4}], "name": "no type"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_fail_with_an_empty_document() {
+        match feature_names(" \n") {
+            Err(PolygonFinderError::Parse(geojson::Error::MalformedJson)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_fail_with_trailing_characters() {
+        match feature_names(r#"{"type": "FeatureCollection", "features": []} {"#) {
+            Err(PolygonFinderError::Parse(geojson::Error::MalformedJson)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
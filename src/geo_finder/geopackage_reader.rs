@@ -0,0 +1,323 @@
+use geojson::{Feature, Geometry};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+
+use log::warn;
+use std::path::Path;
+
+use super::polygon_finder::{has_positions, PolygonFinderError};
+use super::projection::Projection;
+use super::wkb;
+use super::JsonObject;
+
+/// The geometry types that can be indexed. `GEOMETRY` layers are checked feature by feature.
+const INDEXABLE_GEOMETRY_TYPES: [&str; 4] = ["POLYGON", "MULTIPOLYGON", "POINT", "GEOMETRY"];
+
+/// Flag of the GeoPackage geometry header.
+const EMPTY_FLAG: u8 = 0b0001_0000;
+
+/**
+ * Reads the features of a layer of a GeoPackage. The layer can be omitted when the file has only one. The
+ * columns of the table are the properties, except the primary key. Features with a NULL or empty geometry are
+ * skipped, and projected coordinates are converted to longitude and latitude.
+ */
+pub fn read_features<F>(path: &Path, layer: Option<&str>, on_feature: F) -> Result<(), PolygonFinderError>
+where
+    F: FnMut(Feature) -> Result<(), PolygonFinderError>,
+{
+    let mut on_feature = on_feature;
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let layers = connection
+        .prepare("SELECT table_name FROM gpkg_contents WHERE data_type = 'features' ORDER BY table_name")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let layer = match (layer, layers.as_slice()) {
+        (Some(layer), _) if layers.iter().any(|name| name == layer) => layer,
+        (None, [layer]) => layer,
+        (Some(layer), _) => return Err(PolygonFinderError::LayerNotFound(layer.to_owned(), layers.join(", "))),
+        (None, _) => return Err(PolygonFinderError::LayerRequired(layers.join(", "))),
+    };
+
+    let (geometry_column, geometry_type, srs_definition): (String, String, String) = connection.query_row(
+        "SELECT column_name, geometry_type_name, definition
+         FROM gpkg_geometry_columns JOIN gpkg_spatial_ref_sys USING (srs_id)
+         WHERE table_name = ?",
+        [layer],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    if !INDEXABLE_GEOMETRY_TYPES.contains(&geometry_type.to_uppercase().as_str()) {
+        return Err(PolygonFinderError::NotPolygonal(layer.to_owned(), geometry_type));
+    }
+    let projection = Projection::from_wkt(&srs_definition)?;
+
+    let mut property_columns = Vec::new();
+    let mut boolean_columns = Vec::new();
+    {
+        let mut statement = connection.prepare(&format!("PRAGMA table_info({})", quote(layer)))?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let (name, declared_type, primary_key): (String, String, i64) = (row.get(1)?, row.get(2)?, row.get(5)?);
+            if name != geometry_column && primary_key == 0 {
+                boolean_columns.push(declared_type.eq_ignore_ascii_case("BOOLEAN"));
+                property_columns.push(name);
+            }
+        }
+    }
+
+    let columns: Vec<String> = std::iter::once(&geometry_column)
+        .chain(property_columns.iter())
+        .map(|column| quote(column))
+        .collect();
+    let mut statement = connection.prepare(&format!("SELECT {} FROM {}", columns.join(", "), quote(layer)))?;
+    let mut rows = statement.query([])?;
+    let mut empty_geometries = 0;
+    while let Some(row) = rows.next()? {
+        let geometry = match row.get_ref(0)? {
+            ValueRef::Blob(blob) => read_geometry(blob)?.filter(has_positions),
+            _ => None,
+        };
+        let mut geometry = match geometry {
+            Some(geometry) => geometry,
+            None => {
+                empty_geometries += 1;
+                continue;
+            }
+        };
+        if let Some(projection) = &projection {
+            projection.reproject(&mut geometry.value)?;
+        }
+
+        let mut properties = JsonObject::new();
+        for (index, name) in property_columns.iter().enumerate() {
+            let value = match row.get_ref(index + 1)? {
+                ValueRef::Integer(value) if boolean_columns[index] => serde_json::Value::Bool(value != 0),
+                ValueRef::Integer(value) => serde_json::Value::from(value),
+                ValueRef::Real(value) => serde_json::Value::from(value),
+                ValueRef::Text(value) => serde_json::Value::from(String::from_utf8_lossy(value)),
+                // Binary values are not properties.
+                ValueRef::Blob(_) => continue,
+                ValueRef::Null => serde_json::Value::Null,
+            };
+            properties.insert(name.clone(), value);
+        }
+
+        on_feature(Feature {
+            bbox: None,
+            geometry: Some(geometry),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        })?;
+    }
+
+    if empty_geometries > 0 {
+        warn!("Skipped {} features without a geometry in the layer {}", empty_geometries, layer);
+    }
+    Ok(())
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/**
+ * A GeoPackage geometry: a header (with an optional envelope) and the geometry in WKB. `None` when empty.
+ */
+fn read_geometry(blob: &[u8]) -> Result<Option<Geometry>, PolygonFinderError> {
+    let invalid = || PolygonFinderError::InvalidWkb("not a GeoPackage geometry".to_owned());
+    if blob.len() < 8 || &blob[..2] != b"GP" {
+        return Err(invalid());
+    }
+
+    // The byte order flag only matters for the SRS id and the envelope, which are not needed.
+    let flags = blob[3];
+    if flags & EMPTY_FLAG != 0 {
+        return Ok(None);
+    }
+    let envelope_size = match (flags >> 1) & 0b111 {
+        0 => 0,
+        1 => 32,
+        2 | 3 => 48,
+        4 => 64,
+        _ => return Err(invalid()),
+    };
+
+    let wkb = blob.get(8 + envelope_size..).ok_or_else(invalid)?;
+    Ok(Some(Geometry::new(wkb::read_geometry(wkb)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo_finder::test_utils::{assert_reprojected, MEXICO_LCC};
+    use std::fs;
+
+    const WGS84: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]]]"#;
+
+    /// A polygon as a GeoPackage geometry without envelope.
+    fn square_geometry(min: f64, max: f64) -> Vec<u8> {
+        let mut blob = vec![b'G', b'P', 0, 1];
+        blob.extend_from_slice(&4326i32.to_le_bytes());
+        blob.push(1);
+        blob.extend_from_slice(&3u32.to_le_bytes());
+        blob.extend_from_slice(&1u32.to_le_bytes());
+        blob.extend_from_slice(&5u32.to_le_bytes());
+        for (x, y) in &[(min, min), (max, min), (max, max), (min, max), (min, min)] {
+            blob.extend_from_slice(&x.to_le_bytes());
+            blob.extend_from_slice(&y.to_le_bytes());
+        }
+        blob
+    }
+
+    /// A GeoPackage in a temporary file, removed when dropped.
+    struct TestGeoPackage {
+        path: std::path::PathBuf,
+    }
+
+    impl TestGeoPackage {
+        fn new(name: &str, layers: &[(&str, &str, &str)]) -> Self {
+            let path = std::env::temp_dir().join(format!("fsj-{}-{}.gpkg", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            let connection = Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "CREATE TABLE gpkg_spatial_ref_sys (srs_name TEXT, srs_id INTEGER PRIMARY KEY, definition TEXT);
+                     CREATE TABLE gpkg_contents (table_name TEXT PRIMARY KEY, data_type TEXT);
+                     CREATE TABLE gpkg_geometry_columns (table_name TEXT, column_name TEXT, geometry_type_name TEXT,
+                                                         srs_id INTEGER);",
+                )
+                .unwrap();
+            connection
+                .execute("INSERT INTO gpkg_spatial_ref_sys VALUES ('WGS 84', 4326, ?)", [WGS84])
+                .unwrap();
+            connection
+                .execute("INSERT INTO gpkg_spatial_ref_sys VALUES ('Mexico ITRF2008 / LCC', 6372, ?)", [MEXICO_LCC])
+                .unwrap();
+
+            for (layer, geometry_type, srs_id) in layers {
+                connection
+                    .execute_batch(&format!(
+                        "CREATE TABLE {layer} (fid INTEGER PRIMARY KEY, geom BLOB, NOMGEO TEXT, POB INTEGER, AREA REAL,
+                                               CAPITAL BOOLEAN, FOTO BLOB);
+                         INSERT INTO gpkg_contents VALUES ('{layer}', 'features');
+                         INSERT INTO gpkg_geometry_columns VALUES ('{layer}', 'geom', '{geometry_type}', {srs_id});",
+                        layer = layer,
+                        geometry_type = geometry_type,
+                        srs_id = srs_id,
+                    ))
+                    .unwrap();
+                connection
+                    .execute(
+                        &format!("INSERT INTO {} VALUES (1, ?, 'Colima', 731391, 5627.1, 1, x'00')", layer),
+                        [square_geometry(0.0, 1.0)],
+                    )
+                    .unwrap();
+                connection
+                    .execute(
+                        &format!("INSERT INTO {} VALUES (2, ?, 'Manzanillo', NULL, NULL, 0, NULL)", layer),
+                        [square_geometry(1.0, 2.0)],
+                    )
+                    .unwrap();
+            }
+            TestGeoPackage { path }
+        }
+
+        fn read(&self, layer: Option<&str>) -> Result<Vec<Feature>, PolygonFinderError> {
+            let mut features = Vec::new();
+            read_features(&self.path, layer, |feature| {
+                features.push(feature);
+                Ok(())
+            })?;
+            Ok(features)
+        }
+    }
+
+    impl Drop for TestGeoPackage {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn it_should_read_the_features_of_a_layer() {
+        let geopackage =
+            TestGeoPackage::new("layers", &[("municipios", "MULTIPOLYGON", "4326"), ("estados", "POLYGON", "4326")]);
+
+        let features = geopackage.read(Some("municipios")).unwrap();
+
+        assert_eq!(features.len(), 2);
+        match &features[0].geometry.as_ref().unwrap().value {
+            geojson::Value::Polygon(rings) => assert_eq!(rings[0].len(), 5),
+            _ => panic!("Wrong geometry"),
+        }
+        let properties = features[0].properties.as_ref().unwrap();
+        assert_eq!(properties.keys().collect::<Vec<_>>(), vec!["AREA", "CAPITAL", "NOMGEO", "POB"]);
+        assert_eq!(properties["NOMGEO"], "Colima");
+        assert_eq!(properties["POB"], 731391);
+        assert_eq!(properties["AREA"], 5627.1);
+        assert_eq!(properties["CAPITAL"], true);
+        let properties = features[1].properties.as_ref().unwrap();
+        assert!(properties["POB"].is_null());
+        assert_eq!(properties["CAPITAL"], false);
+    }
+
+    #[test]
+    fn it_should_skip_the_features_without_a_geometry() {
+        let geopackage = TestGeoPackage::new("empty", &[("municipios", "MULTIPOLYGON", "4326")]);
+        let mut empty = square_geometry(0.0, 1.0);
+        empty[3] |= EMPTY_FLAG;
+        let connection = Connection::open(&geopackage.path).unwrap();
+        connection.execute("INSERT INTO municipios VALUES (3, NULL, 'Tecomán', 1, 1.0, 0, NULL)", []).unwrap();
+        connection.execute("INSERT INTO municipios VALUES (4, ?, 'Armería', 1, 1.0, 0, NULL)", [empty]).unwrap();
+
+        let features = geopackage.read(None).unwrap();
+
+        let names: Vec<_> =
+            features.iter().map(|feature| feature.properties.as_ref().unwrap()["NOMGEO"].clone()).collect();
+        assert_eq!(names, vec!["Colima", "Manzanillo"]);
+    }
+
+    #[test]
+    fn it_should_need_the_layer_when_there_are_several() {
+        let geopackage =
+            TestGeoPackage::new("several", &[("municipios", "MULTIPOLYGON", "4326"), ("estados", "POLYGON", "4326")]);
+        let single = TestGeoPackage::new("single", &[("municipios", "MULTIPOLYGON", "4326")]);
+
+        match geopackage.read(None) {
+            Err(PolygonFinderError::LayerRequired(layers)) => assert_eq!(layers, "estados, municipios"),
+            _ => panic!("Wrong Error"),
+        }
+        match geopackage.read(Some("localidades")) {
+            Err(PolygonFinderError::LayerNotFound(layer, _)) => assert_eq!(layer, "localidades"),
+            _ => panic!("Wrong Error"),
+        }
+        assert_eq!(single.read(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_should_fail_when_the_layer_is_not_polygonal() {
+        let geopackage = TestGeoPackage::new("lines", &[("carreteras", "LINESTRING", "4326")]);
+
+        match geopackage.read(None) {
+            Err(PolygonFinderError::NotPolygonal(layer, geometry_type)) => {
+                assert_eq!((layer.as_str(), geometry_type.as_str()), ("carreteras", "LINESTRING"));
+            }
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_reproject_projected_coordinates() {
+        let geopackage = TestGeoPackage::new("projected", &[("municipios", "MULTIPOLYGON", "6372")]);
+        let connection = Connection::open(&geopackage.path).unwrap();
+        connection
+            .execute("UPDATE municipios SET geom = ?", [square_geometry(2800000.0, 2810000.0)])
+            .unwrap();
+
+        let features = geopackage.read(None).unwrap();
+
+        assert_eq!(features.len(), 2);
+        assert_reprojected(&features[0]);
+    }
+}
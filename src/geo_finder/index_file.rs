@@ -0,0 +1,408 @@
+// The `Fail` derive of the errors expands to impls inside a const block.
+#![allow(non_local_definitions)]
+
+use failure::Fail;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+use std::str::FromStr;
+
+use super::geo_finder_types::GeoFinder;
+use super::mapped_index::{self, MappedIndex};
+use super::polygon_finder::PolygonFinder;
+
+/**
+ * Index file layout:
+ *
+ * | magic (8 bytes) | format version (u32 LE) | metadata (bincode) | finder (bincode) | CRC32 of metadata + finder (u32 LE) |
+ */
+const MAGIC: &[u8; 8] = b"FSJINDEX";
+
+/// Bump it whenever the serialized structures change.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// How an index is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexLayout {
+    /// Compact, but everything is deserialized to memory when loaded.
+    Bincode,
+    /// Flat arrays queried in place from the memory mapped file, except the properties: they are decoded for
+    /// each feature found. Loads instantly and the pages are shared between processes.
+    Mapped,
+}
+
+impl IndexLayout {
+    pub const VALUES: [&'static str; 2] = ["bincode", "mapped"];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IndexLayout::Bincode => "bincode",
+            IndexLayout::Mapped => "mapped",
+        }
+    }
+
+    pub fn format_version(self) -> u32 {
+        match self {
+            IndexLayout::Bincode => FORMAT_VERSION,
+            IndexLayout::Mapped => mapped_index::FORMAT_VERSION,
+        }
+    }
+}
+
+impl FromStr for IndexLayout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<IndexLayout, String> {
+        match value {
+            "bincode" => Ok(IndexLayout::Bincode),
+            "mapped" => Ok(IndexLayout::Mapped),
+            other => Err(format!("Invalid index layout: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum IndexFileError {
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Not an index file (or built by a version older than the versioned format)")]
+    NotAnIndex,
+    #[fail(display = "Index format version {} is not supported (expected {}). Generate the index again", _0, _1)]
+    IncompatibleVersion(u32, u32),
+    #[fail(display = "The index file is truncated")]
+    Truncated,
+    #[fail(display = "The index file is corrupted: {}", _0)]
+    Corrupted(String),
+    #[fail(display = "The index file is corrupted: checksum mismatch")]
+    ChecksumMismatch,
+}
+
+impl From<io::Error> for IndexFileError {
+    fn from(err: io::Error) -> IndexFileError {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => IndexFileError::Truncated,
+            _ => IndexFileError::Io(err),
+        }
+    }
+}
+
+impl From<bincode::Error> for IndexFileError {
+    fn from(err: bincode::Error) -> IndexFileError {
+        match *err {
+            bincode::ErrorKind::Io(io_err) => IndexFileError::from(io_err),
+            other => IndexFileError::Corrupted(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct IndexSource {
+    pub path: String,
+    /// CRC32 of the file contents.
+    pub crc32: u32,
+}
+
+impl IndexSource {
+    pub fn from_file<P: AsRef<path::Path>>(source_path: P) -> Result<IndexSource, IndexFileError> {
+        let mut reader = HashingReader::new(io::BufReader::new(File::open(&source_path)?));
+        io::copy(&mut reader, &mut io::sink())?;
+
+        Ok(IndexSource {
+            path: source_path.as_ref().display().to_string(),
+            crc32: reader.hasher.finalize(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct IndexMetadata {
+    pub sources: Vec<IndexSource>,
+    pub feature_count: u64,
+    /// Property name to the value types found for it.
+    pub property_schema: BTreeMap<String, Vec<String>>,
+    /// Min x, min y, max x, max y.
+    pub bbox: Option<[f64; 4]>,
+    /// RFC 3339.
+    pub build_time: String,
+    pub generator_version: String,
+}
+
+impl IndexMetadata {
+    pub fn new(finder: &PolygonFinder, sources: Vec<IndexSource>) -> IndexMetadata {
+        IndexMetadata {
+            sources,
+            feature_count: finder.feature_count() as u64,
+            property_schema: finder.property_schema(),
+            bbox: finder.bbox().map(|rect| [rect.min.x, rect.min.y, rect.max.x, rect.max.y]),
+            build_time: chrono::Local::now().to_rfc3339(),
+            generator_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// Computes the CRC32 of everything that goes through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> HashingReader<R> {
+        HashingReader { inner, hasher: crc32fast::Hasher::new() }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn write_index<W: Write>(
+    writer: W,
+    finder: &PolygonFinder,
+    metadata: &IndexMetadata,
+) -> Result<(), IndexFileError> {
+    let mut writer = writer;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    let mut hashing_writer = HashingWriter { inner: writer, hasher: crc32fast::Hasher::new() };
+    bincode::serialize_into(&mut hashing_writer, metadata)?;
+    bincode::serialize_into(&mut hashing_writer, finder)?;
+
+    let checksum = hashing_writer.hasher.finalize();
+    let mut writer = hashing_writer.inner;
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<(), IndexFileError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(|_| IndexFileError::NotAnIndex)?;
+    if &magic != MAGIC {
+        return Err(IndexFileError::NotAnIndex);
+    }
+
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(IndexFileError::IncompatibleVersion(version, FORMAT_VERSION));
+    }
+    Ok(())
+}
+
+/**
+ * Reads an index. `size_limit` is the size of the file: a corrupted length can't make it allocate more.
+ */
+pub fn read_index<R: Read>(reader: R, size_limit: u64) -> Result<(IndexMetadata, PolygonFinder), IndexFileError> {
+    use bincode::Options;
+
+    let mut reader = reader;
+    read_header(&mut reader)?;
+
+    // Same encoding as `bincode::serialize`.
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(size_limit);
+
+    let mut hashing_reader = HashingReader::new(reader);
+    let metadata: IndexMetadata = options.deserialize_from(&mut hashing_reader)?;
+    let finder: PolygonFinder = options.deserialize_from(&mut hashing_reader)?;
+
+    let checksum = hashing_reader.hasher.finalize();
+    let mut expected = [0u8; 4];
+    hashing_reader.inner.read_exact(&mut expected)?;
+    if u32::from_le_bytes(expected) != checksum {
+        return Err(IndexFileError::ChecksumMismatch);
+    }
+
+    Ok((metadata, finder))
+}
+
+pub fn save_index<P: AsRef<path::Path>>(
+    finder: &PolygonFinder,
+    metadata: &IndexMetadata,
+    layout: IndexLayout,
+    output_path: P,
+) -> Result<(), IndexFileError> {
+    // Written aside and renamed, so processes using (or mapping) the previous index never see it change.
+    let output_path = output_path.as_ref();
+    let mut temp_path = output_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let save = || -> Result<(), IndexFileError> {
+        let file_writer = io::BufWriter::new(File::create(&temp_path)?);
+        match layout {
+            IndexLayout::Bincode => write_index(file_writer, finder, metadata)?,
+            IndexLayout::Mapped => mapped_index::write_mapped_index(file_writer, finder, metadata)?,
+        }
+        std::fs::rename(&temp_path, output_path)?;
+        Ok(())
+    };
+
+    let saved = save();
+    if saved.is_err() {
+        // Not left behind half written.
+        std::fs::remove_file(&temp_path).ok();
+    }
+    saved
+}
+
+pub fn load_index<P: AsRef<path::Path>>(input_path: P) -> Result<(IndexMetadata, PolygonFinder), IndexFileError> {
+    let file_reader = File::open(input_path)?;
+    let size_limit = file_reader.metadata()?.len();
+    read_index(io::BufReader::new(file_reader), size_limit)
+}
+
+/**
+ * Opens an index of any layout. Bincode indexes are always checked against their checksum, memory mapped
+ * ones only with `verify` (it reads the whole file).
+ */
+pub fn open_index<P: AsRef<path::Path>>(
+    input_path: P,
+    verify: bool,
+) -> Result<(IndexMetadata, Box<dyn GeoFinder>), IndexFileError> {
+    let mut magic = [0u8; 8];
+    File::open(&input_path)?.read_exact(&mut magic).map_err(|_| IndexFileError::NotAnIndex)?;
+
+    if &magic == MAGIC {
+        let (metadata, finder) = load_index(input_path)?;
+        return Ok((metadata, Box::new(finder)));
+    }
+    if &magic == mapped_index::MAGIC {
+        let index = MappedIndex::open(input_path)?;
+        if verify {
+            index.verify()?;
+        }
+        return Ok((index.metadata()?, Box::new(index)));
+    }
+    Err(IndexFileError::NotAnIndex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::GeoFinder;
+
+    const MEXICO_GEOJSON_STR: &str = include_str!("test_resources/mexico_states.json");
+
+    fn index_bytes() -> Vec<u8> {
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
+        let metadata = IndexMetadata::new(&finder, vec![IndexSource { path: "mexico_states.json".to_owned(), crc32: 1 }]);
+
+        let mut bytes = Vec::new();
+        write_index(&mut bytes, &finder, &metadata).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_should_read_back_a_written_index() {
+        let bytes = index_bytes();
+        let (metadata, finder) = read_index(bytes.as_slice(), bytes.len() as u64).unwrap();
+
+        assert_eq!(metadata.feature_count, 32);
+        assert_eq!(metadata.sources[0].path, "mexico_states.json");
+        assert_eq!(metadata.property_schema["CVEGEO"], vec!["string"]);
+        assert!(metadata.bbox.is_some());
+        assert_eq!(finder.find(28.14606, -105.34232).unwrap().unwrap().props["CVEGEO"], "08");
+    }
+
+    #[test]
+    fn it_should_refuse_a_file_without_magic() {
+        let bytes = bincode::serialize(&PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap()).unwrap();
+
+        match read_index(bytes.as_slice(), bytes.len() as u64).err() {
+            Some(IndexFileError::NotAnIndex) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_refuse_another_format_version() {
+        let mut bytes = index_bytes();
+        bytes[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        match read_index(bytes.as_slice(), bytes.len() as u64).err() {
+            Some(IndexFileError::IncompatibleVersion(version, FORMAT_VERSION)) => assert_eq!(version, FORMAT_VERSION + 1),
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_detect_a_truncated_file() {
+        let bytes = index_bytes();
+
+        match read_index(&bytes[..bytes.len() / 2], bytes.len() as u64).err() {
+            Some(IndexFileError::Truncated) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_detect_a_checksum_mismatch() {
+        let mut bytes = index_bytes();
+        let position = bytes.len() - 1;
+        bytes[position] ^= 0x01;
+
+        match read_index(bytes.as_slice(), bytes.len() as u64).err() {
+            Some(IndexFileError::ChecksumMismatch) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_fail_without_panicking_on_corrupted_bytes() {
+        let original = index_bytes();
+
+        // Whatever gets flipped (lengths, tags or coordinates), it must be an error.
+        for position in (12..original.len() - 4).step_by(97) {
+            let mut bytes = original.clone();
+            bytes[position] ^= 0x80;
+
+            assert!(read_index(bytes.as_slice(), bytes.len() as u64).is_err(), "{}", position);
+        }
+    }
+    #[test]
+    fn it_should_remove_the_temporary_file_when_saving_fails() {
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
+        let metadata = IndexMetadata::new(&finder, Vec::new());
+        // The rename fails: the output is a directory with a file in it.
+        let output_path = std::env::temp_dir().join(format!("fsj-save-failure-{}", std::process::id()));
+        std::fs::create_dir_all(&output_path).unwrap();
+        std::fs::write(output_path.join("file"), b"").unwrap();
+        let temp_path = std::path::PathBuf::from(format!("{}.tmp", output_path.display()));
+
+        for layout in &[IndexLayout::Bincode, IndexLayout::Mapped] {
+            assert!(save_index(&finder, &metadata, *layout, &output_path).is_err());
+            assert!(!temp_path.exists());
+        }
+        std::fs::remove_dir_all(&output_path).unwrap();
+    }
+}
@@ -0,0 +1,1068 @@
+use bytemuck::{Pod, Zeroable};
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo_types::Coordinate;
+use memmap2::Mmap;
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::path;
+
+use super::geo_finder_types::{FindResult, GeoFinder, IndexStats, IndexedFeature, MatchType, PropertyMap, PropertyStats};
+use super::index_file::{IndexFileError, IndexLayout, IndexMetadata};
+use super::polygon_finder::{self, Area, IndexablePolygon, PolygonFinder};
+use super::prepared_area::{self, Cell, Grid, PreparedArea};
+
+/**
+ * Memory mapped index layout. Everything is little endian and every section starts 8 bytes aligned:
+ *
+ * | header | metadata (bincode) | property counts (bincode) | R-tree | geometries | prepared geometries | properties (bincode) |
+ *
+ * The R-tree is packed (sorted by the Hilbert curve): the boxes of the features, followed by the boxes of
+ * each upper level up to the root. Geometries are nested ranges: features to polygons to rings to coordinates.
+ */
+pub const MAGIC: &[u8; 8] = b"FSJMMAP\0";
+
+/// Bump it whenever the layout changes.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Children of each R-tree node.
+const NODE_SIZE: usize = 16;
+
+const NOT_PREPARED: u32 = u32::MAX;
+
+// Feature kinds.
+const POLYGON: u32 = 0;
+const MULTI_POLYGON: u32 = 1;
+const POINT: u32 = 2;
+
+// Sections, in file order.
+const METADATA: usize = 0;
+const PROPERTY_COUNTS: usize = 1;
+const LEVEL_BOUNDS: usize = 2;
+const BOXES: usize = 3;
+const TREE_INDICES: usize = 4;
+const FEATURES: usize = 5;
+const POLYGON_RINGS: usize = 6;
+const RING_COORDS: usize = 7;
+const COORDS: usize = 8;
+const PREPARED: usize = 9;
+const CELLS: usize = 10;
+const BAND_OFFSETS: usize = 11;
+const BAND_EDGES: usize = 12;
+const PROPERTIES: usize = 13;
+const SECTION_COUNT: usize = 14;
+
+/// Byte range of a section in the file.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct Section {
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    /// CRC32 of everything after the header.
+    checksum: u32,
+    feature_count: u64,
+    sections: [Section; SECTION_COUNT],
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct FlatFeature {
+    kind: u32,
+    /// Position in the prepared geometries, or `NOT_PREPARED`.
+    prepared: u32,
+    /// Range of polygons.
+    polygons: [u32; 2],
+    /// Byte range of the properties.
+    properties: [u64; 2],
+    area: f64,
+    /// Position in the files the index was generated from.
+    id: u64,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct FlatPrepared {
+    grid: Grid,
+    /// First coordinate of the feature. Band edges are relative to it.
+    coord_start: u64,
+    /// First of the `size * size` cells.
+    cells_start: u64,
+    /// First of the `size` bands.
+    bands_start: u64,
+}
+
+#[inline]
+fn coordinate(coord: [f64; 2]) -> Coordinate<f64> {
+    Coordinate { x: coord[0], y: coord[1] }
+}
+
+/**
+ * Closest point to `point` in the segment from `a` to `b` (in degrees, like `geo`'s `ClosestPoint`).
+ */
+#[inline]
+fn closest_in_segment(a: Coordinate<f64>, b: Coordinate<f64>, point: &Coordinate<f64>) -> Coordinate<f64> {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length2 = dx * dx + dy * dy;
+    if length2 == 0.0 {
+        return a;
+    }
+    let t = (((point.x - a.x) * dx + (point.y - a.y) * dy) / length2).clamp(0.0, 1.0);
+    Coordinate { x: a.x + t * dx, y: a.y + t * dy }
+}
+
+/**
+ * Position of the point in a 2^16 x 2^16 Hilbert curve.
+ */
+fn hilbert(mut x: u32, mut y: u32) -> u64 {
+    const N: u32 = 1 << 16;
+    let mut d = 0;
+    let mut s = N / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += (s as u64) * (s as u64) * ((3 * rx) ^ ry) as u64;
+        if ry == 0 {
+            if rx == 1 {
+                x = N - 1 - x;
+                y = N - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/// Sections being built.
+struct MappedIndexBuilder {
+    level_bounds: Vec<u64>,
+    boxes: Vec<[f64; 4]>,
+    tree_indices: Vec<u32>,
+    features: Vec<FlatFeature>,
+    /// Offsets: the rings of polygon `i` go from `polygon_rings[i]` to `polygon_rings[i + 1]`.
+    polygon_rings: Vec<u32>,
+    /// Offsets, like `polygon_rings`.
+    ring_coords: Vec<u64>,
+    coords: Vec<[f64; 2]>,
+    prepared: Vec<FlatPrepared>,
+    cells: Vec<u8>,
+    /// Offsets, like `polygon_rings`.
+    band_offsets: Vec<u64>,
+    band_edges: Vec<u32>,
+    properties: Vec<u8>,
+    property_counts: BTreeMap<String, u64>,
+}
+
+impl MappedIndexBuilder {
+    fn new() -> MappedIndexBuilder {
+        MappedIndexBuilder {
+            level_bounds: Vec::new(),
+            boxes: Vec::new(),
+            tree_indices: Vec::new(),
+            features: Vec::new(),
+            polygon_rings: vec![0],
+            ring_coords: vec![0],
+            coords: Vec::new(),
+            prepared: Vec::new(),
+            cells: Vec::new(),
+            band_offsets: vec![0],
+            band_edges: Vec::new(),
+            properties: Vec::new(),
+            property_counts: BTreeMap::new(),
+        }
+    }
+
+    fn add_polygon<'a, I: Iterator<Item = &'a geo::LineString<f64>>>(&mut self, rings: I) {
+        for ring in rings {
+            self.coords.extend(ring.0.iter().map(|coord| [coord.x, coord.y]));
+            self.ring_coords.push(self.coords.len() as u64);
+        }
+        self.polygon_rings.push((self.ring_coords.len() - 1) as u32);
+    }
+
+    /**
+     * Copies the grid, with the edges as positions of their first coordinate (relative to the feature).
+     */
+    fn add_prepared(&mut self, prepared: &PreparedArea, area: &Area, coord_start: usize) -> u32 {
+        let mut edge_coords = Vec::new();
+        let mut ring_start = 0;
+        for ring in area.rings() {
+            let coords = ring.num_coords();
+            edge_coords.extend((0..coords.saturating_sub(1)).map(|coord| (ring_start + coord) as u32));
+            ring_start += coords;
+        }
+
+        self.prepared.push(FlatPrepared {
+            grid: *prepared.grid(),
+            coord_start: coord_start as u64,
+            cells_start: self.cells.len() as u64,
+            bands_start: (self.band_offsets.len() - 1) as u64,
+        });
+        self.cells.extend(prepared.cells().iter().map(|cell| *cell as u8));
+        for band in prepared.bands() {
+            self.band_edges.extend(band.iter().map(|edge| edge_coords[*edge as usize]));
+            self.band_offsets.push(self.band_edges.len() as u64);
+        }
+        (self.prepared.len() - 1) as u32
+    }
+
+    fn add_feature(&mut self, polygon: &IndexablePolygon) -> Result<(), bincode::Error> {
+        let coord_start = self.coords.len();
+        let first_polygon = self.polygon_rings.len() - 1;
+
+        let kind = match &polygon.area {
+            Area::Polygon(p) => {
+                self.add_polygon(std::iter::once(p.exterior()).chain(p.interiors()));
+                POLYGON
+            }
+            Area::MultiPolygon(multi_polygon) => {
+                for p in &multi_polygon.0 {
+                    self.add_polygon(std::iter::once(p.exterior()).chain(p.interiors()));
+                }
+                MULTI_POLYGON
+            }
+            Area::Point(point) => {
+                // A polygon with a single ring of a single coordinate.
+                self.coords.push([point.x(), point.y()]);
+                self.ring_coords.push(self.coords.len() as u64);
+                self.polygon_rings.push((self.ring_coords.len() - 1) as u32);
+                POINT
+            }
+        };
+
+        let prepared = match &polygon.prepared {
+            Some(prepared) => self.add_prepared(prepared, &polygon.area, coord_start),
+            None => NOT_PREPARED,
+        };
+
+        let properties_start = self.properties.len() as u64;
+        bincode::serialize_into(&mut self.properties, &polygon.properties)?;
+        for name in polygon.properties.keys() {
+            *self.property_counts.entry(name.clone()).or_default() += 1;
+        }
+
+        self.features.push(FlatFeature {
+            kind,
+            prepared,
+            polygons: [first_polygon as u32, (self.polygon_rings.len() - 1) as u32],
+            properties: [properties_start, self.properties.len() as u64],
+            area: polygon.area_size,
+            id: polygon.id as u64,
+        });
+        self.boxes.push([polygon.bbox.lower().x, polygon.bbox.lower().y, polygon.bbox.upper().x, polygon.bbox.upper().y]);
+        Ok(())
+    }
+
+    /**
+     * Packs the boxes of the features (already sorted) in nodes, level by level up to the root.
+     */
+    fn build_tree(&mut self) {
+        let feature_count = self.boxes.len();
+        self.tree_indices = (0..feature_count as u32).collect();
+        self.level_bounds = vec![feature_count as u64];
+
+        let mut level_start = 0;
+        while self.boxes.len() - level_start > 1 {
+            let level_end = self.boxes.len();
+            for first_child in (level_start..level_end).step_by(NODE_SIZE) {
+                let children = &self.boxes[first_child..level_end.min(first_child + NODE_SIZE)];
+                let parent = children.iter().fold(
+                    [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY],
+                    |parent, child| {
+                        [parent[0].min(child[0]), parent[1].min(child[1]), parent[2].max(child[2]), parent[3].max(child[3])]
+                    },
+                );
+                self.boxes.push(parent);
+                self.tree_indices.push(first_child as u32);
+            }
+            level_start = level_end;
+            self.level_bounds.push(self.boxes.len() as u64);
+        }
+    }
+}
+
+pub fn write_mapped_index<W: Write>(
+    writer: W,
+    finder: &PolygonFinder,
+    metadata: &IndexMetadata,
+) -> Result<(), IndexFileError> {
+    // Near features end up in the same nodes.
+    let mut polygons: Vec<&IndexablePolygon> = finder.polygons().collect();
+    if let Some(bbox) = finder.bbox() {
+        let scale = |value: f64, min: f64, max: f64| match max > min {
+            true => ((value - min) / (max - min) * f64::from(u16::MAX)) as u32,
+            false => 0,
+        };
+        polygons.sort_by_cached_key(|polygon| {
+            let center = polygon.bbox.center();
+            hilbert(scale(center.x, bbox.min.x, bbox.max.x), scale(center.y, bbox.min.y, bbox.max.y))
+        });
+    }
+
+    let mut builder = MappedIndexBuilder::new();
+    for polygon in polygons {
+        builder.add_feature(polygon)?;
+    }
+    builder.build_tree();
+
+    let metadata_bytes = bincode::serialize(metadata)?;
+    let property_counts_bytes = bincode::serialize(&builder.property_counts)?;
+    let sections: [&[u8]; SECTION_COUNT] = [
+        &metadata_bytes,
+        &property_counts_bytes,
+        bytemuck::cast_slice(&builder.level_bounds),
+        bytemuck::cast_slice(&builder.boxes),
+        bytemuck::cast_slice(&builder.tree_indices),
+        bytemuck::cast_slice(&builder.features),
+        bytemuck::cast_slice(&builder.polygon_rings),
+        bytemuck::cast_slice(&builder.ring_coords),
+        bytemuck::cast_slice(&builder.coords),
+        bytemuck::cast_slice(&builder.prepared),
+        &builder.cells,
+        bytemuck::cast_slice(&builder.band_offsets),
+        bytemuck::cast_slice(&builder.band_edges),
+        &builder.properties,
+    ];
+
+    let mut header = Header {
+        magic: *MAGIC,
+        version: FORMAT_VERSION,
+        checksum: 0,
+        feature_count: builder.features.len() as u64,
+        sections: [Section { offset: 0, len: 0 }; SECTION_COUNT],
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut offset = std::mem::size_of::<Header>() as u64;
+    for (section, bytes) in header.sections.iter_mut().zip(sections.iter()) {
+        let padding = (8 - offset % 8) % 8;
+        hasher.update(&[0u8; 8][..padding as usize]);
+        hasher.update(bytes);
+        *section = Section { offset: offset + padding, len: bytes.len() as u64 };
+        offset = section.offset + section.len;
+    }
+    header.checksum = hasher.finalize();
+
+    let mut writer = writer;
+    writer.write_all(bytemuck::bytes_of(&header))?;
+    let mut written = std::mem::size_of::<Header>() as u64;
+    for (section, bytes) in header.sections.iter().zip(sections.iter()) {
+        writer.write_all(&[0u8; 8][..(section.offset - written) as usize])?;
+        writer.write_all(bytes)?;
+        written = section.offset + section.len;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/**
+ * Index queried in place from a memory mapped file. Opening it only reads the header and checks the sizes of
+ * the sections, the pages are loaded (and shared with other processes) as the lookups touch them. The offsets
+ * are checked where they are read: the lookups of a corrupted index fail instead of panicking.
+ *
+ * The geometries and the R-tree are used in place, the properties are not: they are bincode maps, decoded for
+ * each feature found.
+ */
+pub struct MappedIndex {
+    mmap: Mmap,
+    header: Header,
+    property_counts: BTreeMap<String, u64>,
+}
+
+impl MappedIndex {
+    pub fn open<P: AsRef<path::Path>>(input_path: P) -> Result<MappedIndex, IndexFileError> {
+        let file = File::open(input_path)?;
+        // Only sound while nobody writes the file in place: `save_index` replaces it with a new one instead.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let header: Header = match mmap.get(..std::mem::size_of::<Header>()) {
+            Some(bytes) => bytemuck::pod_read_unaligned(bytes),
+            None => return Err(IndexFileError::Truncated),
+        };
+        if &header.magic != MAGIC {
+            return Err(IndexFileError::NotAnIndex);
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(IndexFileError::IncompatibleVersion(header.version, FORMAT_VERSION));
+        }
+        if cfg!(target_endian = "big") {
+            return Err(IndexFileError::Corrupted("memory mapped indexes are little endian".to_owned()));
+        }
+
+        for section in &header.sections {
+            match section.offset.checked_add(section.len) {
+                Some(end) if end <= mmap.len() as u64 => {}
+                _ => return Err(IndexFileError::Truncated),
+            }
+            if section.offset % 8 != 0 {
+                return Err(IndexFileError::Corrupted("misaligned section".to_owned()));
+            }
+        }
+
+        let mut index = MappedIndex { mmap, header, property_counts: BTreeMap::new() };
+        index.check_section::<u64>(LEVEL_BOUNDS)?;
+        index.check_section::<[f64; 4]>(BOXES)?;
+        index.check_section::<u32>(TREE_INDICES)?;
+        index.check_section::<FlatFeature>(FEATURES)?;
+        index.check_section::<u32>(POLYGON_RINGS)?;
+        index.check_section::<u64>(RING_COORDS)?;
+        index.check_section::<[f64; 2]>(COORDS)?;
+        index.check_section::<FlatPrepared>(PREPARED)?;
+        index.check_section::<u64>(BAND_OFFSETS)?;
+        index.check_section::<u32>(BAND_EDGES)?;
+
+        let feature_count = index.header.feature_count as usize;
+        let boxes = index.section::<[f64; 4]>(BOXES).len();
+        if index.features().len() != feature_count || boxes < feature_count || index.section::<u32>(TREE_INDICES).len() != boxes {
+            return Err(IndexFileError::Corrupted("inconsistent R-tree".to_owned()));
+        }
+
+        index.property_counts = bincode::deserialize(index.bytes(PROPERTY_COUNTS))?;
+        Ok(index)
+    }
+
+    fn check_section<T: Pod>(&self, section: usize) -> Result<(), IndexFileError> {
+        bytemuck::try_cast_slice::<u8, T>(self.bytes(section))
+            .map(|_| ())
+            .map_err(|err| IndexFileError::Corrupted(format!("section {}: {:?}", section, err)))
+    }
+
+    #[inline]
+    fn bytes(&self, section: usize) -> &[u8] {
+        let Section { offset, len } = self.header.sections[section];
+        &self.mmap[offset as usize..(offset + len) as usize]
+    }
+
+    /// Size and alignment checked when opened.
+    #[inline]
+    fn section<T: Pod>(&self, section: usize) -> &[T] {
+        bytemuck::cast_slice(self.bytes(section))
+    }
+
+    #[inline]
+    fn features(&self) -> &[FlatFeature] {
+        self.section(FEATURES)
+    }
+
+    /**
+     * Compares the whole file with its checksum.
+     */
+    pub fn verify(&self) -> Result<(), IndexFileError> {
+        match crc32fast::hash(&self.mmap[std::mem::size_of::<Header>()..]) == self.header.checksum {
+            true => Ok(()),
+            false => Err(IndexFileError::ChecksumMismatch),
+        }
+    }
+
+    pub fn metadata(&self) -> Result<IndexMetadata, IndexFileError> {
+        Ok(bincode::deserialize(self.bytes(METADATA))?)
+    }
+
+    /**
+     * Features whose bounding box intersects the rectangle.
+     */
+    fn search(&self, min: Coordinate<f64>, max: Coordinate<f64>) -> Result<Vec<&FlatFeature>, IndexFileError> {
+        let boxes: &[[f64; 4]] = self.section(BOXES);
+        let tree_indices: &[u32] = self.section(TREE_INDICES);
+        let level_bounds: &[u64] = self.section(LEVEL_BOUNDS);
+        let features = self.features();
+
+        let mut results = Vec::new();
+        let mut stack = match boxes.len() {
+            0 => Vec::new(),
+            len => vec![len - 1],
+        };
+        // Positions only go down from the root: `tree_indices` is as long as `boxes` (checked when opened).
+        while let Some(position) = stack.pop() {
+            let node = &boxes[position];
+            if node[0] > max.x || node[1] > max.y || node[2] < min.x || node[3] < min.y {
+                continue;
+            }
+            if position < features.len() {
+                results.push(features.get(tree_indices[position] as usize).ok_or_else(|| corrupted("R-tree leaf"))?);
+                continue;
+            }
+
+            // The children of a node are always before it.
+            let first_child = tree_indices[position] as usize;
+            if first_child >= position {
+                return Err(corrupted("R-tree node"));
+            }
+            let level_end = level_bounds
+                .iter()
+                .map(|bound| *bound as usize)
+                .find(|bound| *bound > first_child)
+                .unwrap_or(boxes.len());
+            stack.extend(first_child..level_end.min(first_child + NODE_SIZE).min(position));
+        }
+        Ok(results)
+    }
+
+    #[inline]
+    fn ring_range(&self, polygons: Range<usize>) -> Result<Range<usize>, IndexFileError> {
+        let polygon_rings: &[u32] = self.section(POLYGON_RINGS);
+        match (polygon_rings.get(polygons.start), polygon_rings.get(polygons.end)) {
+            (Some(start), Some(end)) if start <= end => Ok(*start as usize..*end as usize),
+            _ => Err(corrupted("polygon rings")),
+        }
+    }
+
+    #[inline]
+    fn ring(&self, ring: usize) -> Result<&[[f64; 2]], IndexFileError> {
+        let ring_coords: &[u64] = self.section(RING_COORDS);
+        let coords = match ring_coords.get(ring..) {
+            Some([start, end, ..]) => self.section::<[f64; 2]>(COORDS).get(*start as usize..*end as usize),
+            _ => None,
+        };
+        coords.ok_or_else(|| corrupted("ring coordinates"))
+    }
+
+    fn rings(&self, feature: &FlatFeature) -> Result<Vec<&[[f64; 2]]>, IndexFileError> {
+        self.ring_range(polygons(feature))?.map(|ring| self.ring(ring)).collect()
+    }
+
+    fn prepared_contains(&self, prepared: &FlatPrepared, point: &Coordinate<f64>) -> Result<bool, IndexFileError> {
+        let size = prepared.grid.size();
+        if size == 0 {
+            return Err(corrupted("prepared grid"));
+        }
+        let (col, row) = match prepared.grid.locate(point) {
+            Some(cell) => cell,
+            None => return Ok(false),
+        };
+
+        let cell = row
+            .checked_mul(size)
+            .and_then(|cell| cell.checked_add(col))
+            .and_then(|cell| cell.checked_add(prepared.cells_start as usize))
+            .and_then(|cell| self.section::<u8>(CELLS).get(cell))
+            .ok_or_else(|| corrupted("prepared cells"))?;
+        match Cell::from_u8(*cell) {
+            Cell::Inside => Ok(true),
+            Cell::Outside => Ok(false),
+            Cell::Boundary => {
+                let band_offsets: &[u64] = self.section(BAND_OFFSETS);
+                let band = (prepared.bands_start as usize).checked_add(row);
+                let edges = match band.and_then(|band| band_offsets.get(band..)) {
+                    Some([start, end, ..]) => self.section::<u32>(BAND_EDGES).get(*start as usize..*end as usize),
+                    _ => None,
+                };
+                let edges = edges.ok_or_else(|| corrupted("prepared bands"))?;
+                let coords = self
+                    .section::<[f64; 2]>(COORDS)
+                    .get(prepared.coord_start as usize..)
+                    .ok_or_else(|| corrupted("prepared coordinates"))?;
+
+                let mut crossings = 0;
+                for edge in edges {
+                    match coords.get(*edge as usize..) {
+                        Some([a, b, ..]) => {
+                            if prepared_area::crosses_ray(coordinate(*a), coordinate(*b), point) {
+                                crossings += 1;
+                            }
+                        }
+                        _ => return Err(corrupted("prepared edges")),
+                    }
+                }
+                Ok(crossings % 2 == 1)
+            }
+        }
+    }
+
+    /**
+     * Even-odd ray casting, polygon by polygon (or the prepared geometry when there is one).
+     */
+    fn contains(&self, feature: &FlatFeature, point: &Coordinate<f64>) -> Result<bool, IndexFileError> {
+        if feature.kind == POINT {
+            let rings = self.rings(feature)?;
+            return Ok(rings.iter().any(|ring| ring.first().map(|coord| coordinate(*coord)) == Some(*point)));
+        }
+        if feature.prepared != NOT_PREPARED {
+            let prepared = self
+                .section::<FlatPrepared>(PREPARED)
+                .get(feature.prepared as usize)
+                .ok_or_else(|| corrupted("prepared geometry"))?;
+            return self.prepared_contains(prepared, point);
+        }
+
+        for polygon in polygons(feature) {
+            let mut crossings = 0;
+            for ring in self.ring_range(polygon..polygon + 1)? {
+                crossings += self
+                    .ring(ring)?
+                    .windows(2)
+                    .filter(|edge| prepared_area::crosses_ray(coordinate(edge[0]), coordinate(edge[1]), point))
+                    .count();
+            }
+            if crossings % 2 == 1 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /**
+     * Haversine distance in meters from the point to the closest point of the geometry boundary.
+     */
+    fn distance_meters(&self, feature: &FlatFeature, point: &geo::Point<f64>) -> Result<Option<f64>, IndexFileError> {
+        let coord = Coordinate { x: point.x(), y: point.y() };
+        let distance2 = |other: &Coordinate<f64>| (other.x - coord.x).powi(2) + (other.y - coord.y).powi(2);
+
+        let closest = self
+            .rings(feature)?
+            .into_iter()
+            .flat_map(|ring| match ring.len() {
+                1 => vec![coordinate(ring[0])],
+                _ => ring
+                    .windows(2)
+                    .map(|edge| closest_in_segment(coordinate(edge[0]), coordinate(edge[1]), &coord))
+                    .collect(),
+            })
+            .min_by(|a, b| distance2(a).total_cmp(&distance2(b)));
+        Ok(closest.map(|closest| point.haversine_distance(&geo::Point::from((closest.x, closest.y)))))
+    }
+
+    fn properties(&self, feature: &FlatFeature) -> Result<PropertyMap, IndexFileError> {
+        let [start, end] = feature.properties;
+        let bytes = self
+            .bytes(PROPERTIES)
+            .get(start as usize..end as usize)
+            .ok_or_else(|| corrupted("feature properties"))?;
+        bincode::deserialize(bytes)
+            .map_err(|err| IndexFileError::Corrupted(format!("invalid feature properties: {}", err)))
+    }
+
+    #[inline]
+    fn find_result(
+        &self,
+        feature: &FlatFeature,
+        match_type: MatchType,
+        distance: f64,
+    ) -> Result<FindResult<'_>, IndexFileError> {
+        Ok(FindResult {
+            feature: feature.id as usize,
+            props: Cow::Owned(self.properties(feature)?),
+            match_type,
+            distance,
+            area: feature.area,
+        })
+    }
+
+    fn geometry(&self, feature: &FlatFeature) -> Result<geo_types::Geometry<f64>, IndexFileError> {
+        let line_string = |ring: usize| -> Result<geo_types::LineString<f64>, IndexFileError> {
+            let coords = self.ring(ring)?;
+            Ok(geo_types::LineString(coords.iter().copied().map(coordinate).collect()))
+        };
+        let polygon = |polygon: usize| -> Result<geo_types::Polygon<f64>, IndexFileError> {
+            let rings: Vec<_> = self.ring_range(polygon..polygon + 1)?.map(line_string).collect::<Result<_, _>>()?;
+            let mut rings = rings.into_iter();
+            let exterior = rings.next().unwrap_or_else(|| geo_types::LineString(Vec::new()));
+            Ok(geo_types::Polygon::new(exterior, rings.collect()))
+        };
+
+        let polygons = polygons(feature);
+        match feature.kind {
+            POLYGON => Ok(geo_types::Geometry::Polygon(polygon(polygons.start)?)),
+            MULTI_POLYGON => Ok(geo_types::Geometry::MultiPolygon(geo_types::MultiPolygon(
+                polygons.map(polygon).collect::<Result<_, _>>()?,
+            ))),
+            POINT => {
+                let coord = self.rings(feature)?.first().and_then(|ring| ring.first()).copied().map(coordinate);
+                Ok(geo_types::Geometry::Point(geo_types::Point(coord.unwrap_or(Coordinate { x: 0.0, y: 0.0 }))))
+            }
+            _ => Err(corrupted("feature kind")),
+        }
+    }
+}
+
+/// The range of polygons of a feature.
+#[inline]
+fn polygons(feature: &FlatFeature) -> Range<usize> {
+    feature.polygons[0] as usize..feature.polygons[1] as usize
+}
+
+fn corrupted(what: &str) -> IndexFileError {
+    IndexFileError::Corrupted(format!("invalid {}", what))
+}
+
+impl GeoFinder for MappedIndex {
+    fn layout(&self) -> IndexLayout {
+        IndexLayout::Mapped
+    }
+
+    fn feature_count(&self) -> usize {
+        self.header.feature_count as usize
+    }
+
+    fn find(&self, latitude: f64, longitude: f64) -> Result<Option<Box<FindResult<'_>>>, IndexFileError> {
+        let point = Coordinate { x: longitude, y: latitude };
+        for feature in self.search(point, point)? {
+            if self.contains(feature, &point)? {
+                return Ok(Some(Box::new(self.find_result(feature, MatchType::Inside, 0.0)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_all(&self, latitude: f64, longitude: f64) -> Result<Vec<FindResult<'_>>, IndexFileError> {
+        let point = Coordinate { x: longitude, y: latitude };
+        let mut results = Vec::new();
+        for feature in self.search(point, point)? {
+            if self.contains(feature, &point)? {
+                results.push(self.find_result(feature, MatchType::Inside, 0.0)?);
+            }
+        }
+        Ok(results)
+    }
+
+    fn find_nearest(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        max_distance: f64,
+    ) -> Result<Option<Box<FindResult<'_>>>, IndexFileError> {
+        let point = geo::Point::from((longitude, latitude));
+        let coord = Coordinate { x: longitude, y: latitude };
+        let radius = polygon_finder::search_radius(&point, max_distance);
+
+        let min = Coordinate { x: longitude - radius, y: latitude - radius };
+        let max = Coordinate { x: longitude + radius, y: latitude + radius };
+        let mut nearest: Option<(&FlatFeature, f64)> = None;
+        for feature in self.search(min, max)? {
+            let distance = match self.contains(feature, &coord)? {
+                true => Some(0.0),
+                false => self.distance_meters(feature, &point)?,
+            };
+            match (distance, nearest) {
+                (Some(distance), _) if distance > max_distance => {}
+                (Some(distance), Some((_, nearest_distance))) if distance >= nearest_distance => {}
+                (Some(distance), _) => nearest = Some((feature, distance)),
+                (None, _) => {}
+            }
+        }
+        nearest
+            .map(|(feature, distance)| self.find_result(feature, MatchType::Nearest, distance).map(Box::new))
+            .transpose()
+    }
+
+    fn missing_properties<'p>(&self, properties: &[&'p str]) -> Vec<(&'p str, usize)> {
+        properties
+            .iter()
+            .map(|property| {
+                let present = self.property_counts.get(*property).copied().unwrap_or(0) as usize;
+                (*property, self.feature_count() - present)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    fn stats(&self, max_samples: usize) -> Result<IndexStats, IndexFileError> {
+        let features = self.features();
+        let properties = features.iter().map(|feature| self.properties(feature)).collect::<Result<Vec<_>, _>>()?;
+        let mut stats = IndexStats {
+            feature_count: self.feature_count(),
+            polygons: 0,
+            multi_polygons: 0,
+            points: 0,
+            prepared: self.section::<FlatPrepared>(PREPARED).len(),
+            vertices: self.section::<[f64; 2]>(COORDS).len(),
+            bbox: self.section::<[f64; 4]>(BOXES).last().copied(),
+            tree_depth: self.section::<u64>(LEVEL_BOUNDS).len(),
+            // Shared with every process using the index.
+            memory_size: self.mmap.len(),
+            properties: PropertyStats::collect(properties, max_samples),
+        };
+
+        for feature in features {
+            match feature.kind {
+                POLYGON => stats.polygons += 1,
+                MULTI_POLYGON => stats.multi_polygons += 1,
+                _ => stats.points += 1,
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn indexed_features(&self) -> Box<dyn Iterator<Item = Result<IndexedFeature<'_>, IndexFileError>> + '_> {
+        Box::new(self.features().iter().map(move |feature| {
+            let props = Cow::Owned(self.properties(feature)?);
+            Ok(IndexedFeature::new(feature.id as usize, props, move || self.geometry(feature)))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::index_file::IndexSource;
+
+    const MEXICO_GEOJSON_STR: &str = include_str!("test_resources/mexico_states.json");
+    const NESTED_SQUARES_GEOJSON_STR: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"NAME": "big"},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]]]}},
+        {"type": "Feature", "properties": {"NAME": "small", "KIND": "inner"},
+         "geometry": {"type": "Polygon", "coordinates": [[[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]]}},
+        {"type": "Feature", "properties": {"NAME": "apart"},
+         "geometry": {"type": "Point", "coordinates": [20, 20]}}
+    ]}"#;
+
+    /// Writes the index to a temporary file and maps it.
+    fn mapped_index(name: &str, finder: &PolygonFinder) -> MappedIndex {
+        let metadata = IndexMetadata::new(finder, vec![IndexSource { path: name.to_owned(), crc32: 1 }]);
+        let path = std::env::temp_dir().join(format!("fsj-{}-{}.idx", name, std::process::id()));
+        write_mapped_index(File::create(&path).unwrap(), finder, &metadata).unwrap();
+
+        let index = MappedIndex::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        index
+    }
+
+    fn names(results: &[FindResult]) -> Vec<String> {
+        let mut names: Vec<String> = results.iter().map(|result| result.props["CVEGEO"].to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn it_should_agree_with_the_bincode_index() {
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
+        let index = mapped_index("mexico", &finder);
+
+        assert_eq!(index.feature_count(), 32);
+        assert_eq!(index.metadata().unwrap().feature_count, 32);
+
+        // Grid over the country (and the sea around it).
+        for i in 0..60 {
+            for j in 0..40 {
+                let (latitude, longitude) = (14.0 + j as f64 * 0.5, -118.0 + i as f64 * 0.55);
+
+                assert_eq!(
+                    names(&index.find_all(latitude, longitude).unwrap()),
+                    names(&finder.find_all(latitude, longitude).unwrap()),
+                    "{:?}",
+                    (latitude, longitude)
+                );
+
+                let nearest = index.find_nearest(latitude, longitude, 50_000.0).unwrap();
+                let expected = finder.find_nearest(latitude, longitude, 50_000.0).unwrap();
+                assert_eq!(nearest.is_some(), expected.is_some(), "{:?}", (latitude, longitude));
+                if let (Some(nearest), Some(expected)) = (nearest, expected) {
+                    // Not comparing the features: neighbours can be at the same distance.
+                    assert!((nearest.distance - expected.distance).abs() < 1e-3);
+                }
+            }
+        }
+    }
+
+    /// A multi polygon with two spiky circles (lots of vertices, so they are prepared), one with a hole.
+    fn spiky_geojson(vertices: usize) -> String {
+        let ring = |center: (f64, f64), radius: f64| -> String {
+            let coords: Vec<String> = (0..=vertices)
+                .map(|i| {
+                    let angle = 2.0 * std::f64::consts::PI * (i % vertices) as f64 / vertices as f64;
+                    let r = if i % 2 == 0 { radius } else { radius * 0.8 };
+                    format!("[{}, {}]", center.0 + r * angle.cos(), center.1 + r * angle.sin())
+                })
+                .collect();
+            format!("[{}]", coords.join(", "))
+        };
+        format!(
+            r#"{{"type": "FeatureCollection", "features": [{{"type": "Feature", "properties": {{"CVEGEO": "spiky"}},
+                "geometry": {{"type": "MultiPolygon", "coordinates": [[{}, {}], [{}]]}}}}]}}"#,
+            ring((-100.0, 20.0), 5.0),
+            ring((-100.0, 20.0), 2.0),
+            ring((-90.0, 20.0), 3.0)
+        )
+    }
+
+    #[test]
+    fn it_should_agree_with_the_bincode_index_on_prepared_geometries() {
+        let finder = PolygonFinder::new_from_string(&spiky_geojson(1000)).unwrap();
+        let index = mapped_index("spiky", &finder);
+        assert_eq!(index.stats(0).unwrap().prepared, 1);
+
+        for i in 0..200 {
+            for j in 0..100 {
+                let (latitude, longitude) = (14.0 + j as f64 * 0.12, -106.0 + i as f64 * 0.1);
+                assert_eq!(
+                    index.find(latitude, longitude).unwrap().is_some(),
+                    finder.find(latitude, longitude).unwrap().is_some(),
+                    "{:?}",
+                    (latitude, longitude)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_give_back_the_features_of_the_bincode_index() {
+        let features = |geo_finder: &dyn GeoFinder| {
+            let mut features: Vec<_> = geo_finder
+                .indexed_features()
+                .map(|feature| feature.unwrap())
+                .map(|feature| (feature.id, feature.geometry().unwrap(), feature.props.into_owned()))
+                .collect();
+            features.sort_by_key(|feature| feature.0);
+            features
+        };
+
+        for geojson in &[spiky_geojson(100), NESTED_SQUARES_GEOJSON_STR.to_owned()] {
+            let finder = PolygonFinder::new_from_string(geojson).unwrap();
+            let index = mapped_index("features", &finder);
+
+            assert_eq!(features(&index), features(&finder));
+        }
+    }
+
+    #[test]
+    fn it_should_identify_the_features_by_their_position_in_the_file() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+        let index = mapped_index("ids", &finder);
+
+        let mut ids: Vec<usize> = index.find_all(5.0, 5.0).unwrap().iter().map(|result| result.feature).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(index.find_nearest(20.0, 20.0, 1.0).unwrap().map(|result| result.feature), Some(2));
+    }
+
+    #[test]
+    fn it_should_find_every_match() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+        let index = mapped_index("nested", &finder);
+
+        let mut areas: Vec<f64> = index.find_all(5.0, 5.0).unwrap().iter().map(|result| result.area).collect();
+        areas.sort_by(f64::total_cmp);
+        assert_eq!(areas, vec![4.0, 100.0]);
+
+        assert_eq!(index.find(1.0, 1.0).unwrap().unwrap().props["NAME"], "big");
+        assert_eq!(index.find(20.0, 20.0).unwrap().unwrap().props["NAME"], "apart");
+        assert!(index.find(15.0, 15.0).unwrap().is_none());
+
+        let nearest = index.find_nearest(10.0, 11.0, 200_000.0).unwrap().unwrap();
+        assert_eq!(nearest.props["NAME"], "big");
+        assert_eq!(nearest.match_type, MatchType::Nearest);
+    }
+
+    #[test]
+    fn it_should_count_the_missing_properties() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+        let index = mapped_index("missing", &finder);
+
+        assert_eq!(index.missing_properties(&["NAME", "KIND", "OTHER"]), vec![("KIND", 2), ("OTHER", 3)]);
+
+        let stats = index.stats(5).unwrap();
+        assert_eq!((stats.polygons, stats.points), (2, 1));
+        assert_eq!(stats.properties.iter().find(|property| property.name == "KIND").unwrap().missing, 2);
+    }
+
+    #[test]
+    fn it_should_detect_a_corrupted_file() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+        let metadata = IndexMetadata::new(&finder, Vec::new());
+        let mut bytes = Vec::new();
+        write_mapped_index(&mut bytes, &finder, &metadata).unwrap();
+
+        let path = std::env::temp_dir().join(format!("fsj-corrupted-{}.idx", std::process::id()));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+        let verify_result = MappedIndex::open(&path).unwrap().verify();
+
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        let truncated_result = MappedIndex::open(&path).err();
+        std::fs::remove_file(&path).unwrap();
+
+        match verify_result {
+            Err(IndexFileError::ChecksumMismatch) => {}
+            _ => panic!("Wrong Error"),
+        }
+        match truncated_result {
+            Some(IndexFileError::Truncated) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_fail_to_read_corrupted_properties() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+        let metadata = IndexMetadata::new(&finder, Vec::new());
+        let mut bytes = Vec::new();
+        write_mapped_index(&mut bytes, &finder, &metadata).unwrap();
+        let header: Header = bytemuck::pod_read_unaligned(&bytes[..std::mem::size_of::<Header>()]);
+        // The end of the properties of the first feature.
+        let properties_end = header.sections[FEATURES].offset as usize + 24;
+        let properties_start = header.sections[PROPERTIES].offset as usize;
+
+        let path = std::env::temp_dir().join(format!("fsj-corrupted-properties-{}.idx", std::process::id()));
+        let mut out_of_range = bytes.clone();
+        out_of_range[properties_end..properties_end + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &out_of_range).unwrap();
+        let out_of_range_index = MappedIndex::open(&path).unwrap();
+        let mut undecodable = bytes.clone();
+        // The length of the first map.
+        undecodable[properties_start..properties_start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &undecodable).unwrap();
+        let undecodable_index = MappedIndex::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for index in [out_of_range_index, undecodable_index] {
+            match index.stats(0) {
+                Err(IndexFileError::Corrupted(_)) => {}
+                _ => panic!("Wrong Error"),
+            }
+            assert_eq!(index.indexed_features().filter(Result::is_err).count(), 1);
+        }
+    }
+
+    #[test]
+    fn it_should_fail_the_lookups_of_a_corrupted_index_without_panicking() {
+        let path = std::env::temp_dir().join(format!("fsj-corrupted-offsets-{}.idx", std::process::id()));
+
+        for geojson in &[spiky_geojson(100), NESTED_SQUARES_GEOJSON_STR.to_owned()] {
+            let finder = PolygonFinder::new_from_string(geojson).unwrap();
+            let metadata = IndexMetadata::new(&finder, Vec::new());
+            let mut bytes = Vec::new();
+            write_mapped_index(&mut bytes, &finder, &metadata).unwrap();
+            let header: Header = bytemuck::pod_read_unaligned(&bytes[..std::mem::size_of::<Header>()]);
+            let bbox = finder.bbox().unwrap();
+            let points: Vec<(f64, f64)> = (0..=10)
+                .flat_map(|i| (0..=10).map(move |j| (i as f64 / 10.0, j as f64 / 10.0)))
+                .map(|(i, j)| (bbox.min.y + i * bbox.height(), bbox.min.x + j * bbox.width()))
+                .collect();
+
+            let mut failed_lookups = 0;
+            let offsets = [TREE_INDICES, FEATURES, POLYGON_RINGS, RING_COORDS, PREPARED, BAND_OFFSETS, BAND_EDGES];
+            for section in offsets {
+                let Section { offset, len } = header.sections[section];
+                for position in (offset as usize..(offset + len) as usize).step_by(4) {
+                    let mut corrupted = bytes.clone();
+                    corrupted[position..position + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+                    std::fs::write(&path, &corrupted).unwrap();
+                    let index = MappedIndex::open(&path).unwrap();
+
+                    for (latitude, longitude) in &points {
+                        match index.find_all(*latitude, *longitude) {
+                            Err(IndexFileError::Corrupted(_)) => failed_lookups += 1,
+                            Err(_) => panic!("Wrong Error"),
+                            Ok(_) => {}
+                        }
+                        let _ = index.find_nearest(*latitude, *longitude, 100_000.0);
+                    }
+                    let _ = index.indexed_features().map(|feature| feature?.geometry()).count();
+                }
+            }
+            assert!(failed_lookups > 0);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,23 @@
+mod csv_geometry_reader;
+mod flatgeobuf_reader;
 mod geo_finder_types;
+mod geojson_reader;
+mod geopackage_reader;
+mod index_file;
+mod mapped_index;
 mod polygon_finder;
+mod prepared_area;
+mod projection;
+mod shapefile_reader;
+#[cfg(test)]
+mod test_utils;
+mod wkb;
 
 
 pub use geo_finder_types::*;
-pub use polygon_finder::PolygonFinder;
\ No newline at end of file
+pub use geojson_reader::{read_geojson_texts, GeoJsonText};
+pub use polygon_finder::{PolygonFinder, SourceFormat};
+pub use index_file::{open_index, save_index, IndexFileError, IndexLayout, IndexMetadata, IndexSource};
+
+/// The members of a JSON object, what `geojson` builds the features and their properties from.
+pub type JsonObject = serde_json::Map<String, serde_json::Value>;
@@ -1,42 +1,64 @@
+// The `Fail` derive of the errors expands to impls inside a const block.
+#![allow(non_local_definitions)]
+
 // use geo::{polygon};
 
 use cgmath::Point2;
 use failure::Fail;
 use geojson::Error as GeoJsonError;
-use geojson::GeoJson;
 
 use std::io;
 use std::path;
 
-use super::geo_finder_types::{PropertyMap, FindResult};
+use super::geo_finder_types::{PropertyMap, PropertyValue, FindResult, GeoFinder, IndexedFeature, MatchType, IndexStats, PropertyStats};
+use super::index_file::{IndexFileError, IndexLayout};
+use super::csv_geometry_reader;
+use super::flatgeobuf_reader;
+use super::geojson_reader;
+use super::geopackage_reader;
+use super::shapefile_reader;
+use super::prepared_area::PreparedArea;
+use geo::algorithm::area::Area as GeoArea;
 use geo::algorithm::bounding_rect::BoundingRect;
 use geo::algorithm::centroid::Centroid;
+use geo::algorithm::closest_point::ClosestPoint;
 use geo::algorithm::contains::Contains;
-// use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::algorithm::haversine_distance::HaversineDistance;
 // use geo::algorithm::euclidean_distance::EuclideanDistance;
 // use geo::algorithm::closest_point::ClosestPoint;
-use geo_types;
-use serde_json;
-use spade;
 use spade::rtree::RTree;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
-use std::io::prelude::*;
 use std::fs::File;
 
-use serde;
-
 // TODO: import from base module (without super::super::)
 use super::super::cli_utils;
 
 use log::{info};
 
+/// Length of a degree of latitude, using the same earth radius as `geo`'s haversine distance.
+const METERS_PER_DEGREE: f64 = 6_371_000.0 * std::f64::consts::PI / 180.0;
+
+/// Property with the name of the file of each feature, when they are tagged.
+const SOURCE_PROPERTY: &str = "_source";
+
+/**
+ * Radius (in degrees) around the point holding everything within `max_distance` meters.
+ */
+pub(super) fn search_radius(point: &geo::Point<f64>, max_distance: f64) -> f64 {
+    // A degree of longitude shrinks with the latitude, so take the widest search radius (in degrees)
+    // that can still be within `max_distance` meters.
+    let max_latitude = (point.y().abs() + max_distance / METERS_PER_DEGREE).min(89.9);
+    max_distance / (METERS_PER_DEGREE * max_latitude.to_radians().cos())
+}
+
 
 // #[cfg(test)] #[macro_use]
 // extern crate assert_matches;
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
-enum Area {
+pub(super) enum Area {
     Polygon(geo::Polygon<f64>),
     MultiPolygon(geo::MultiPolygon<f64>),
     Point(geo::Point<f64>),
@@ -83,6 +105,64 @@ impl Area where {
         }
     }
 
+    /**
+     * Unsigned area of the geometry (in squared degrees). Points have no area.
+     */
+    #[inline]
+    fn size(&self) -> f64 {
+        match self {
+            Area::Polygon(p) => p.area().abs(),
+            Area::MultiPolygon(p) => p.area().abs(),
+            Area::Point(_) => 0.0,
+        }
+    }
+
+    /**
+     * Exterior and interior rings of every polygon.
+     */
+    pub(super) fn rings(&self) -> Vec<&geo::LineString<f64>> {
+        fn polygon_rings(p: &geo::Polygon<f64>) -> impl Iterator<Item = &geo::LineString<f64>> {
+            std::iter::once(p.exterior()).chain(p.interiors())
+        }
+        match self {
+            Area::Polygon(p) => polygon_rings(p).collect(),
+            Area::MultiPolygon(p) => p.0.iter().flat_map(polygon_rings).collect(),
+            Area::Point(_) => Vec::new(),
+        }
+    }
+
+    /**
+     * Whether every coordinate is a number, NaN and infinite ones can't be located.
+     */
+    fn is_finite(&self) -> bool {
+        let finite = |coord: &geo::Coordinate<f64>| coord.x.is_finite() && coord.y.is_finite();
+        match self {
+            Area::Point(p) => finite(&p.0),
+            _ => self.rings().iter().all(|ring| ring.0.iter().all(finite)),
+        }
+    }
+
+    pub(super) fn vertices(&self) -> usize {
+        match self {
+            Area::Point(_) => 1,
+            _ => self.rings().iter().map(|ring| ring.num_coords()).sum(),
+        }
+    }
+
+    /**
+     * Estimated heap memory, in bytes.
+     */
+    fn heap_size(&self) -> usize {
+        let rings = self.rings();
+        let polygons = match self {
+            Area::MultiPolygon(p) => p.0.len(),
+            _ => 0,
+        };
+        rings.len() * std::mem::size_of::<geo::LineString<f64>>()
+            + polygons * std::mem::size_of::<geo::Polygon<f64>>()
+            + self.vertices() * std::mem::size_of::<geo::Coordinate<f64>>()
+    }
+
     /**
      * No optimization
      */
@@ -95,14 +175,59 @@ impl Area where {
         }
     }
 
+    /**
+     * Haversine distance in meters from the point to the closest point of the geometry boundary.
+     */
+    #[inline]
+    fn distance_meters(&self, point: &geo::Point<f64>) -> Option<f64> {
+        let closest = match self {
+            Area::Polygon(p) => p.closest_point(point),
+            Area::MultiPolygon(p) => p.closest_point(point),
+            Area::Point(p) => p.closest_point(point),
+        };
+
+        match closest {
+            geo::Closest::Intersection(p) | geo::Closest::SinglePoint(p) => Some(point.haversine_distance(&p)),
+            geo::Closest::Indeterminate => None,
+        }
+    }
+
+}
+
+/**
+ * Whether a geometry has any position. The readers skip the empty ones (and the missing ones), which cannot be
+ * indexed.
+ */
+pub(super) fn has_positions(geometry: &geojson::Geometry) -> bool {
+    match &geometry.value {
+        geojson::Value::Point(position) => position.len() >= 2,
+        geojson::Value::Polygon(rings) => rings.iter().any(|ring| !ring.is_empty()),
+        geojson::Value::MultiPolygon(polygons) => polygons.iter().flatten().any(|ring| !ring.is_empty()),
+        // Not indexable either, failing with the invalid feature error.
+        _ => true,
+    }
+}
+
+/**
+ * Whether the extension of the path is `extension`, ignoring the case: how the formats are told apart.
+ */
+pub(super) fn has_extension(path: &path::Path, extension: &str) -> bool {
+    path.extension()
+        .map(|path_extension| path_extension.eq_ignore_ascii_case(extension))
+        .unwrap_or(false)
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
-struct IndexablePolygon {
-    bbox: spade::BoundingRect::<Point2<f64>>,
+pub(super) struct IndexablePolygon {
+    /// Position in the files the index was generated from.
+    pub(super) id: usize,
+    pub(super) bbox: spade::BoundingRect::<Point2<f64>>,
     centroid: geo::Point<f64>,
-    area: Area,
-    properties: PropertyMap,
+    pub(super) area: Area,
+    pub(super) area_size: f64,
+    /// Only for the geometries with lots of vertices.
+    pub(super) prepared: Option<PreparedArea>,
+    pub(super) properties: PropertyMap,
 }
 
 impl IndexablePolygon {
@@ -136,6 +261,9 @@ impl IndexablePolygon {
             }
             _ => return Err(PolygonFinderError::InvalidFeature),
         };
+        if !area.is_finite() {
+            return Err(PolygonFinderError::NonFiniteCoordinates);
+        }
 
         let rect_bbox = area.mbr().ok_or(PolygonFinderError::GeometryNotFound)?;
 
@@ -145,35 +273,66 @@ impl IndexablePolygon {
             &Point2::new(rect_bbox.max.x, rect_bbox.max.y),
         );
     
+        let prepared = match area.vertices() >= PreparedArea::MIN_VERTICES {
+            true => Some(PreparedArea::new(&area.rings(), rect_bbox)),
+            false => None,
+        };
+
         let properties_json: serde_json::map::Map<String, serde_json::value::Value> =
             feature.properties.unwrap_or_default();
 
-        let mut properties = HashMap::new();
-        for (k, v) in properties_json {
-            match v {
-                serde_json::Value::String(v_str) => {
-                    properties.insert(k, v_str);
-                }
-                serde_json::Value::Number(v_num) => {
-                    properties.insert(k, v_num.to_string());
-                }
-                other => {
-                    return Err(PolygonFinderError::InvalidProperty(other))
-                },
-            }
-        }
+        let properties: PropertyMap = properties_json
+            .into_iter()
+            .map(|(k, v)| (k, PropertyValue::from(v)))
+            .collect();
 
         Ok(IndexablePolygon {
+            id: 0,
             centroid: area.centroid().unwrap(), // TODO: unwrap is not cool
             bbox,
+            area_size: area.size(),
+            prepared,
             area,
             properties,
         })
     }
 
+    /**
+     * Estimated memory, in bytes.
+     */
+    fn memory_size(&self) -> usize {
+        let properties_size: usize = self
+            .properties
+            .iter()
+            .map(|(name, value)| {
+                let value_size = match value {
+                    PropertyValue::String(text) | PropertyValue::Json(text) => text.len(),
+                    _ => 0,
+                };
+                std::mem::size_of::<(String, PropertyValue)>() + name.len() + value_size
+            })
+            .sum();
+
+        std::mem::size_of::<IndexablePolygon>()
+            + self.area.heap_size()
+            + self.prepared.as_ref().map(PreparedArea::heap_size).unwrap_or(0)
+            + properties_size
+    }
+
+    /**
+     * Exact test, using the prepared geometry when there is one.
+     */
+    #[inline]
+    fn contains_exact(&self, point: &geo::Point<f64>) -> bool {
+        match &self.prepared {
+            Some(prepared) => prepared.contains(point),
+            None => self.area.contains_exact(point),
+        }
+    }
+
     #[inline]
-    pub fn bbox(&self) -> &spade::BoundingRect::<Point2<f64>> {
-        return &self.bbox;
+    fn find_result(&self, match_type: MatchType, distance: f64) -> FindResult<'_> {
+        FindResult { feature: self.id, props: Cow::Borrowed(&self.properties), match_type, distance, area: self.area_size }
     }
 
 }
@@ -183,7 +342,7 @@ impl spade::SpatialObject for IndexablePolygon {
 
     #[inline]
     fn mbr(&self) -> spade::BoundingRect<Self::Point> {
-        return self.bbox;
+        self.bbox
     }
 
     /**
@@ -191,7 +350,7 @@ impl spade::SpatialObject for IndexablePolygon {
      */
     #[inline]
     fn distance2(&self, point: &Self::Point) -> f64 {
-        return self.bbox.distance2(point);
+        self.bbox.distance2(point)
 
         // Alternatives.
         // let centroid = self.centroid;
@@ -223,11 +382,9 @@ impl spade::SpatialObject for IndexablePolygon {
 pub enum PolygonFinderError {
     #[fail(display = "GeoJSON error: {}", _0)]
     Parse(GeoJsonError),
-    #[fail(display = "Invalid property: {}", _0)]
-    InvalidProperty(serde_json::Value),
     #[fail(display = "Invalid feature")]
     InvalidFeature,
-    #[fail(display = "Feature collection not found")]
+    #[fail(display = "Feature collection, feature or geometry not found")]
     FeatureCollectionNotFound,
     #[fail(display = "Geometry not found")]
     GeometryNotFound,
@@ -237,11 +394,41 @@ pub enum PolygonFinderError {
     InvalidMultiPolygon(GeoJsonError),
     #[fail(display = "Invalid point polygon: {}", _0)]
     InvalidPoint(GeoJsonError),
+    #[fail(display = "Coordinates must be finite numbers")]
+    NonFiniteCoordinates,
     #[allow(dead_code)]
     #[fail(display = "Cannot calculate distance")]
     CannotCalculateDistance,
     #[fail(display = "I/O error: {}", _0)]
     Io(io::Error),
+    #[fail(display = "{}: {}", _0, _1)]
+    InFile(String, Box<PolygonFinderError>),
+    #[fail(display = "Invalid shapefile: {}", _0)]
+    InvalidShapefile(String),
+    #[fail(display = "Projected coordinates ({}) are not supported, reproject the layer to WGS 84 first", _0)]
+    ProjectedCoordinates(String),
+    #[fail(display = "Cannot reproject the coordinates {}", _0)]
+    Reprojection(String),
+    #[fail(display = "Invalid WKB geometry: {}", _0)]
+    InvalidWkb(String),
+    #[fail(display = "Invalid FlatGeobuf file: {}", _0)]
+    InvalidFlatGeobuf(String),
+    #[fail(display = "GeoPackage error: {}", _0)]
+    GeoPackage(rusqlite::Error),
+    #[fail(display = "Layer '{}' not found, the layers are: {}", _0, _1)]
+    LayerNotFound(String, String),
+    #[fail(display = "The file has several layers, choose one of: {}", _0)]
+    LayerRequired(String),
+    #[fail(display = "CSV error: {}", _0)]
+    Csv(csv::Error),
+    #[fail(display = "Geometry column '{}' not found, the columns are: {}", _0, _1)]
+    ColumnNotFound(String, String),
+    #[fail(display = "Invalid WKT geometry: {}", _0)]
+    InvalidWkt(String),
+    #[fail(display = "line {}: {}", _0, _1)]
+    InRecord(u64, Box<PolygonFinderError>),
+    #[fail(display = "Layer '{}' has {} geometries, only polygons (and points) can be indexed", _0, _1)]
+    NotPolygonal(String, String),
 }
 
 impl From<GeoJsonError> for PolygonFinderError {
@@ -251,11 +438,73 @@ impl From<GeoJsonError> for PolygonFinderError {
     }
 }
 
+impl From<csv::Error> for PolygonFinderError {
+    fn from(err: csv::Error) -> PolygonFinderError {
+        PolygonFinderError::Csv(err)
+    }
+}
+
+impl From<rusqlite::Error> for PolygonFinderError {
+    fn from(err: rusqlite::Error) -> PolygonFinderError {
+        PolygonFinderError::GeoPackage(err)
+    }
+}
+
+impl From<serde_json::Error> for PolygonFinderError {
+    fn from(err: serde_json::Error) -> PolygonFinderError {
+        info!("Error parsing geojson: {}", err);
+        match err.classify() {
+            serde_json::error::Category::Io => PolygonFinderError::Io(err.into()),
+            serde_json::error::Category::Data => PolygonFinderError::FeatureCollectionNotFound,
+            _ => PolygonFinderError::Parse(GeoJsonError::MalformedJson),
+        }
+    }
+}
+
+/**
+ * Appends the polygon of a feature. The readers call it as they parse the features, so only the indexed
+ * geometries are kept in memory.
+ */
+fn add_polygon(
+    feature: geojson::Feature,
+    source: Option<&str>,
+    polygons: &mut Vec<IndexablePolygon>,
+) -> Result<(), PolygonFinderError> {
+    let mut polygon = IndexablePolygon::new(feature)?;
+    polygon.id = polygons.len();
+    if let Some(source) = source {
+        polygon.properties.insert(SOURCE_PROPERTY.to_owned(), PropertyValue::from(source));
+    }
+    polygons.push(polygon);
+    Ok(())
+}
+
+/**
+ * How the files of an index are read.
+ */
+pub enum SourceFormat<'a> {
+    /// Chosen by the extension: shapefiles (.shp), FlatGeobuf (.fgb), GeoPackages (.gpkg, the `layer`, which
+    /// can be omitted when they have only one) or GeoJSON documents (see `geojson_reader::read_features`).
+    Features { layer: Option<&'a str> },
+    /// CSV files with a WKT or hex encoded WKB geometry column (see `csv_geometry_reader::read_features`).
+    Csv { delimiter: u8, geometry_column: &'a str },
+}
+
+impl<'a> SourceFormat<'a> {
+    /// The files read for the source at `path`: itself, and the .dbf, .prj and .cpg files of shapefiles.
+    pub fn files_read(&self, path: &path::Path) -> Vec<path::PathBuf> {
+        let mut paths = vec![path.to_path_buf()];
+        if let (SourceFormat::Features { .. }, true) = (self, has_extension(path, "shp")) {
+            paths.extend(shapefile_reader::sidecar_paths(path));
+        }
+        paths
+    }
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct PolygonFinder {
     // geojson: GeoJson
     tree: RTree<IndexablePolygon>,
-    neighbors_tests: usize
 }
 
 impl PolygonFinder {
@@ -268,97 +517,247 @@ impl PolygonFinder {
     // }
 
     
-    pub fn new<P: AsRef<path::Path>>(geojson_path: P) -> Result<PolygonFinder, PolygonFinderError> {
-        let mut file = File::open(&geojson_path).unwrap();
-        let mut file_contents = String::new();
-        let _ = file.read_to_string(&mut file_contents);
+    /**
+     * Merges the features of every file in one index. With `tag_source`, each feature gets the name of its
+     * file in the `SOURCE_PROPERTY` property.
+     */
+    pub fn new_from_files<P: AsRef<path::Path>>(
+        paths: &[P],
+        format: &SourceFormat,
+        tag_source: bool,
+    ) -> Result<PolygonFinder, PolygonFinderError> {
+        let in_file = |path: &P, err| PolygonFinderError::InFile(path.as_ref().display().to_string(), Box::new(err));
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut total_size = 0;
+        for path in paths {
+            let file = File::open(path).map_err(|err| in_file(path, PolygonFinderError::Io(err)))?;
+            total_size += file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            files.push(file);
+        }
 
-        PolygonFinder::new_from_string(&file_contents)
+        let progress_bar = cli_utils::create_progress_bar_bytes(false, "Loading features...", Some(total_size));
+        let mut polygons = Vec::new();
+        for (path, file) in paths.iter().zip(files) {
+            let file_name = path.as_ref().file_name().unwrap_or_default().to_string_lossy();
+            let source = match tag_source {
+                true => Some(file_name.as_ref()),
+                false => None,
+            };
+
+            let file_size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            let reader = cli_utils::ProgressReader::new(file, progress_bar.clone());
+            let add_feature = |feature| add_polygon(feature, source, &mut polygons);
+            let result = if let SourceFormat::Csv { delimiter, geometry_column } = *format {
+                csv_geometry_reader::read_features(reader, delimiter, geometry_column, add_feature)
+            } else if has_extension(path.as_ref(), "shp") {
+                shapefile_reader::read_features(path.as_ref(), reader, add_feature)
+            } else if has_extension(path.as_ref(), "fgb") {
+                flatgeobuf_reader::read_features(path.as_ref(), reader, add_feature)
+            } else if let (true, SourceFormat::Features { layer }) = (has_extension(path.as_ref(), "gpkg"), format) {
+                // SQLite reads the file by itself.
+                let result = geopackage_reader::read_features(path.as_ref(), *layer, add_feature);
+                progress_bar.inc(file_size);
+                result
+            } else {
+                geojson_reader::read_features(io::BufReader::new(reader), add_feature)
+            };
+            if let Err(err) = result {
+                progress_bar.finish();
+                return Err(in_file(path, err));
+            }
+        }
+        progress_bar.finish();
+
+        Ok(PolygonFinder::bulk_load(polygons))
     }
 
+    #[cfg(test)]
     pub fn new_from_string(geojson_str: &str) -> Result<PolygonFinder, PolygonFinderError> {
-        
+        let mut polygons = Vec::new();
+        geojson_reader::read_features(geojson_str.as_bytes(), |feature| add_polygon(feature, None, &mut polygons))?;
+        Ok(PolygonFinder::bulk_load(polygons))
+    }
 
+    fn bulk_load(polygons: Vec<IndexablePolygon>) -> PolygonFinder {
+        info!("Bulk load of {} features", polygons.len());
+        let tree = RTree::bulk_load(polygons);
+        info!("Bulk load ended");
 
-        let progress_bar = cli_utils::create_progress_bar_count(false, "Parsing geojson...", None);
-        progress_bar.enable_steady_tick(200);
-        let geojson = geojson_str.parse::<GeoJson>()?;
-        progress_bar.finish();
+        PolygonFinder { tree }
+    }
 
-        let feature_collection = if let GeoJson::FeatureCollection(ctn) = geojson {
-            ctn
-        } else {
-            return Err(PolygonFinderError::FeatureCollectionNotFound);
-        };
 
-        let feature_count: u64 = feature_collection.features.len().try_into().unwrap();
-        let progress_bar = cli_utils::create_progress_bar_count(false, "Loading features...", Some(feature_count));
-    
-        let polygons: Result<Vec<_>, _> = feature_collection
-            .features
+    /**
+     * Every feature whose bounding box contains the point. Only those can contain the point exactly.
+     */
+    #[inline]
+    fn candidates(&self, point: &geo::Point<f64>) -> Vec<&IndexablePolygon> {
+        let tree_point = Point2::new(point.x(), point.y());
+        self.tree.lookup_in_rectangle(&spade::BoundingRect::from_point(tree_point))
+    }
+
+    pub fn find_by_point(&self, point: &geo::Point<f64>) -> Option<Box<FindResult<'_>>> {
+        /*
+         * We want to optimize for the "not in any geometry" scenario, so the tree filters everything with the
+         * bounding box before doing an exact lookup (that is much expensiver).
+         */
+        self.candidates(point)
+            .into_iter()
+            .find(|result| result.contains_exact(point))
+            .map(|result| Box::new(result.find_result(MatchType::Inside, 0.0)))
+    }
+
+    /**
+     * Like `find_by_point`, but returns every feature whose geometry contains the point (useful when the
+     * layers overlap).
+     */
+    pub fn find_all_by_point(&self, point: &geo::Point<f64>) -> Vec<FindResult<'_>> {
+        self.candidates(point)
+            .into_iter()
+            .filter(|result| result.contains_exact(point))
+            .map(|result| result.find_result(MatchType::Inside, 0.0))
+            .collect()
+    }
+
+    /**
+     * Finds the feature nearest to the point (by exact geometry distance) within `max_distance` meters.
+     * Meant as a fallback for points that are not inside any geometry.
+     */
+    pub fn find_nearest_by_point(&self, point: &geo::Point<f64>, max_distance: f64) -> Option<Box<FindResult<'_>>> {
+        let tree_point = Point2::new(point.x(), point.y());
+        let radius = search_radius(point, max_distance);
+
+        self.tree
+            .lookup_in_circle(&tree_point, &(radius * radius))
             .into_iter()
-            .map(|f| {
-                progress_bar.inc(1); 
-                IndexablePolygon::new(f)
+            .filter_map(|result| {
+                if result.contains_exact(point) {
+                    return Some((result, 0.0));
+                }
+                result.area.distance_meters(point).map(|distance| (result, distance))
             })
-            .collect();
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(result, distance)| Box::new(result.find_result(MatchType::Nearest, distance)))
+    }
 
-        progress_bar.finish();
+}
 
-        info!("Generating index");
+impl PolygonFinder {
+    pub(super) fn polygons(&self) -> impl Iterator<Item = &IndexablePolygon> {
+        self.tree.iter()
+    }
 
-        // let progress_bar = cli_utils::create_progress_bar_count(false, "Generating index...", None);
-        // progress_bar.enable_steady_tick(200);
-        info!("Bulk load");
-        let tree = RTree::bulk_load(polygons?);
-        info!("Bulk load ended");
-        // progress_bar.finish();
-        
+    /**
+     * Bounding box of every feature.
+     */
+    pub fn bbox(&self) -> Option<geo_types::Rect<f64>> {
+        self.tree.mbr().map(|mbr| geo_types::Rect {
+            min: geo::Coordinate { x: mbr.lower().x, y: mbr.lower().y },
+            max: geo::Coordinate { x: mbr.upper().x, y: mbr.upper().y },
+        })
+    }
+
+    /**
+     * Every property name, with the value types found for it.
+     */
+    pub fn property_schema(&self) -> BTreeMap<String, Vec<String>> {
+        let mut schema: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+        for polygon in self.tree.iter() {
+            for (name, value) in &polygon.properties {
+                schema.entry(name.clone()).or_default().insert(value.type_name());
+            }
+        }
+        schema
+            .into_iter()
+            .map(|(name, types)| (name, types.into_iter().map(String::from).collect()))
+            .collect()
+    }
+}
 
-        Ok(PolygonFinder { tree, neighbors_tests: 10 })
+impl GeoFinder for PolygonFinder {
+    fn layout(&self) -> IndexLayout {
+        IndexLayout::Bincode
     }
 
+    fn feature_count(&self) -> usize {
+        self.tree.size()
+    }
 
-    pub fn find_by_point(&self, point: &geo::Point<f64>) -> Option<Box<FindResult>> {
-        let tree_point = Point2::new(point.x(), point.y());
-        // let result = self.tree.lookup(&tree_point);
-        
-        // Since a lot of bounding boxes may match. We need the nearest, and test them.
-        let results = self.tree.nearest_n_neighbors(&tree_point, self.neighbors_tests);
+    fn find(&self, latitude: f64, longitude: f64) -> Result<Option<Box<FindResult<'_>>>, IndexFileError> {
+        Ok(self.find_by_point(&geo::Point::from((longitude, latitude))))
+    }
 
-        let point_geometry = Point2::new(point.x(), point.y());
-        
-        for result in results {
-            /* 
-             * But we want to optimize for the "not in any geometry" scenario, so we check everything with the 
-             * bounding box before doing an exact lookup (that is much expensiver).
-             */ 
-            if  !result.bbox().contains_point(&point_geometry)  {
-                return None
-            }
+    fn find_all(&self, latitude: f64, longitude: f64) -> Result<Vec<FindResult<'_>>, IndexFileError> {
+        Ok(self.find_all_by_point(&geo::Point::from((longitude, latitude))))
+    }
 
-            if  result.area.contains_exact(point) {
+    fn find_nearest(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        max_distance: f64,
+    ) -> Result<Option<Box<FindResult<'_>>>, IndexFileError> {
+        Ok(self.find_nearest_by_point(&geo::Point::from((longitude, latitude)), max_distance))
+    }
+
+    fn missing_properties<'p>(&self, properties: &[&'p str]) -> Vec<(&'p str, usize)> {
+        properties
+            .iter()
+            .map(|property| {
+                let count = self.tree.iter().filter(|polygon| !polygon.properties.contains_key(*property)).count();
+                (*property, count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
 
-                // let distance = result.area.haversine_distance2(point);
+    fn stats(&self, max_samples: usize) -> Result<IndexStats, IndexFileError> {
+        let mut stats = IndexStats {
+            feature_count: self.feature_count(),
+            polygons: 0,
+            multi_polygons: 0,
+            points: 0,
+            prepared: 0,
+            vertices: 0,
+            bbox: self.bbox().map(|rect| [rect.min.x, rect.min.y, rect.max.x, rect.max.y]),
+            tree_depth: self.tree.root().depth(),
+            memory_size: std::mem::size_of::<PolygonFinder>(),
+            properties: PropertyStats::collect(self.tree.iter().map(|polygon| &polygon.properties), max_samples),
+        };
 
-                return Some(Box::new(FindResult { props: &result.properties, distance: 0.0 } ));
+        for polygon in self.tree.iter() {
+            match polygon.area {
+                Area::Polygon(_) => stats.polygons += 1,
+                Area::MultiPolygon(_) => stats.multi_polygons += 1,
+                Area::Point(_) => stats.points += 1,
+            }
+            if polygon.prepared.is_some() {
+                stats.prepared += 1;
             }
+            stats.vertices += polygon.area.vertices();
+            stats.memory_size += polygon.memory_size();
         }
 
-        return None;
+        Ok(stats)
     }
 
-}
-
-impl PolygonFinder {
-    pub fn find(&self, latitude: f64, longitude: f64) -> Option<Box<FindResult>> {
-        return self.find_by_point(&geo::Point::from((longitude, latitude)));
+    fn indexed_features(&self) -> Box<dyn Iterator<Item = Result<IndexedFeature<'_>, IndexFileError>> + '_> {
+        Box::new(self.tree.iter().map(|polygon| {
+            Ok(IndexedFeature::new(polygon.id, Cow::Borrowed(&polygon.properties), move || match &polygon.area {
+                Area::Polygon(p) => Ok(geo_types::Geometry::Polygon(p.clone())),
+                Area::MultiPolygon(p) => Ok(geo_types::Geometry::MultiPolygon(p.clone())),
+                Area::Point(p) => Ok(geo_types::Geometry::Point(*p)),
+            }))
+        }))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     // let hello: &str =
     const MEXICO_GEOJSON_STR: &str = include_str!("test_resources/mexico_states.json");
@@ -368,15 +767,32 @@ mod tests {
     const ONE_FEATURE_GEOJSON_STR: &str = include_str!("test_resources/one_feature_geojson.json");
     const MALFORMED_GEOJSON_STR: &str = include_str!("test_resources/malformed_geojson.json");
 
-    const COLIMA_AGEBS_GEOJSON_STR: &str = include_str!("test_resources/agebs_colima.json");
+    // The AGEBs fixture is not shipped with the repository (too big). Tests using it are ignored
+    // by default: drop the file in `test_resources` and run them with `cargo test -- --ignored`.
+    const COLIMA_AGEBS_GEOJSON_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/src/geo_finder/test_resources/agebs_colima.json");
+    const NESTED_SQUARES_GEOJSON_STR: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"NAME": "big"},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]]]}},
+        {"type": "Feature", "properties": {"NAME": "small"},
+         "geometry": {"type": "Polygon", "coordinates": [[[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]]}},
+        {"type": "Feature", "properties": {"NAME": "apart"},
+         "geometry": {"type": "Polygon", "coordinates": [[[20, 20], [30, 20], [30, 30], [20, 30], [20, 20]]]}}
+    ]}"#;
+    const ZIP_CODES_WITH_MISSING_PROPERTY_GEOJSON_STR: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"ZIP_CODE": "28000", "STATE": "col"},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}},
+        {"type": "Feature", "properties": {"ZIP_CODE": "28001"},
+         "geometry": {"type": "Polygon", "coordinates": [[[1, 0], [2, 0], [2, 1], [1, 1], [1, 0]]]}}
+    ]}"#;
     const COLIMA_ZIP_CODES_GEOJSON_STR: &str = include_str!("test_resources/zip_codes_colima.json");
 
 
     #[test]
     fn it_should_parse_a_valid_geojson() {
-        let finder_result = PolygonFinder::new(&MEXICO_GEOJSON_STR);
+        let finder_result = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR);
 
-        assert_eq!(finder_result.is_ok(), true);
+        assert!(finder_result.is_ok());
     }
 
     // #[test]
@@ -387,8 +803,15 @@ mod tests {
     // }
 
     #[test]
-    fn it_should_fail_with_an_invalid_geojson_without_feature_collection() {
-        let finder_result = PolygonFinder::new(&ONE_FEATURE_GEOJSON_STR);
+    fn it_should_index_a_single_feature() {
+        let finder = PolygonFinder::new_from_string(ONE_FEATURE_GEOJSON_STR).unwrap();
+
+        assert_eq!(finder.feature_count(), 1);
+    }
+
+    #[test]
+    fn it_should_fail_with_an_invalid_geojson_without_features() {
+        let finder_result = PolygonFinder::new_from_string(r#"{"type": "Topology", "objects": {}}"#);
         match finder_result.err() {
             Some(PolygonFinderError::FeatureCollectionNotFound) => {}
             _ => {
@@ -399,7 +822,7 @@ mod tests {
 
     #[test]
     fn it_should_fail_with_an_malformed_geojson() {
-        let finder_result = PolygonFinder::new(&MALFORMED_GEOJSON_STR);
+        let finder_result = PolygonFinder::new_from_string(MALFORMED_GEOJSON_STR);
         match finder_result.err() {
             Some(PolygonFinderError::Parse(geojson::Error::MalformedJson)) => {}
             _ => {
@@ -408,44 +831,66 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_should_fail_with_non_finite_coordinates() {
+        let feature = |coordinates: Vec<Vec<f64>>| geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::new(geojson::Value::Polygon(vec![coordinates]))),
+            id: None,
+            properties: None,
+            foreign_members: None,
+        };
+
+        for bad in &[f64::NAN, f64::INFINITY] {
+            let coordinates = vec![vec![0.0, 0.0], vec![*bad, 0.0], vec![1.0, 1.0], vec![0.0, 0.0]];
+            match IndexablePolygon::new(feature(coordinates)).err() {
+                Some(PolygonFinderError::NonFiniteCoordinates) => {}
+                _ => panic!("Wrong Error"),
+            }
+        }
+        let coordinates = vec![vec![0.0, 0.0], vec![1.0, 0.0], vec![1.0, 1.0], vec![0.0, 0.0]];
+        assert!(IndexablePolygon::new(feature(coordinates)).is_ok());
+    }
+
     #[test]
     fn it_should_find_a_point_in_a_polygon() {
-        let finder = PolygonFinder::new(&MEXICO_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
 
         let result = finder.find_by_point(&geo::Point::from((-103.9936459, 23.1775256)));
 
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
     }
 
     #[test]
     fn it_should_not_find_a_point_outside_a_polygon() {
-        let finder = PolygonFinder::new(&MEXICO_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
 
         let result = finder.find_by_point(&geo::Point::from((0.1, 0.1)));
 
         print!("RESULT: {:?}", result);
-        assert_eq!(result.is_some(), false);
+        assert!(result.is_none());
     }
 
     // Mexico tests.
     #[test]
+    #[ignore]
     fn it_should_finds_easy_point_ageb() {
-        let finder = PolygonFinder::new(&COLIMA_AGEBS_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_files(&[COLIMA_AGEBS_GEOJSON_PATH], &SourceFormat::Features { layer: None }, false).unwrap();
 
-        let result = finder.find(19.320921, -103.8088817);
+        let result = finder.find(19.320921, -103.8088817).unwrap();
 
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
         assert_eq!(result.unwrap().props["CVEGEO"], "060030033");
     }
 
 
     #[test]
     fn it_should_find_coordinates_in_chihuahua() {
-        let finder = PolygonFinder::new(&MEXICO_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
 
-        let result = finder.find(28.14606, -105.34232);
+        let result = finder.find(28.14606, -105.34232).unwrap();
 
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
         assert_eq!(result.unwrap().props["CVEGEO"], "08");
         
     }
@@ -453,35 +898,231 @@ mod tests {
 
     #[test]
     fn it_should_find_coordinates_in_veracruz_border() {
-        let finder = PolygonFinder::new(&MEXICO_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_string(MEXICO_GEOJSON_STR).unwrap();
 
-        let result = finder.find(22.22553, -97.90096);
+        let result = finder.find(22.22553, -97.90096).unwrap();
 
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
         assert_eq!(result.unwrap().props["CVEGEO"], "30");
         
     }
 
 
     #[test]
+    #[ignore]
     fn it_should_not_find_a_point_outside() {
-        let finder = PolygonFinder::new(&COLIMA_AGEBS_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_files(&[COLIMA_AGEBS_GEOJSON_PATH], &SourceFormat::Features { layer: None }, false).unwrap();
 
         let result = finder.find_by_point(&geo::Point::from((0.0, 0.0)));
 
-        assert_eq!(result.is_some(), false);
+        assert!(result.is_none());
     }
 
     #[test]
     fn it_should_find_a_point_in_zip_codes() {
-        let finder = PolygonFinder::new(&COLIMA_ZIP_CODES_GEOJSON_STR).unwrap();
+        let finder = PolygonFinder::new_from_string(COLIMA_ZIP_CODES_GEOJSON_STR).unwrap();
 
-        let result = finder.find(19.2740353, -103.7427995);
+        let result = finder.find(19.2740353, -103.7427995).unwrap();
 
-        assert_eq!(result.is_some(), true);
+        assert!(result.is_some());
 
         let result = result.unwrap();
         assert_eq!(result.props["ZIP_CODE"], "28989");
         assert_eq!(result.props["STATE"], "col");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn it_should_find_all_overlapping_polygons() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+
+        let results = finder.find_all(5.0, 5.0).unwrap();
+        let mut names: Vec<&str> = results.iter().map(|result| result.props["NAME"].as_str().unwrap()).collect();
+        names.sort();
+
+        assert_eq!(names, vec!["big", "small"]);
+    }
+
+    #[test]
+    fn it_should_find_all_with_a_single_polygon() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+
+        let results = finder.find_all(1.0, 1.0).unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].props["NAME"], "big");
+        assert!((results[0].area - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_should_find_all_nothing_outside() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+
+        assert!(finder.find_all(15.0, 15.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_should_find_the_nearest_polygon_within_the_max_distance() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+
+        // ~111 meters east of the big square.
+        let result = finder.find_nearest(5.0, 10.001, 200.0).unwrap().unwrap();
+
+        assert_eq!(result.props["NAME"], "big");
+        assert_eq!(result.match_type, MatchType::Nearest);
+        assert!((result.distance - 110.8).abs() < 1.0);
+    }
+
+    #[test]
+    fn it_should_not_find_the_nearest_polygon_beyond_the_max_distance() {
+        let finder = PolygonFinder::new_from_string(NESTED_SQUARES_GEOJSON_STR).unwrap();
+
+        assert!(finder.find_nearest(5.0, 10.001, 50.0).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_should_find_a_point_among_many_overlapping_bounding_boxes() {
+        // Lots of triangles whose bbox contains (6, 6) but that don't contain it, plus squares that do.
+        let decoys = (0..200).map(|i| {
+            format!(
+                r#"{{"type": "Feature", "properties": {{"NAME": "decoy {}"}},
+                    "geometry": {{"type": "Polygon", "coordinates": [[[{o}, {o}], [10, {o}], [{o}, 10], [{o}, {o}]]]}}}}"#,
+                i,
+                o = i as f64 / 1000.0
+            )
+        });
+        let targets = (0..50).map(|i| {
+            format!(
+                r#"{{"type": "Feature", "properties": {{"NAME": "target {}"}},
+                    "geometry": {{"type": "Polygon", "coordinates": [[[{min}, {min}], [{max}, {min}], [{max}, {max}], [{min}, {max}], [{min}, {min}]]]}}}}"#,
+                i,
+                min = 5.9 - i as f64 / 100.0,
+                max = 6.1 + i as f64 / 100.0
+            )
+        });
+        let features: Vec<String> = decoys.chain(targets).collect();
+        let geojson = format!(r#"{{"type": "FeatureCollection", "features": [{}]}}"#, features.join(","));
+
+        let finder = PolygonFinder::new_from_string(&geojson).unwrap();
+
+        let result = finder.find(6.0, 6.0).unwrap();
+        assert!(result.is_some());
+        assert!(result.unwrap().props["NAME"].as_str().unwrap().starts_with("target"));
+
+        let results = finder.find_all(6.0, 6.0).unwrap();
+        assert_eq!(results.len(), 50);
+        assert!(results.iter().all(|result| result.props["NAME"].as_str().unwrap().starts_with("target")));
+    }
+
+    #[test]
+    fn it_should_count_the_features_missing_a_property() {
+        let finder = PolygonFinder::new_from_string(ZIP_CODES_WITH_MISSING_PROPERTY_GEOJSON_STR).unwrap();
+
+        let missing = finder.missing_properties(&["ZIP_CODE", "STATE", "NAME"]);
+
+        assert_eq!(finder.feature_count(), 2);
+        assert_eq!(missing, vec![("STATE", 1), ("NAME", 2)]);
+    }
+
+    #[test]
+    fn it_should_keep_every_property_type() {
+        let geojson = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature",
+             "properties": {"name": "a", "count": 3, "ratio": 0.5, "active": true, "note": null, "tags": ["x", 1]},
+             "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}}
+        ]}"#;
+        let finder = PolygonFinder::new_from_string(geojson).unwrap();
+
+        let result = finder.find(0.5, 0.5).unwrap().unwrap();
+
+        assert_eq!(result.props["name"], PropertyValue::String("a".to_owned()));
+        assert_eq!(result.props["count"], PropertyValue::Integer(3));
+        assert_eq!(result.props["ratio"], PropertyValue::Float(0.5));
+        assert_eq!(result.props["active"], PropertyValue::Bool(true));
+        assert_eq!(result.props["note"], PropertyValue::Null);
+        assert_eq!(result.props["tags"], PropertyValue::Json(r#"["x",1]"#.to_owned()));
+    }
+
+    #[test]
+    fn it_should_keep_the_integers_above_i64_max() {
+        let geojson = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"id": 18446744073709551615, "small": -1},
+             "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}}
+        ]}"#;
+        let finder = PolygonFinder::new_from_string(geojson).unwrap();
+        let bytes = bincode::serialize(&finder).unwrap();
+        let finder: PolygonFinder = bincode::deserialize(&bytes).unwrap();
+
+        let result = finder.find(0.5, 0.5).unwrap().unwrap();
+        assert_eq!(result.props["id"], PropertyValue::UInteger(u64::MAX));
+        assert_eq!(result.props["small"], PropertyValue::Integer(-1));
+        assert_eq!(result.props["id"].to_string(), "18446744073709551615");
+        assert_eq!(serde_json::Value::from(&result.props["id"]), serde_json::json!(18446744073709551615u64));
+    }
+
+    #[test]
+    fn it_should_keep_the_property_types_in_the_serialized_index() {
+        let geojson = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"note": null, "nested": {"a": [1, 2]}, "ratio": 1e-3},
+             "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]}}
+        ]}"#;
+        let finder = PolygonFinder::new_from_string(geojson).unwrap();
+
+        let bytes = bincode::serialize(&finder).unwrap();
+        let finder: PolygonFinder = bincode::deserialize(&bytes).unwrap();
+
+        let result = finder.find(0.5, 0.5).unwrap().unwrap();
+        assert_eq!(result.props["note"], PropertyValue::Null);
+        assert_eq!(result.props["nested"], PropertyValue::Json(r#"{"a":[1,2]}"#.to_owned()));
+        assert_eq!(result.props["ratio"], PropertyValue::Float(0.001));
+    }
+
+    #[test]
+    fn it_should_describe_the_index() {
+        let finder = PolygonFinder::new_from_string(ZIP_CODES_WITH_MISSING_PROPERTY_GEOJSON_STR).unwrap();
+
+        let stats = finder.stats(1).unwrap();
+
+        assert_eq!(stats.feature_count, 2);
+        assert_eq!((stats.polygons, stats.multi_polygons, stats.points), (2, 0, 0));
+        assert_eq!(stats.vertices, 10);
+        assert_eq!(stats.bbox, Some([0.0, 0.0, 2.0, 1.0]));
+        assert_eq!(stats.properties.len(), 2);
+
+        let state = &stats.properties[0];
+        assert_eq!((state.name.as_str(), state.cardinality, state.missing), ("STATE", 1, 1));
+
+        let zip_code = &stats.properties[1];
+        assert_eq!((zip_code.name.as_str(), zip_code.cardinality, zip_code.missing), ("ZIP_CODE", 2, 0));
+        assert_eq!(zip_code.samples, vec!["28000"]);
+    }
+
+    #[test]
+    fn it_should_merge_several_files_tagging_their_source() {
+        let dir = std::env::temp_dir().join(format!("fsj-sources-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("squares.json"), NESTED_SQUARES_GEOJSON_STR).unwrap();
+        fs::write(dir.join("zip_codes.json"), ZIP_CODES_WITH_MISSING_PROPERTY_GEOJSON_STR).unwrap();
+
+        let finder = PolygonFinder::new_from_files(&[dir.join("squares.json"), dir.join("zip_codes.json")], &SourceFormat::Features { layer: None }, true);
+        let missing = PolygonFinder::new_from_files(&[dir.join("squares.json"), dir.join("missing.json")], &SourceFormat::Features { layer: None }, true);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let finder = finder.unwrap();
+        assert_eq!(finder.feature_count(), 5);
+        let results = finder.find_all(0.5, 0.5).unwrap();
+        let mut sources: Vec<_> = results.iter().map(|result| result.props[SOURCE_PROPERTY].to_string()).collect();
+        sources.sort();
+        assert_eq!(sources, vec!["squares.json", "zip_codes.json"]);
+
+        match missing {
+            Err(PolygonFinderError::InFile(path, error)) => {
+                assert!(path.ends_with("missing.json"));
+                match *error {
+                    PolygonFinderError::Io(_) => {}
+                    _ => panic!("Wrong Error"),
+                }
+            }
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use geo_types::{Coordinate, Line, LineString, Rect};
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[repr(u8)]
+pub enum Cell {
+    Outside,
+    Inside,
+    /// Some edge crosses the cell, points inside it need an exact test.
+    Boundary,
+}
+
+impl Cell {
+    /// Inverse of `cell as u8`. Unknown values need the exact test.
+    #[inline]
+    pub fn from_u8(value: u8) -> Cell {
+        match value {
+            0 => Cell::Outside,
+            1 => Cell::Inside,
+            _ => Cell::Boundary,
+        }
+    }
+}
+
+/**
+ * Splits the bounding box of a geometry in `size` x `size` cells.
+ *
+ * Plain data, so the memory mapped index can use it in place.
+ */
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct Grid {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+    cell_width: f64,
+    cell_height: f64,
+    size: u64,
+}
+
+impl Grid {
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    #[inline]
+    fn cell_of(&self, x: f64, y: f64) -> (usize, usize) {
+        let last = (self.size - 1) as f64;
+        let col = ((x - self.min_x) / self.cell_width).floor().clamp(0.0, last);
+        let row = ((y - self.min_y) / self.cell_height).floor().clamp(0.0, last);
+        (col as usize, row as usize)
+    }
+
+    /**
+     * Column and row of the cell containing the point, if it is inside the bounding box.
+     */
+    #[inline]
+    pub fn locate(&self, point: &Coordinate<f64>) -> Option<(usize, usize)> {
+        if point.x < self.min_x || point.x > self.max_x || point.y < self.min_y || point.y > self.max_y {
+            return None;
+        }
+        Some(self.cell_of(point.x, point.y))
+    }
+}
+
+/**
+ * Whether the edge from `a` to `b` crosses the horizontal ray going right from the point.
+ */
+#[inline]
+pub fn crosses_ray(a: Coordinate<f64>, b: Coordinate<f64>, point: &Coordinate<f64>) -> bool {
+    (a.y > point.y) != (b.y > point.y) && point.x < a.x + (point.y - a.y) * (b.x - a.x) / (b.y - a.y)
+}
+
+/**
+ * Point in polygon acceleration for geometries with lots of vertices.
+ *
+ * The bounding box is split in a grid. Cells that no edge crosses are entirely inside or outside the
+ * geometry, so it is classified once when the index is built. For the rest, the edges are bucketed in
+ * horizontal bands, so the ray casting only looks at the edges that can cross the ray.
+ */
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct PreparedArea {
+    grid: Grid,
+    cells: Vec<Cell>,
+    edges: Vec<Line<f64>>,
+    bands: Vec<Vec<u32>>,
+}
+
+impl PreparedArea {
+    /// Geometries with less vertices are fast enough without preparing them.
+    pub const MIN_VERTICES: usize = 256;
+
+    const MAX_GRID_SIZE: usize = 512;
+
+    /**
+     * Builds the acceleration structure from every ring (exteriors and interiors) of the geometry.
+     */
+    pub fn new(rings: &[&LineString<f64>], bbox: Rect<f64>) -> PreparedArea {
+        let edges: Vec<Line<f64>> = rings.iter().flat_map(|ring| ring.lines()).collect();
+
+        let size = ((edges.len() as f64).sqrt() as usize).clamp(1, PreparedArea::MAX_GRID_SIZE);
+        let cell_size = |length: f64| if length > 0.0 { length / size as f64 } else { 1.0 };
+
+        let grid = Grid {
+            min_x: bbox.min.x,
+            min_y: bbox.min.y,
+            max_x: bbox.max.x,
+            max_y: bbox.max.y,
+            cell_width: cell_size(bbox.max.x - bbox.min.x),
+            cell_height: cell_size(bbox.max.y - bbox.min.y),
+            size: size as u64,
+        };
+        let mut prepared = PreparedArea {
+            grid,
+            cells: vec![Cell::Outside; size * size],
+            edges: Vec::new(),
+            bands: vec![Vec::new(); size],
+        };
+
+        for (idx, edge) in edges.iter().enumerate() {
+            let (min_col, min_row) = grid.cell_of(edge.start.x.min(edge.end.x), edge.start.y.min(edge.end.y));
+            let (max_col, max_row) = grid.cell_of(edge.start.x.max(edge.end.x), edge.start.y.max(edge.end.y));
+
+            for row in min_row..=max_row {
+                prepared.bands[row].push(idx as u32);
+                // Conservative: every cell in the edge bbox is a boundary cell.
+                for col in min_col..=max_col {
+                    prepared.cells[row * size + col] = Cell::Boundary;
+                }
+            }
+        }
+        prepared.edges = edges;
+
+        // Classify the rest of the cells with one ray per row (through the centers of its cells).
+        for row in 0..size {
+            let y = grid.min_y + (row as f64 + 0.5) * grid.cell_height;
+            let mut crossings = prepared.crossings(row, y);
+            crossings.sort_by(f64::total_cmp);
+
+            let mut crossed = 0;
+            for col in 0..size {
+                let x = grid.min_x + (col as f64 + 0.5) * grid.cell_width;
+                while crossed < crossings.len() && crossings[crossed] <= x {
+                    crossed += 1;
+                }
+                let cell = &mut prepared.cells[row * size + col];
+                if *cell != Cell::Boundary {
+                    // Inside when the edges to the right of the center are odd.
+                    *cell = match (crossings.len() - crossed) % 2 == 1 {
+                        true => Cell::Inside,
+                        false => Cell::Outside,
+                    };
+                }
+            }
+        }
+
+        prepared
+    }
+
+    /**
+     * X coordinates where the edges of the band cross the horizontal line at `y`.
+     */
+    fn crossings(&self, band: usize, y: f64) -> Vec<f64> {
+        self.bands[band]
+            .iter()
+            .map(|&idx| &self.edges[idx as usize])
+            .filter(|edge| (edge.start.y > y) != (edge.end.y > y))
+            .map(|edge| edge.start.x + (y - edge.start.y) * (edge.end.x - edge.start.x) / (edge.end.y - edge.start.y))
+            .collect()
+    }
+
+    /**
+     * Even-odd ray casting, only with the edges of the band.
+     */
+    #[inline]
+    fn ray_cast(&self, band: usize, point: &Coordinate<f64>) -> bool {
+        let mut inside = false;
+        for &idx in &self.bands[band] {
+            let edge = &self.edges[idx as usize];
+            if crosses_ray(edge.start, edge.end, point) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    /// Row by row.
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// For each row, the edges that cross it. Edges are numbered ring after ring, in the order given to `new`.
+    pub fn bands(&self) -> &[Vec<u32>] {
+        &self.bands
+    }
+
+    /**
+     * Estimated heap memory, in bytes.
+     */
+    pub fn heap_size(&self) -> usize {
+        self.cells.len() * std::mem::size_of::<Cell>()
+            + self.edges.len() * std::mem::size_of::<Line<f64>>()
+            + self.bands.iter().map(|band| std::mem::size_of::<Vec<u32>>() + band.len() * 4).sum::<usize>()
+    }
+
+    #[inline]
+    pub fn contains(&self, point: &geo::Point<f64>) -> bool {
+        let coord = Coordinate { x: point.x(), y: point.y() };
+        match self.grid.locate(&coord) {
+            None => false,
+            Some((col, row)) => match self.cells[row * self.grid.size() + col] {
+                Cell::Inside => true,
+                Cell::Outside => false,
+                Cell::Boundary => self.ray_cast(row, &coord),
+            },
+        }
+    }
+}
+
+/// Geometries shared by the tests and the benchmark (`benches/prepared_area.rs`).
+#[doc(hidden)]
+#[allow(dead_code)]
+pub mod fixtures {
+    use super::PreparedArea;
+    use geo::algorithm::bounding_rect::BoundingRect;
+    use geo_types::LineString;
+
+    /// A spiky circle (lots of concave parts) with a spiky hole.
+    pub fn spiky_polygon(vertices: usize) -> geo::Polygon<f64> {
+        let ring = |radius: f64, spike: f64| -> LineString<f64> {
+            let mut coords: Vec<(f64, f64)> = (0..vertices)
+                .map(|i| {
+                    let angle = 2.0 * std::f64::consts::PI * i as f64 / vertices as f64;
+                    let r = if i % 2 == 0 { radius } else { radius * spike };
+                    (-100.0 + r * angle.cos(), 20.0 + r * angle.sin())
+                })
+                .collect();
+            coords.push(coords[0]);
+            coords.into()
+        };
+        geo::Polygon::new(ring(10.0, 0.9), vec![ring(3.0, 0.8)])
+    }
+
+    /// Deterministic pseudo random points around the polygon.
+    pub fn sample_points(count: usize) -> Vec<geo::Point<f64>> {
+        let mut seed: u64 = 42;
+        let mut next = || {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        (0..count)
+            .map(|_| geo::Point::from((-112.0 + 24.0 * next(), 8.0 + 24.0 * next())))
+            .collect()
+    }
+
+    pub fn prepare(polygon: &geo::Polygon<f64>) -> PreparedArea {
+        let rings: Vec<&LineString<f64>> = std::iter::once(polygon.exterior()).chain(polygon.interiors()).collect();
+        PreparedArea::new(&rings, polygon.bounding_rect().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+    use geo::algorithm::contains::Contains;
+
+    #[test]
+    fn it_should_agree_with_the_exact_contains() {
+        let polygon = spiky_polygon(2000);
+        let prepared = prepare(&polygon);
+
+        for point in sample_points(20_000) {
+            assert_eq!(prepared.contains(&point), polygon.contains(&point), "{:?}", point);
+        }
+    }
+
+    #[test]
+    fn it_should_not_contain_points_outside_the_bbox() {
+        let polygon = spiky_polygon(1000);
+        let prepared = prepare(&polygon);
+
+        assert!(!prepared.contains(&geo::Point::from((0.0, 0.0))));
+        assert!(prepared.contains(&geo::Point::from((-100.0, 26.0))));
+        assert!(!prepared.contains(&geo::Point::from((-100.0, 20.0)))); // In the hole.
+    }
+
+    #[test]
+    fn it_should_prepare_a_ring_with_nan_coordinates() {
+        let mut polygon = spiky_polygon(300);
+        polygon.exterior_mut(|ring| ring.0.iter_mut().step_by(7).for_each(|coord| coord.x = f64::NAN));
+        // The bounding rect of `geo` panics on NaN.
+        let bbox = Rect { min: Coordinate { x: -110.0, y: 10.0 }, max: Coordinate { x: -90.0, y: 30.0 } };
+        let prepared = PreparedArea::new(&[polygon.exterior()], bbox);
+
+        assert!(!prepared.contains(&geo::Point::from((0.0, 0.0))));
+    }
+}
@@ -0,0 +1,324 @@
+use geojson::{Position, Value};
+use proj4rs::proj::Proj;
+
+use super::polygon_finder::PolygonFinderError;
+
+/// A value of a WKT coordinate reference system: `KEYWORD["text",number,KEYWORD[...]]`.
+#[derive(Debug, PartialEq)]
+enum WktValue {
+    Text(String),
+    Number(f64),
+    Node(WktNode),
+}
+
+#[derive(Debug, PartialEq)]
+struct WktNode {
+    keyword: String,
+    values: Vec<WktValue>,
+}
+
+impl WktNode {
+    fn name(&self) -> &str {
+        match self.values.first() {
+            Some(WktValue::Text(name)) => name,
+            _ => "",
+        }
+    }
+
+    fn number(&self, index: usize) -> Option<f64> {
+        match self.values.get(index) {
+            Some(WktValue::Number(number)) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The direct children with the keyword (case insensitive).
+    fn children<'a>(&'a self, keyword: &'a str) -> impl Iterator<Item = &'a WktNode> + 'a {
+        self.values.iter().filter_map(move |value| match value {
+            WktValue::Node(node) if node.keyword.eq_ignore_ascii_case(keyword) => Some(node),
+            _ => None,
+        })
+    }
+
+    fn child<'a>(&'a self, keyword: &'a str) -> Option<&'a WktNode> {
+        self.children(keyword).next()
+    }
+
+    /// Searches the nested nodes too, depth first.
+    fn find(&self, keyword: &str) -> Option<&WktNode> {
+        self.values.iter().find_map(|value| match value {
+            WktValue::Node(node) if node.keyword.eq_ignore_ascii_case(keyword) => Some(node),
+            WktValue::Node(node) => node.find(keyword),
+            _ => None,
+        })
+    }
+}
+
+/**
+ * Parses a WKT 1 coordinate reference system, as in .prj files. Both `[]` and `()` delimit the values.
+ */
+fn parse_wkt(text: &str) -> Option<WktNode> {
+    let mut chars = text.trim().chars().peekable();
+    let node = parse_node(&mut chars)?;
+    match chars.all(char::is_whitespace) {
+        true => Some(node),
+        false => None,
+    }
+}
+
+fn parse_node(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<WktNode> {
+    let mut keyword = String::new();
+    while let Some(&c) = chars.peek() {
+        match c.is_ascii_alphanumeric() || c == '_' {
+            true => keyword.push(c),
+            false => break,
+        }
+        chars.next();
+    }
+    skip_whitespace(chars);
+    if keyword.is_empty() || !matches!(chars.next(), Some('[') | Some('(')) {
+        return None;
+    }
+
+    let mut values = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        let value = match *chars.peek()? {
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next()? {
+                        // A quote is escaped doubling it.
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            text.push('"');
+                        }
+                        '"' => break,
+                        c => text.push(c),
+                    }
+                }
+                WktValue::Text(text)
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    match c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.' {
+                        true => number.push(c),
+                        false => break,
+                    }
+                    chars.next();
+                }
+                WktValue::Number(number.parse().ok()?)
+            }
+            _ => WktValue::Node(parse_node(chars)?),
+        };
+        values.push(value);
+
+        skip_whitespace(chars);
+        match chars.next()? {
+            ',' => {}
+            ']' | ')' => return Some(WktNode { keyword, values }),
+            _ => return None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+        chars.next();
+    }
+}
+
+/**
+ * The proj string of a `PROJCS`: the Lambert conformal conic, transverse Mercator, Mercator and Albers
+ * projections, the ones of national and UTM grids. `None` for the others.
+ */
+fn proj_string(projcs: &WktNode) -> Option<String> {
+    let projection = projcs.child("PROJECTION")?.name().to_ascii_lowercase();
+    let parameter = |names: &[&str]| {
+        projcs
+            .children("PARAMETER")
+            .find(|parameter| names.iter().any(|name| parameter.name().eq_ignore_ascii_case(name)))
+            .and_then(|parameter| parameter.number(1))
+    };
+    let latitude_of_origin = parameter(&["latitude_of_origin", "latitude_of_center"]).unwrap_or(0.0);
+    let standard_parallel_1 = parameter(&["standard_parallel_1"]);
+
+    let mut proj = match projection.as_str() {
+        "lambert_conformal_conic" | "lambert_conformal_conic_1sp" | "lambert_conformal_conic_2sp" => {
+            let lat_1 = standard_parallel_1.unwrap_or(latitude_of_origin);
+            let lat_2 = parameter(&["standard_parallel_2"]).unwrap_or(lat_1);
+            format!("+proj=lcc +lat_1={} +lat_2={} +lat_0={}", lat_1, lat_2, latitude_of_origin)
+        }
+        "albers" | "albers_conic_equal_area" => {
+            let lat_1 = standard_parallel_1?;
+            let lat_2 = parameter(&["standard_parallel_2"]).unwrap_or(lat_1);
+            format!("+proj=aea +lat_1={} +lat_2={} +lat_0={}", lat_1, lat_2, latitude_of_origin)
+        }
+        "transverse_mercator" => format!("+proj=tmerc +lat_0={}", latitude_of_origin),
+        "mercator" | "mercator_1sp" => "+proj=merc".to_owned(),
+        "mercator_2sp" => format!("+proj=merc +lat_ts={}", standard_parallel_1.unwrap_or(0.0)),
+        _ => return None,
+    };
+
+    // The false easting and northing are in the linear unit, proj takes them in meters.
+    let to_meter = projcs.child("UNIT").and_then(|unit| unit.number(1)).unwrap_or(1.0);
+    let central_meridian = parameter(&["central_meridian", "longitude_of_center", "longitude_of_origin"]);
+    proj.push_str(&format!(
+        " +lon_0={} +x_0={} +y_0={} +to_meter={}",
+        central_meridian.unwrap_or(0.0),
+        parameter(&["false_easting"]).unwrap_or(0.0) * to_meter,
+        parameter(&["false_northing"]).unwrap_or(0.0) * to_meter,
+        to_meter,
+    ));
+    if let Some(scale_factor) = parameter(&["scale_factor"]) {
+        proj.push_str(&format!(" +k_0={}", scale_factor));
+    }
+    proj.push_str(&ellipsoid(projcs));
+    Some(proj)
+}
+
+/// The ellipsoid of the datum, GRS 80 when it is not given. A sphere when the inverse flattening is 0.
+fn ellipsoid(projcs: &WktNode) -> String {
+    let spheroid = projcs.find("SPHEROID").or_else(|| projcs.find("ELLIPSOID"));
+    match spheroid.and_then(|spheroid| Some((spheroid.number(1)?, spheroid.number(2)?))) {
+        Some((a, 0.0)) => format!(" +a={} +b={}", a, a),
+        Some((a, rf)) => format!(" +a={} +rf={}", a, rf),
+        None => " +ellps=GRS80".to_owned(),
+    }
+}
+
+/**
+ * Converts the projected coordinates of a layer to longitudes and latitudes, on the same ellipsoid (the datum
+ * shifts are below the precision of the administrative boundaries).
+ */
+pub struct Projection {
+    projected: Proj,
+    geographic: Proj,
+    /// Longitude of the prime meridian from Greenwich.
+    prime_meridian: f64,
+}
+
+impl Projection {
+    /**
+     * The projection of a WKT coordinate reference system (the contents of a .prj file, or the CRS of a
+     * GeoPackage or FlatGeobuf layer), `None` when the coordinates are already geographic. Fails with the
+     * unsupported projections, and with the projected CRSs in WKT 2 (`PROJCRS`).
+     */
+    pub fn from_wkt(wkt: &str) -> Result<Option<Projection>, PolygonFinderError> {
+        let unsupported = || PolygonFinderError::ProjectedCoordinates(wkt.split('"').nth(1).unwrap_or_default().to_owned());
+        let keyword = wkt.trim_start().to_ascii_uppercase();
+        if keyword.starts_with("PROJCRS") {
+            return Err(unsupported());
+        }
+        if !keyword.starts_with("PROJCS") {
+            return Ok(None);
+        }
+
+        let projcs = parse_wkt(wkt).ok_or_else(unsupported)?;
+        let proj = proj_string(&projcs).ok_or_else(unsupported)?;
+        let projected = Proj::from_proj_string(&proj).map_err(|_| unsupported())?;
+        let geographic = Proj::from_proj_string(&format!("+proj=longlat{}", ellipsoid(&projcs)))
+            .map_err(|_| unsupported())?;
+        let prime_meridian = projcs.find("PRIMEM").and_then(|primem| primem.number(1)).unwrap_or(0.0);
+
+        Ok(Some(Projection { projected, geographic, prime_meridian }))
+    }
+
+    /// The longitude and latitude, in degrees, of a projected position.
+    pub fn to_geographic(&self, x: f64, y: f64) -> Result<(f64, f64), PolygonFinderError> {
+        let mut point = (x, y, 0.0);
+        proj4rs::transform::transform(&self.projected, &self.geographic, &mut point)
+            .map_err(|err| PolygonFinderError::Reprojection(format!("({}, {}): {}", x, y, err)))?;
+        Ok((point.0.to_degrees() + self.prime_meridian, point.1.to_degrees()))
+    }
+
+    /// Converts every position of a geometry to longitude and latitude.
+    pub fn reproject(&self, value: &mut Value) -> Result<(), PolygonFinderError> {
+        let positions: Box<dyn Iterator<Item = &mut Position>> = match value {
+            Value::Point(position) => Box::new(std::iter::once(position)),
+            Value::MultiPoint(positions) | Value::LineString(positions) => Box::new(positions.iter_mut()),
+            Value::Polygon(rings) | Value::MultiLineString(rings) => Box::new(rings.iter_mut().flatten()),
+            Value::MultiPolygon(polygons) => Box::new(polygons.iter_mut().flatten().flatten()),
+            Value::GeometryCollection(geometries) => {
+                return geometries.iter_mut().try_for_each(|geometry| self.reproject(&mut geometry.value));
+            }
+        };
+        for position in positions.filter(|position| position.len() >= 2) {
+            let (longitude, latitude) = self.to_geographic(position[0], position[1])?;
+            position[0] = longitude;
+            position[1] = latitude;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEXICO_LCC: &str = r#"PROJCS["MEXICO_ITRF_2008_LCC",GEOGCS["GCS_ITRF_2008",DATUM["D_ITRF_2008",
+        SPHEROID["GRS_1980",6378137.0,298.257222101]],PRIMEM["Greenwich",0.0],UNIT["Degree",0.0174532925199433]],
+        PROJECTION["Lambert_Conformal_Conic"],PARAMETER["False_Easting",2500000.0],PARAMETER["False_Northing",0.0],
+        PARAMETER["Central_Meridian",-102.0],PARAMETER["Standard_Parallel_1",17.5],
+        PARAMETER["Standard_Parallel_2",29.5],PARAMETER["Latitude_Of_Origin",12.0],UNIT["Meter",1.0]]"#;
+
+    #[test]
+    fn it_should_convert_projected_coordinates_to_geographic() {
+        let projection = Projection::from_wkt(MEXICO_LCC).unwrap().unwrap();
+
+        let (longitude, latitude) = projection.to_geographic(2800000.0, 1000000.0).unwrap();
+        assert!((longitude - -99.1018).abs() < 1e-4);
+        assert!((latitude - 20.9822).abs() < 1e-4);
+
+        let utm = r#"PROJCS["WGS 84 / UTM zone 14N",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,
+            298.257223563]]],PROJECTION["Transverse_Mercator"],PARAMETER["latitude_of_origin",0],
+            PARAMETER["central_meridian",-99],PARAMETER["scale_factor",0.9996],PARAMETER["false_easting",500000],
+            PARAMETER["false_northing",0],UNIT["metre",1]]"#;
+        let projection = Projection::from_wkt(utm).unwrap().unwrap();
+        let (longitude, latitude) = projection.to_geographic(500000.0, 0.0).unwrap();
+        assert!((longitude - -99.0).abs() < 1e-9);
+        assert!(latitude.abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_should_leave_geographic_coordinates() {
+        let wkt = r#"GEOGCS["GCS_WGS_1984",DATUM["D_WGS_1984",SPHEROID["WGS_1984",6378137.0,298.257223563]]]"#;
+        assert!(Projection::from_wkt(wkt).unwrap().is_none());
+    }
+
+    #[test]
+    fn it_should_reproject_every_position_of_a_geometry() {
+        let projection = Projection::from_wkt(MEXICO_LCC).unwrap().unwrap();
+        let ring = vec![vec![2800000.0, 1000000.0], vec![2800000.0, 1000000.0]];
+        let mut value = Value::MultiPolygon(vec![vec![ring.clone()], vec![ring]]);
+
+        projection.reproject(&mut value).unwrap();
+
+        match value {
+            Value::MultiPolygon(polygons) => {
+                for position in polygons.iter().flatten().flatten() {
+                    assert!((position[0] - -99.1018).abs() < 1e-4);
+                    assert!((position[1] - 20.9822).abs() < 1e-4);
+                }
+            }
+            _ => panic!("Wrong geometry"),
+        }
+    }
+
+    #[test]
+    fn it_should_fail_with_unsupported_projections() {
+        let wkt = r#"PROJCS["Polar",GEOGCS["WGS 84"],PROJECTION["Polar_Stereographic"],UNIT["metre",1]]"#;
+        match Projection::from_wkt(wkt) {
+            Err(PolygonFinderError::ProjectedCoordinates(name)) => assert_eq!(name, "Polar"),
+            _ => panic!("Wrong Error"),
+        }
+
+        let wkt2 = r#"PROJCRS["WGS 84 / UTM zone 14N",BASEGEOGCRS["WGS 84"]]"#;
+        match Projection::from_wkt(wkt2) {
+            Err(PolygonFinderError::ProjectedCoordinates(name)) => assert_eq!(name, "WGS 84 / UTM zone 14N"),
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use encoding_rs::Encoding;
+use geo_types::Coordinate;
+use geojson::{Feature, Geometry, PolygonType, Position, Value};
+
+use log::warn;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::polygon_finder::PolygonFinderError;
+use super::JsonObject;
+use super::prepared_area::crosses_ray;
+use super::projection::Projection;
+
+const FILE_CODE: i32 = 9994;
+const HEADER_SIZE: usize = 100;
+/// Sanity limit for the content of a record, in bytes.
+const MAX_RECORD_SIZE: usize = 1 << 30;
+
+const NULL_SHAPE: i32 = 0;
+const POINT_SHAPES: [i32; 3] = [1, 11, 21];
+const POLYGON_SHAPES: [i32; 3] = [5, 15, 25];
+
+/// Ends the field descriptors of the .dbf header.
+const DBF_HEADER_END: u8 = 0x0d;
+const DBF_DELETED: u8 = b'*';
+
+/**
+ * The files read with the .shp file which exist: its .dbf, and its .prj and .cpg when it has them.
+ */
+pub fn sidecar_paths(shp_path: &Path) -> Vec<PathBuf> {
+    ["dbf", "prj", "cpg"]
+        .iter()
+        .map(|extension| shp_path.with_extension(extension))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/**
+ * Reads the features of an ESRI shapefile, one record at a time. The geometries come from `shp_reader` (the
+ * .shp file), the properties from the .dbf file next to `shp_path`, decoded with the encoding of the .cpg file
+ * (or the language driver of the .dbf when there is none).
+ *
+ * Only points and polygons are supported. Projected coordinates (.prj) are converted to longitudes and latitudes.
+ * Deleted records and null shapes are skipped.
+ */
+pub fn read_features<R, F>(shp_path: &Path, shp_reader: R, on_feature: F) -> Result<(), PolygonFinderError>
+where
+    R: io::Read,
+    F: FnMut(Feature) -> Result<(), PolygonFinderError>,
+{
+    let mut on_feature = on_feature;
+    let projection = read_projection(shp_path)?;
+
+    let mut shp_reader = io::BufReader::new(shp_reader);
+    let shape_type = read_shp_header(&mut shp_reader)?;
+
+    let dbf_file = File::open(shp_path.with_extension("dbf"))
+        .map_err(|err| invalid(format!("cannot open the .dbf file: {}", err)))?;
+    let mut dbf = DbfReader::new(io::BufReader::new(dbf_file), shp_path)?;
+
+    let mut record = Vec::new();
+    let mut null_shapes = 0;
+    while let Some(geometry) = read_shape(&mut shp_reader, shape_type, projection.as_ref(), &mut record)? {
+        // Read even for the skipped shapes, to keep the .dbf in step with the .shp.
+        let properties = match dbf.read_record()? {
+            Some(DbfRecord::Deleted) => continue,
+            Some(DbfRecord::Properties(properties)) => properties,
+            None => return Err(invalid("the .dbf file has less records than the .shp file".to_owned())),
+        };
+        if geometry.is_none() {
+            null_shapes += 1;
+            continue;
+        }
+
+        on_feature(Feature {
+            bbox: None,
+            geometry,
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        })?;
+    }
+
+    if null_shapes > 0 {
+        warn!("Skipped {} records without a shape in {}", null_shapes, shp_path.display());
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> PolygonFinderError {
+    PolygonFinderError::InvalidShapefile(message)
+}
+
+/// For the reads of a record already in memory.
+fn truncated(_: io::Error) -> PolygonFinderError {
+    invalid("a .shp record is truncated".to_owned())
+}
+
+/**
+ * The projection of the .prj file, `None` for geographic coordinates: the index needs longitudes and latitudes.
+ */
+fn read_projection(shp_path: &Path) -> Result<Option<Projection>, PolygonFinderError> {
+    let prj = match fs::read(shp_path.with_extension("prj")) {
+        Ok(prj) => prj,
+        // Without a .prj, the coordinates are assumed geographic.
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(PolygonFinderError::Io(err)),
+    };
+
+    Projection::from_wkt(&String::from_utf8_lossy(&prj))
+}
+
+/**
+ * Validates the 100 bytes header of the .shp file. The shape type of every record.
+ */
+fn read_shp_header<R: io::Read>(reader: &mut R) -> Result<i32, PolygonFinderError> {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(|_| invalid("the .shp header is truncated".to_owned()))?;
+
+    let mut header = &header[..];
+    if header.read_i32::<BigEndian>().map_err(truncated)? != FILE_CODE {
+        return Err(invalid("not a .shp file".to_owned()));
+    }
+
+    let shape_type = (&header[28..32]).read_i32::<LittleEndian>().map_err(truncated)?;
+    match POINT_SHAPES.contains(&shape_type) || POLYGON_SHAPES.contains(&shape_type) || shape_type == NULL_SHAPE {
+        true => Ok(shape_type),
+        false => Err(invalid(format!("shape type {} is not supported, only points and polygons", shape_type))),
+    }
+}
+
+/**
+ * The geometry of the next record (`None` for a null shape), or `None` at the end of the file. `record` is
+ * the buffer for its content.
+ */
+fn read_shape<R: io::Read>(
+    reader: &mut R,
+    shape_type: i32,
+    projection: Option<&Projection>,
+    record: &mut Vec<u8>,
+) -> Result<Option<Option<Geometry>>, PolygonFinderError> {
+    let mut record_header = [0; 8];
+    match reader.read(&mut record_header[..1]).map_err(PolygonFinderError::Io)? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut record_header[1..]).map_err(truncated)?,
+    }
+
+    // In 16 bits words.
+    let content_length = (&record_header[4..]).read_i32::<BigEndian>().map_err(truncated)?;
+    if content_length < 0 || content_length as usize * 2 > MAX_RECORD_SIZE {
+        return Err(invalid(format!("a .shp record has an invalid length: {}", content_length)));
+    }
+    let content_length = content_length as usize * 2;
+    record.resize(content_length, 0);
+    reader.read_exact(record).map_err(|_| invalid("a .shp record is truncated".to_owned()))?;
+
+    let mut content = &record[..];
+    let record_shape_type = content.read_i32::<LittleEndian>().map_err(truncated)?;
+    if record_shape_type == NULL_SHAPE {
+        return Ok(Some(None));
+    }
+    if record_shape_type != shape_type {
+        return Err(invalid(format!("shape type {} in a file of shape type {}", record_shape_type, shape_type)));
+    }
+
+    let value = match POINT_SHAPES.contains(&shape_type) {
+        true => Value::Point(read_position(&mut content, projection)?),
+        false => read_polygon(&mut content, projection)?,
+    };
+    Ok(Some(Some(Geometry::new(value))))
+}
+
+fn read_position(content: &mut &[u8], projection: Option<&Projection>) -> Result<Position, PolygonFinderError> {
+    let x = content.read_f64::<LittleEndian>().map_err(truncated)?;
+    let y = content.read_f64::<LittleEndian>().map_err(truncated)?;
+    match projection {
+        Some(projection) => projection.to_geographic(x, y).map(|(longitude, latitude)| vec![longitude, latitude]),
+        None => Ok(vec![x, y]),
+    }
+}
+
+/**
+ * A polygon record: the box, the parts (rings) and the points. The measures and elevations after them are
+ * ignored.
+ */
+fn read_polygon(content: &mut &[u8], projection: Option<&Projection>) -> Result<Value, PolygonFinderError> {
+    *content = content.get(32..).ok_or_else(|| invalid("a polygon record is truncated".to_owned()))?;
+    let part_count = content.read_i32::<LittleEndian>().map_err(truncated)?.max(0) as usize;
+    let point_count = content.read_i32::<LittleEndian>().map_err(truncated)?.max(0) as usize;
+    if content.len() < part_count * 4 + point_count * 16 {
+        return Err(invalid("a polygon record is truncated".to_owned()));
+    }
+
+    let mut starts = Vec::with_capacity(part_count);
+    for _ in 0..part_count {
+        starts.push((content.read_i32::<LittleEndian>().map_err(truncated)?.max(0) as usize).min(point_count));
+    }
+    let mut points = Vec::with_capacity(point_count);
+    for _ in 0..point_count {
+        points.push(read_position(content, projection)?);
+    }
+
+    let mut rings = Vec::with_capacity(part_count);
+    for (part, &start) in starts.iter().enumerate() {
+        let end = starts.get(part + 1).cloned().unwrap_or(point_count).max(start);
+        rings.push(points[start..end].to_vec());
+    }
+
+    let mut polygons = assemble_polygons(rings);
+    match polygons.len() {
+        1 => Ok(Value::Polygon(polygons.remove(0))),
+        _ => Ok(Value::MultiPolygon(polygons)),
+    }
+}
+
+/**
+ * Twice the signed area of the ring: negative when it is clockwise.
+ */
+fn signed_area(ring: &[Position]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().skip(1))
+        .map(|(a, b)| a[0] * b[1] - b[0] * a[1])
+        .sum()
+}
+
+fn ring_contains(ring: &[Position], position: &Position) -> bool {
+    let point = Coordinate { x: position[0], y: position[1] };
+    let coordinate = |position: &Position| Coordinate { x: position[0], y: position[1] };
+
+    ring.iter()
+        .zip(ring.iter().skip(1))
+        .filter(|(a, b)| crosses_ray(coordinate(a), coordinate(b), &point))
+        .count()
+        % 2
+        == 1
+}
+
+/**
+ * Groups the rings in polygons. Outer rings are clockwise, and the holes (counterclockwise) belong to the
+ * smallest outer ring that contains them (islands in a lake are outer rings too). A hole outside of every
+ * outer ring is taken as an outer ring.
+ */
+fn assemble_polygons(rings: Vec<Vec<Position>>) -> Vec<PolygonType> {
+    let (outers, holes): (Vec<_>, Vec<_>) = rings
+        .into_iter()
+        .filter(|ring| !ring.is_empty())
+        .partition(|ring| signed_area(ring) <= 0.0);
+
+    let mut polygons: Vec<PolygonType> = outers.into_iter().map(|outer| vec![outer]).collect();
+    let outer_count = polygons.len();
+    for hole in holes {
+        let outer = (0..outer_count)
+            .filter(|&outer| ring_contains(&polygons[outer][0], &hole[0]))
+            .min_by(|&a, &b| {
+                let size = |outer: usize| signed_area(&polygons[outer][0]).abs();
+                size(a).partial_cmp(&size(b)).unwrap_or(std::cmp::Ordering::Equal)
+            });
+        match outer {
+            Some(outer) => polygons[outer].push(hole),
+            None => polygons.push(vec![hole]),
+        }
+    }
+    polygons
+}
+
+struct DbfField {
+    name: String,
+    field_type: u8,
+    length: usize,
+    decimals: u8,
+}
+
+enum DbfRecord {
+    Deleted,
+    Properties(JsonObject),
+}
+
+/**
+ * Reads the attributes of a .dbf file, one record at a time.
+ */
+struct DbfReader<R> {
+    reader: R,
+    fields: Vec<DbfField>,
+    encoding: &'static Encoding,
+    record: Vec<u8>,
+    remaining: u32,
+}
+
+impl<R: io::Read> DbfReader<R> {
+    fn new(mut reader: R, shp_path: &Path) -> Result<DbfReader<R>, PolygonFinderError> {
+        let truncated = || invalid("the .dbf header is truncated".to_owned());
+
+        let mut header = [0; 32];
+        reader.read_exact(&mut header).map_err(|_| truncated())?;
+        let record_count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let header_length = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let record_length = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let language_driver = header[29];
+
+        let mut descriptors = vec![0; header_length.saturating_sub(header.len())];
+        reader.read_exact(&mut descriptors).map_err(|_| truncated())?;
+
+        let encoding = match fs::read(shp_path.with_extension("cpg")) {
+            Ok(cpg) => {
+                let code_page = String::from_utf8_lossy(&cpg);
+                encoding_for_code_page(code_page.trim())
+                    .ok_or_else(|| invalid(format!("unknown encoding in the .cpg file: {}", code_page.trim())))?
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => encoding_for_language_driver(language_driver),
+            Err(err) => return Err(PolygonFinderError::Io(err)),
+        };
+
+        let mut fields = Vec::new();
+        for descriptor in descriptors.chunks(32).take_while(|descriptor| descriptor[0] != DBF_HEADER_END) {
+            if descriptor.len() < 32 {
+                return Err(truncated());
+            }
+            let name_length = descriptor[..11].iter().position(|&byte| byte == 0).unwrap_or(11);
+            fields.push(DbfField {
+                name: encoding.decode_without_bom_handling(&descriptor[..name_length]).0.trim().to_owned(),
+                field_type: descriptor[11].to_ascii_uppercase(),
+                length: descriptor[16] as usize,
+                decimals: descriptor[17],
+            });
+        }
+
+        if fields.iter().map(|field| field.length).sum::<usize>() + 1 > record_length {
+            return Err(invalid("the .dbf fields do not fit in its records".to_owned()));
+        }
+
+        Ok(DbfReader {
+            reader,
+            fields,
+            encoding,
+            record: vec![0; record_length],
+            remaining: record_count,
+        })
+    }
+
+    fn read_record(&mut self) -> Result<Option<DbfRecord>, PolygonFinderError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        self.reader
+            .read_exact(&mut self.record)
+            .map_err(|_| invalid("a .dbf record is truncated".to_owned()))?;
+
+        if self.record[0] == DBF_DELETED {
+            return Ok(Some(DbfRecord::Deleted));
+        }
+
+        let mut properties = JsonObject::new();
+        let mut offset = 1;
+        for field in &self.fields {
+            let raw = &self.record[offset..offset + field.length];
+            offset += field.length;
+            properties.insert(field.name.clone(), self.decode(field, raw));
+        }
+        Ok(Some(DbfRecord::Properties(properties)))
+    }
+
+    /**
+     * The JSON value of an attribute: numbers, booleans, dates as `YYYY-MM-DD` and text. Blank numbers,
+     * booleans and dates are null.
+     */
+    fn decode(&self, field: &DbfField, raw: &[u8]) -> serde_json::Value {
+        let text = self.encoding.decode_without_bom_handling(raw).0;
+        let trimmed = text.trim_matches([' ', '\0']);
+
+        match field.field_type {
+            b'N' | b'F' => {
+                let integer = match field.decimals {
+                    0 => trimmed.parse::<i64>().ok().map(serde_json::Value::from),
+                    _ => None,
+                };
+                integer
+                    .or_else(|| trimmed.parse::<f64>().ok().map(serde_json::Value::from))
+                    .unwrap_or(serde_json::Value::Null)
+            }
+            b'L' => match trimmed {
+                "Y" | "y" | "T" | "t" => serde_json::Value::Bool(true),
+                "N" | "n" | "F" | "f" => serde_json::Value::Bool(false),
+                _ => serde_json::Value::Null,
+            },
+            b'D' if trimmed.len() == 8 && trimmed.bytes().all(|byte| byte.is_ascii_digit()) => {
+                serde_json::Value::from(format!("{}-{}-{}", &trimmed[..4], &trimmed[4..6], &trimmed[6..]))
+            }
+            b'D' => serde_json::Value::Null,
+            _ => serde_json::Value::from(text.trim_end_matches([' ', '\0'])),
+        }
+    }
+}
+
+/**
+ * The encoding of a .cpg file: an encoding name (`UTF-8`, `ISO-8859-1`) or an ESRI code page (`1252`, `88591`).
+ */
+fn encoding_for_code_page(code_page: &str) -> Option<&'static Encoding> {
+    let label = match code_page.parse::<u32>() {
+        Ok(65001) => "utf-8".to_owned(),
+        Ok(874) | Ok(1250..=1258) => format!("windows-{}", code_page),
+        Ok(932) => "shift_jis".to_owned(),
+        Ok(936) => "gbk".to_owned(),
+        Ok(949) => "euc-kr".to_owned(),
+        Ok(950) => "big5".to_owned(),
+        Ok(20866) => "koi8-r".to_owned(),
+        Ok(28591..=28606) => format!("iso-8859-{}", code_page.parse::<u32>().unwrap() - 28590),
+        Ok(_) if code_page.starts_with("8859") => format!("iso-8859-{}", &code_page[4..]),
+        _ => code_page.to_owned(),
+    };
+    Encoding::for_label(label.as_bytes())
+}
+
+/**
+ * The encoding of the language driver id of a .dbf file without .cpg. Latin 1 for the unknown ones.
+ */
+fn encoding_for_language_driver(language_driver: u8) -> &'static Encoding {
+    match language_driver {
+        0x13 | 0x7b => encoding_rs::SHIFT_JIS,
+        0x4d | 0x7a => encoding_rs::GBK,
+        0x4e | 0x79 => encoding_rs::EUC_KR,
+        0x4f | 0x78 => encoding_rs::BIG5,
+        0x57..=0x59 => encoding_rs::WINDOWS_1252,
+        0xc8 => encoding_rs::WINDOWS_1250,
+        0xc9 => encoding_rs::WINDOWS_1251,
+        0xca => encoding_rs::WINDOWS_1254,
+        0xcb => encoding_rs::WINDOWS_1253,
+        _ => encoding_rs::WINDOWS_1252,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo_finder::test_utils::{assert_reprojected, MEXICO_LCC};
+    use byteorder::WriteBytesExt;
+
+    /// A shapefile in a temporary directory, removed when dropped.
+    struct TestShapefile {
+        dir: PathBuf,
+    }
+
+    impl TestShapefile {
+        /**
+         * Writes `test.shp` with the polygons (lists of rings, a null shape when empty) and `test.dbf` with a NAME (text), POP (number),
+         * AREA (number with decimals), CAPITAL (logical) and FOUNDED (date) fields.
+         */
+        fn new(name: &str, polygons: &[Vec<Vec<[f64; 2]>>], records: &[&[&[u8]; 5]], cpg: Option<&str>) -> Self {
+            let dir = std::env::temp_dir().join(format!("fsj-shapefile-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut shp = Vec::new();
+            for (number, polygon) in polygons.iter().enumerate() {
+                if polygon.is_empty() {
+                    shp.write_i32::<BigEndian>(number as i32 + 1).unwrap();
+                    shp.write_i32::<BigEndian>(2).unwrap();
+                    shp.write_i32::<LittleEndian>(NULL_SHAPE).unwrap();
+                    continue;
+                }
+                let points: Vec<_> = polygon.iter().flatten().collect();
+                let content_length = 44 + 4 * polygon.len() + 16 * points.len();
+                shp.write_i32::<BigEndian>(number as i32 + 1).unwrap();
+                shp.write_i32::<BigEndian>(content_length as i32 / 2).unwrap();
+                shp.write_i32::<LittleEndian>(5).unwrap();
+                shp.extend_from_slice(&[0; 32]);
+                shp.write_i32::<LittleEndian>(polygon.len() as i32).unwrap();
+                shp.write_i32::<LittleEndian>(points.len() as i32).unwrap();
+                let mut start = 0;
+                for ring in polygon {
+                    shp.write_i32::<LittleEndian>(start).unwrap();
+                    start += ring.len() as i32;
+                }
+                for point in points {
+                    shp.write_f64::<LittleEndian>(point[0]).unwrap();
+                    shp.write_f64::<LittleEndian>(point[1]).unwrap();
+                }
+            }
+            let mut header = Vec::new();
+            header.write_i32::<BigEndian>(FILE_CODE).unwrap();
+            header.extend_from_slice(&[0; 20]);
+            header.write_i32::<BigEndian>((HEADER_SIZE + shp.len()) as i32 / 2).unwrap();
+            header.write_i32::<LittleEndian>(1000).unwrap();
+            header.write_i32::<LittleEndian>(5).unwrap();
+            header.extend_from_slice(&[0; 64]);
+            fs::write(dir.join("test.shp"), [header, shp].concat()).unwrap();
+
+            let fields: [(&str, u8, u8, u8); 5] = [
+                ("NAME", b'C', 20, 0),
+                ("POP", b'N', 10, 0),
+                ("AREA", b'N', 10, 2),
+                ("CAPITAL", b'L', 1, 0),
+                ("FOUNDED", b'D', 8, 0),
+            ];
+            let record_length = 1 + fields.iter().map(|field| field.2 as usize).sum::<usize>();
+            let mut dbf = vec![3, 119, 1, 1];
+            dbf.write_u32::<LittleEndian>(records.len() as u32).unwrap();
+            dbf.write_u16::<LittleEndian>((32 + 32 * fields.len() + 1) as u16).unwrap();
+            dbf.write_u16::<LittleEndian>(record_length as u16).unwrap();
+            dbf.extend_from_slice(&[0; 20]);
+            for (field_name, field_type, length, decimals) in &fields {
+                let mut descriptor = [0; 32];
+                descriptor[..field_name.len()].copy_from_slice(field_name.as_bytes());
+                descriptor[11] = *field_type;
+                descriptor[16] = *length;
+                descriptor[17] = *decimals;
+                dbf.extend_from_slice(&descriptor);
+            }
+            dbf.push(DBF_HEADER_END);
+            for record in records {
+                let start = dbf.len();
+                dbf.push(b' ');
+                for (value, field) in record.iter().zip(&fields) {
+                    let mut raw = value.to_vec();
+                    raw.resize(field.2 as usize, b' ');
+                    dbf.extend_from_slice(&raw);
+                }
+                assert_eq!(dbf.len() - start, record_length);
+            }
+            fs::write(dir.join("test.dbf"), dbf).unwrap();
+
+            if let Some(cpg) = cpg {
+                fs::write(dir.join("test.cpg"), cpg).unwrap();
+            }
+            TestShapefile { dir }
+        }
+
+        fn shp_path(&self) -> PathBuf {
+            self.dir.join("test.shp")
+        }
+
+        fn read(&self) -> Result<Vec<Feature>, PolygonFinderError> {
+            let mut features = Vec::new();
+            let shp_file = File::open(self.shp_path()).map_err(PolygonFinderError::Io)?;
+            read_features(&self.shp_path(), shp_file, |feature| {
+                features.push(feature);
+                Ok(())
+            })?;
+            Ok(features)
+        }
+    }
+
+    impl Drop for TestShapefile {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn square(min: f64, max: f64, clockwise: bool) -> Vec<[f64; 2]> {
+        let mut ring = vec![[min, min], [min, max], [max, max], [max, min], [min, min]];
+        if !clockwise {
+            ring.reverse();
+        }
+        ring
+    }
+
+    #[test]
+    fn it_should_read_polygons_with_holes_and_parts() {
+        let shapefile = TestShapefile::new(
+            "parts",
+            &[
+                vec![square(0.0, 10.0, true), square(4.0, 6.0, false)],
+                vec![square(0.0, 1.0, true), square(20.0, 30.0, true), square(22.0, 24.0, false)],
+            ],
+            &[&[b"a", b"1", b"", b"", b""], &[b"b", b"2", b"", b"", b""]],
+            None,
+        );
+
+        let features = shapefile.read().unwrap();
+
+        assert_eq!(features.len(), 2);
+        match &features[0].geometry.as_ref().unwrap().value {
+            Value::Polygon(rings) => assert_eq!(rings.len(), 2),
+            _ => panic!("Wrong geometry"),
+        }
+        match &features[1].geometry.as_ref().unwrap().value {
+            Value::MultiPolygon(polygons) => {
+                assert_eq!(polygons.iter().map(|polygon| polygon.len()).collect::<Vec<_>>(), vec![1, 2]);
+            }
+            _ => panic!("Wrong geometry"),
+        }
+    }
+
+    #[test]
+    fn it_should_decode_the_attributes() {
+        let shapefile = TestShapefile::new(
+            "attributes",
+            &[vec![square(0.0, 1.0, true)], vec![square(1.0, 2.0, true)]],
+            &[
+                &[b"Quer\xe9taro", b"2368467", b"11699.17", b"T", b"15310725"],
+                &[b"", b"", b"*****", b"?", b"        "],
+            ],
+            Some("ISO-8859-1"),
+        );
+
+        let features = shapefile.read().unwrap();
+
+        let properties = features[0].properties.as_ref().unwrap();
+        assert_eq!(properties["NAME"], "Querétaro");
+        assert_eq!(properties["POP"], 2368467);
+        assert_eq!(properties["AREA"], 11699.17);
+        assert_eq!(properties["CAPITAL"], true);
+        assert_eq!(properties["FOUNDED"], "1531-07-25");
+
+        let properties = features[1].properties.as_ref().unwrap();
+        assert_eq!(properties["NAME"], "");
+        for name in &["POP", "AREA", "CAPITAL", "FOUNDED"] {
+            assert!(properties[*name].is_null());
+        }
+    }
+
+    #[test]
+    fn it_should_respect_the_code_page() {
+        let shapefile = TestShapefile::new(
+            "code-page",
+            &[vec![square(0.0, 1.0, true)]],
+            &[&["Querétaro".as_bytes(), b"1", b"", b"", b""]],
+            Some("UTF-8\n"),
+        );
+
+        let features = shapefile.read().unwrap();
+
+        assert_eq!(features[0].properties.as_ref().unwrap()["NAME"], "Querétaro");
+        assert_eq!(encoding_for_code_page("1252"), Some(encoding_rs::WINDOWS_1252));
+        assert_eq!(encoding_for_code_page("88591"), Encoding::for_label(b"iso-8859-1"));
+        assert_eq!(encoding_for_code_page("nope"), None);
+    }
+
+    #[test]
+    fn it_should_reproject_projected_coordinates() {
+        let shapefile = TestShapefile::new(
+            "projected",
+            &[vec![square(2800000.0, 2810000.0, true)]],
+            &[&[b"a", b"", b"", b"", b""]],
+            None,
+        );
+        fs::write(
+            shapefile.dir.join("test.prj"),
+            MEXICO_LCC,
+        )
+        .unwrap();
+
+        let features = shapefile.read().unwrap();
+
+        assert_reprojected(&features[0]);
+    }
+
+    #[test]
+    fn it_should_fail_with_unsupported_projections() {
+        let shapefile =
+            TestShapefile::new("unsupported", &[vec![square(0.0, 1.0, true)]], &[&[b"a", b"", b"", b"", b""]], None);
+        fs::write(
+            shapefile.dir.join("test.prj"),
+            r#"PROJCS["MEXICO_ITRF_2008_LCC",GEOGCS["GCS_ITRF_2008",DATUM["D_ITRF_2008"]]]"#,
+        )
+        .unwrap();
+
+        match shapefile.read() {
+            Err(PolygonFinderError::ProjectedCoordinates(name)) => assert_eq!(name, "MEXICO_ITRF_2008_LCC"),
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_skip_the_null_shapes_with_their_attributes() {
+        let shapefile = TestShapefile::new(
+            "null-shapes",
+            &[vec![], vec![square(0.0, 1.0, true)], vec![]],
+            &[&[b"a", b"", b"", b"", b""], &[b"b", b"", b"", b"", b""], &[b"c", b"", b"", b"", b""]],
+            None,
+        );
+
+        let features = shapefile.read().unwrap();
+
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].properties.as_ref().unwrap()["NAME"], "b");
+    }
+
+    #[test]
+    fn it_should_fail_with_an_invalid_record_length() {
+        let shapefile =
+            TestShapefile::new("record-length", &[vec![square(0.0, 1.0, true)]], &[&[b"a", b"", b"", b"", b""]], None);
+        let mut shp = fs::read(shapefile.shp_path()).unwrap();
+        shp[HEADER_SIZE + 4..HEADER_SIZE + 8].copy_from_slice(&(-2i32).to_be_bytes());
+        fs::write(shapefile.shp_path(), &shp).unwrap();
+
+        match shapefile.read() {
+            Err(PolygonFinderError::InvalidShapefile(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_list_the_sidecar_files_which_exist() {
+        let shapefile = TestShapefile::new("sidecars", &[vec![square(0.0, 1.0, true)]], &[&[b"A", b"", b"", b"", b""]], Some("UTF-8"));
+
+        assert_eq!(
+            sidecar_paths(&shapefile.shp_path()),
+            vec![shapefile.dir.join("test.dbf"), shapefile.dir.join("test.cpg")]
+        );
+    }
+
+    #[test]
+    fn it_should_fail_with_a_truncated_shapefile() {
+        let shapefile =
+            TestShapefile::new("truncated", &[vec![square(0.0, 1.0, true)]], &[&[b"a", b"", b"", b"", b""]], None);
+        let shp = fs::read(shapefile.shp_path()).unwrap();
+        fs::write(shapefile.shp_path(), &shp[..shp.len() - 10]).unwrap();
+
+        match shapefile.read() {
+            Err(PolygonFinderError::InvalidShapefile(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use geojson::{Feature, Value};
+
+/// Mexico ITRF2008 / LCC (EPSG:6372), the projection of the national statistics institute's layers.
+pub const MEXICO_LCC: &str = r#"PROJCS["Mexico ITRF2008 / LCC",GEOGCS["Mexico ITRF2008",DATUM["Mexico_ITRF2008",
+    SPHEROID["GRS 1980",6378137,298.257222101]],PRIMEM["Greenwich",0],UNIT["degree",0.0174532925199433]],
+    PROJECTION["Lambert_Conformal_Conic_2SP"],PARAMETER["standard_parallel_1",17.5],
+    PARAMETER["standard_parallel_2",29.5],PARAMETER["latitude_of_origin",12],PARAMETER["central_meridian",-102],
+    PARAMETER["false_easting",2500000],PARAMETER["false_northing",0],UNIT["metre",1]]"#;
+
+/**
+ * Asserts that the feature is the square from 2800000 to 2810000 in MEXICO_LCC, converted to longitudes and
+ * latitudes.
+ */
+pub fn assert_reprojected(feature: &Feature) {
+    match &feature.geometry.as_ref().unwrap().value {
+        Value::Polygon(rings) => {
+            assert_eq!(rings.len(), 1);
+            for position in &rings[0] {
+                assert!(position[0] > -98.8 && position[0] < -98.5, "{:?}", position);
+                assert!(position[1] > 37.1 && position[1] < 37.3, "{:?}", position);
+            }
+        }
+        _ => panic!("Wrong geometry"),
+    }
+}
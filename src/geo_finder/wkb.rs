@@ -0,0 +1,248 @@
+use geojson::{Position, Value};
+
+use std::convert::TryInto;
+
+use super::polygon_finder::PolygonFinderError;
+
+const POINT: u32 = 1;
+const LINE_STRING: u32 = 2;
+const POLYGON: u32 = 3;
+const MULTI_POINT: u32 = 4;
+const MULTI_LINE_STRING: u32 = 5;
+const MULTI_POLYGON: u32 = 6;
+const GEOMETRY_COLLECTION: u32 = 7;
+
+/// Flags of the PostGIS extended WKB (EWKB) geometry type.
+const EWKB_Z: u32 = 0x8000_0000;
+const EWKB_M: u32 = 0x4000_0000;
+const EWKB_SRID: u32 = 0x2000_0000;
+
+/// Sanity limit for the nesting of geometry collections, so a corrupted one does not overflow the stack.
+const MAX_COLLECTION_DEPTH: usize = 32;
+
+/**
+ * Reads a geometry in Well-Known Binary: ISO WKB (with Z and M coordinates, which are dropped) or the
+ * PostGIS extended WKB.
+ */
+pub fn read_geometry(bytes: &[u8]) -> Result<Value, PolygonFinderError> {
+    let mut reader = WkbReader { bytes, position: 0, little_endian: true, depth: 0 };
+    let value = reader.geometry()?;
+    match reader.position == bytes.len() {
+        true => Ok(value),
+        false => Err(invalid("trailing bytes after the geometry")),
+    }
+}
+
+fn invalid(message: &str) -> PolygonFinderError {
+    PolygonFinderError::InvalidWkb(message.to_owned())
+}
+
+struct WkbReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// Of the geometry being read: each one has its own byte order.
+    little_endian: bool,
+    /// The geometry collections the geometry being read is in.
+    depth: usize,
+}
+
+impl<'a> WkbReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], PolygonFinderError> {
+        let taken = self
+            .position
+            .checked_add(count)
+            .and_then(|end| self.bytes.get(self.position..end))
+            .ok_or_else(|| invalid("the geometry is truncated"))?;
+        self.position += count;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, PolygonFinderError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn f64(&mut self) -> Result<f64, PolygonFinderError> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        Ok(match self.little_endian {
+            true => f64::from_le_bytes(bytes),
+            false => f64::from_be_bytes(bytes),
+        })
+    }
+
+    /// A count, checked against the bytes left so a corrupted one does not allocate too much.
+    fn count(&mut self, min_item_size: usize) -> Result<usize, PolygonFinderError> {
+        let count = self.u32()? as usize;
+        match count.saturating_mul(min_item_size) <= self.bytes.len() - self.position {
+            true => Ok(count),
+            false => Err(invalid("the geometry is truncated")),
+        }
+    }
+
+    fn positions(&mut self, dimensions: usize) -> Result<Vec<Position>, PolygonFinderError> {
+        let count = self.count(dimensions * 8)?;
+        (0..count).map(|_| self.position_of(dimensions)).collect()
+    }
+
+    fn position_of(&mut self, dimensions: usize) -> Result<Position, PolygonFinderError> {
+        let x = self.f64()?;
+        let y = self.f64()?;
+        // Z and M are not indexed.
+        self.take((dimensions - 2) * 8)?;
+        Ok(vec![x, y])
+    }
+
+    fn rings(&mut self, dimensions: usize) -> Result<Vec<Vec<Position>>, PolygonFinderError> {
+        let count = self.count(4)?;
+        (0..count).map(|_| self.positions(dimensions)).collect()
+    }
+
+    /// The parts of a multi geometry, which must be of the given type.
+    fn parts<T, F>(&mut self, part_type: u32, read_part: F) -> Result<Vec<T>, PolygonFinderError>
+    where
+        F: Fn(&mut Self, usize) -> Result<T, PolygonFinderError>,
+    {
+        let count = self.count(5)?;
+        (0..count)
+            .map(|_| {
+                let (geometry_type, dimensions) = self.header()?;
+                match geometry_type == part_type {
+                    true => read_part(self, dimensions),
+                    false => Err(invalid("unexpected geometry type in a multi geometry")),
+                }
+            })
+            .collect()
+    }
+
+    /// The byte order and the type. The type without flags and the number of dimensions.
+    fn header(&mut self) -> Result<(u32, usize), PolygonFinderError> {
+        self.little_endian = match self.take(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid("unknown byte order")),
+        };
+
+        let geometry_type = self.u32()?;
+        let extended_dimensions = (geometry_type & EWKB_Z != 0) as usize + (geometry_type & EWKB_M != 0) as usize;
+        if geometry_type & EWKB_SRID != 0 {
+            self.u32()?;
+        }
+
+        let iso_type = geometry_type & 0x0fff_ffff;
+        let iso_dimensions = match iso_type / 1000 {
+            0 => 0,
+            1 | 2 => 1,
+            3 => 2,
+            _ => return Err(invalid("unknown geometry type")),
+        };
+        Ok((iso_type % 1000, 2 + extended_dimensions.max(iso_dimensions)))
+    }
+
+    fn geometry(&mut self) -> Result<Value, PolygonFinderError> {
+        let (geometry_type, dimensions) = self.header()?;
+        self.body(geometry_type, dimensions)
+    }
+
+    fn body(&mut self, geometry_type: u32, dimensions: usize) -> Result<Value, PolygonFinderError> {
+        Ok(match geometry_type {
+            POINT => Value::Point(self.position_of(dimensions)?),
+            LINE_STRING => Value::LineString(self.positions(dimensions)?),
+            POLYGON => Value::Polygon(self.rings(dimensions)?),
+            MULTI_POINT => Value::MultiPoint(self.parts(POINT, Self::position_of)?),
+            MULTI_LINE_STRING => Value::MultiLineString(self.parts(LINE_STRING, Self::positions)?),
+            MULTI_POLYGON => Value::MultiPolygon(self.parts(POLYGON, Self::rings)?),
+            GEOMETRY_COLLECTION => {
+                if self.depth == MAX_COLLECTION_DEPTH {
+                    return Err(invalid("too many nested geometry collections"));
+                }
+                let count = self.count(5)?;
+                self.depth += 1;
+                let geometries = (0..count)
+                    .map(|_| self.geometry().map(geojson::Geometry::new))
+                    .collect::<Result<_, _>>()?;
+                self.depth -= 1;
+                Value::GeometryCollection(geometries)
+            }
+            _ => return Err(invalid("unknown geometry type")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn it_should_read_a_point() {
+        // POINT (-99.13 19.43), little and big endian.
+        let little = hex("0101000000b81e85eb51c858c0ae47e17a146e3340");
+        let big = hex("0000000001c058c851eb851eb840336e147ae147ae");
+
+        assert_eq!(read_geometry(&little).unwrap(), Value::Point(vec![-99.13, 19.43]));
+        assert_eq!(read_geometry(&big).unwrap(), Value::Point(vec![-99.13, 19.43]));
+    }
+
+    #[test]
+    fn it_should_read_a_multi_polygon_dropping_z() {
+        // MULTIPOLYGON Z (((0 0 5, 1 0 5, 1 1 5, 0 0 5))) in ISO WKB.
+        let mut bytes = hex("01ee03000001000000");
+        bytes.extend(hex("01eb0300000100000004000000"));
+        for (x, y) in &[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 0.0)] {
+            for value in &[*x, *y, 5.0f64] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let ring = vec![vec![0.0, 0.0], vec![1.0, 0.0], vec![1.0, 1.0], vec![0.0, 0.0]];
+        assert_eq!(read_geometry(&bytes).unwrap(), Value::MultiPolygon(vec![vec![ring]]));
+    }
+
+    #[test]
+    fn it_should_read_an_extended_wkb_with_srid() {
+        // SRID=4326;POINT (-99.13 19.43)
+        let bytes = hex("0101000020e6100000b81e85eb51c858c0ae47e17a146e3340");
+
+        assert_eq!(read_geometry(&bytes).unwrap(), Value::Point(vec![-99.13, 19.43]));
+    }
+
+    #[test]
+    fn it_should_fail_with_a_truncated_geometry() {
+        let bytes = hex("01030000000100000004000000000000000000");
+
+        match read_geometry(&bytes) {
+            Err(PolygonFinderError::InvalidWkb(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_fail_with_too_many_nested_collections() {
+        // GEOMETRYCOLLECTION (GEOMETRYCOLLECTION (... POINT (-99.13 19.43)))
+        let nested = |depth: usize| {
+            let mut bytes = hex("010700000001000000").repeat(depth);
+            bytes.extend(hex("0101000000b81e85eb51c858c0ae47e17a146e3340"));
+            bytes
+        };
+
+        let mut value = read_geometry(&nested(MAX_COLLECTION_DEPTH)).unwrap();
+        for _ in 0..MAX_COLLECTION_DEPTH {
+            value = match value {
+                Value::GeometryCollection(mut geometries) => geometries.remove(0).value,
+                _ => panic!("Wrong geometry"),
+            };
+        }
+        assert_eq!(value, Value::Point(vec![-99.13, 19.43]));
+
+        match read_geometry(&nested(100_000)) {
+            Err(PolygonFinderError::InvalidWkb(message)) => assert_eq!(message, "too many nested geometry collections"),
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
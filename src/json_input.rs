@@ -0,0 +1,251 @@
+use super::file_processor::{
+    FileProcessorError, InputFormat, JoinOptions, JoinRow, JoinedValue, NestedValuePolicy, LATITUDE_NAMES,
+    LONGITUDE_NAMES,
+};
+use super::geo_finder;
+use super::geo_finder::{GeoJsonText, JsonObject};
+
+use std::borrow::Cow;
+use std::io;
+
+/**
+ * A JSON object of the input. The joined columns are added to the properties of the GeoJSON features, and to
+ * the object itself otherwise.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonRecord {
+    pub object: JsonObject,
+    pub is_feature: bool,
+}
+
+impl JsonRecord {
+    pub fn new(object: JsonObject) -> JsonRecord {
+        let is_feature = object.get("type").and_then(serde_json::Value::as_str) == Some("Feature");
+        JsonRecord { object, is_feature }
+    }
+
+    /**
+     * The (latitude, longitude) at the pointers, when they are numbers (or numeric strings). Without pointers,
+     * the coordinates of a Point feature or the members with a commonly used name.
+     */
+    pub fn coordinates(&self, latitude_pointer: Option<&str>, longitude_pointer: Option<&str>) -> (Option<f64>, Option<f64>) {
+        let coordinate = |pointer: Option<&str>, names: &[&str], position: usize| {
+            let value = match pointer {
+                Some(pointer) => self.pointer(pointer),
+                None if self.is_feature => self
+                    .object
+                    .get("geometry")
+                    .filter(|geometry| geometry["type"] == "Point")
+                    .and_then(|geometry| geometry["coordinates"].get(position)),
+                None => names.iter().find_map(|name| {
+                    self.object.iter().find(|(key, _)| key.trim().eq_ignore_ascii_case(name)).map(|(_, value)| value)
+                }),
+            };
+            as_number(value?)
+        };
+
+        (
+            coordinate(latitude_pointer, &LATITUDE_NAMES, 1),
+            coordinate(longitude_pointer, &LONGITUDE_NAMES, 0),
+        )
+    }
+
+    /// The number at the pointer, like the coordinates.
+    pub fn number(&self, pointer: &str) -> Option<f64> {
+        self.pointer(pointer).and_then(as_number)
+    }
+
+    /// A JSON pointer (`/location/lat`), or the same with dots (`location.lat`).
+    fn pointer(&self, pointer: &str) -> Option<&serde_json::Value> {
+        let tokens: Vec<Cow<str>> = match pointer.strip_prefix('/') {
+            Some(pointer) => pointer
+                .split('/')
+                .map(|token| match token.contains('~') {
+                    true => Cow::Owned(token.replace("~1", "/").replace("~0", "~")),
+                    false => Cow::Borrowed(token),
+                })
+                .collect(),
+            None => pointer.split('.').map(Cow::Borrowed).collect(),
+        };
+
+        let mut tokens = tokens.iter();
+        let mut value = self.object.get(tokens.next()?.as_ref())?;
+        for token in tokens {
+            value = match value {
+                serde_json::Value::Object(object) => object.get(token.as_ref())?,
+                serde_json::Value::Array(array) => array.get(token.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// Numbers, or numeric strings.
+fn as_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+impl JoinRow for JsonRecord {
+    fn push_joined(&mut self, column: &str, value: JoinedValue, options: &JoinOptions) {
+        let value = match value {
+            JoinedValue::Empty => serde_json::Value::Null,
+            JoinedValue::Text(text) => serde_json::Value::String(text.into_owned()),
+            JoinedValue::Property(geo_finder::PropertyValue::Json(_)) if options.nested_values == NestedValuePolicy::Empty => {
+                serde_json::Value::Null
+            }
+            JoinedValue::Property(value) => serde_json::Value::from(value),
+            // Like the text output.
+            JoinedValue::Distance(distance) => serde_json::Value::from((distance * 10.0).round() / 10.0),
+        };
+
+        let members = match self.is_feature {
+            true => {
+                let properties = self.object.entry("properties").or_insert(serde_json::Value::Null);
+                if !properties.is_object() {
+                    *properties = serde_json::Value::Object(JsonObject::new());
+                }
+                properties.as_object_mut().unwrap()
+            }
+            false => &mut self.object,
+        };
+        members.insert(column.to_owned(), value);
+    }
+}
+
+/**
+ * Reads the JSON objects of the input, in order, until `on_record` returns false or fails. The ones which can't
+ * be read are passed as errors, so they are counted.
+ *
+ * NDJSON is read one line at a time. GeoJSON can be a FeatureCollection, whose features are read one at a time so
+ * the whole document is never in memory, a Feature, or a sequence of them (one per line or RFC 8142).
+ */
+pub fn read_records<R, F>(reader: R, format: InputFormat, on_record: F) -> Result<(), FileProcessorError>
+where
+    R: io::BufRead,
+    F: FnMut(Result<JsonRecord, String>) -> Result<bool, FileProcessorError>,
+{
+    let mut reader = reader;
+    let mut on_record = on_record;
+
+    if format == InputFormat::NdJson {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).map_err(FileProcessorError::Io)? == 0 {
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = match serde_json::from_str(&line) {
+                Ok(serde_json::Value::Object(object)) => Ok(JsonRecord::new(object)),
+                Ok(_) => Err("not a JSON object".to_owned()),
+                Err(err) => Err(err.to_string()),
+            };
+            if !on_record(record)? {
+                return Ok(());
+            }
+        }
+    }
+
+    geo_finder::read_geojson_texts(reader, |text| {
+        let record = match text {
+            GeoJsonText::Feature(serde_json::Value::Object(object)) | GeoJsonText::Object(object) => {
+                Ok(JsonRecord::new(object))
+            }
+            GeoJsonText::Feature(_) => Err("not a JSON object".to_owned()),
+        };
+        on_record(record)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &str, format: InputFormat) -> Result<Vec<Result<JsonRecord, String>>, FileProcessorError> {
+        let mut records = Vec::new();
+        read_records(input.as_bytes(), format, |record| {
+            records.push(record);
+            Ok(true)
+        })?;
+        Ok(records)
+    }
+
+    fn record(json: &str) -> JsonRecord {
+        match serde_json::from_str(json).unwrap() {
+            serde_json::Value::Object(object) => JsonRecord::new(object),
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn it_should_read_ndjson_skipping_invalid_lines() {
+        let input = "{\"id\": 1, \"lat\": 19.4}\n\n{\"id\": 2\n[1, 2]\n{\"id\": 3}\n";
+
+        let records = read(input, InputFormat::NdJson).unwrap();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].as_ref().unwrap().object["id"], 1);
+        assert!(records[1].is_err());
+        assert_eq!(records[2], Err("not a JSON object".to_owned()));
+        assert_eq!(records[3].as_ref().unwrap().object["id"], 3);
+    }
+
+    #[test]
+    fn it_should_stream_the_features_of_a_collection_and_a_sequence() {
+        let collection = r#"{"features": [{"type": "Feature", "properties": {"id": 1}}, {"type": "Feature"}],
+                             "type": "FeatureCollection"}"#;
+        let sequence = "\x1e{\"type\": \"Feature\", \"properties\": {\"id\": 1}}\n\x1e{\"type\": \"Feature\"}\n";
+
+        for input in &[collection, sequence] {
+            let records = read(input, InputFormat::GeoJson).unwrap();
+
+            assert_eq!(records.len(), 2);
+            assert!(records.iter().all(|record| record.as_ref().unwrap().is_feature));
+            assert_eq!(records[0].as_ref().unwrap().object["properties"]["id"], 1);
+        }
+    }
+
+    #[test]
+    fn it_should_stop_when_asked() {
+        let input = r#"{"type": "FeatureCollection", "features": [{"type": "Feature"}, {"type": "Feature"}]}"#;
+        let mut count = 0;
+
+        let result = read_records(input.as_bytes(), InputFormat::GeoJson, |_| {
+            count += 1;
+            Ok(false)
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn it_should_fail_with_invalid_geojson() {
+        match read(r#"{"type": "FeatureCollection", "features": [{"type": "Feature"},"#, InputFormat::GeoJson) {
+            Err(FileProcessorError::Json(_)) => {}
+            _ => panic!("Wrong Error"),
+        }
+    }
+
+    #[test]
+    fn it_should_find_the_coordinates() {
+        let nested = record(r#"{"location": {"lat": "19.43", "lon": -99.13}, "lat": 1}"#);
+        let named = record(r#"{"Latitude": 19.43, "lng": -99.13}"#);
+        let point = record(r#"{"type": "Feature", "geometry": {"type": "Point", "coordinates": [-99.13, 19.43]}}"#);
+        let line = record(r#"{"type": "Feature", "geometry": {"type": "LineString", "coordinates": [[0, 1], [1, 1]]}}"#);
+
+        assert_eq!(nested.coordinates(Some("/location/lat"), Some("location.lon")), (Some(19.43), Some(-99.13)));
+        assert_eq!(named.coordinates(None, None), (Some(19.43), Some(-99.13)));
+        assert_eq!(point.coordinates(None, None), (Some(19.43), Some(-99.13)));
+        assert_eq!(line.coordinates(None, None), (None, None));
+    }
+}
@@ -2,59 +2,82 @@
 extern crate clap;
 use clap::{App, Arg, SubCommand};
 
-#[macro_use]
-extern crate failure;
 use failure::Error;
 
-use bincode;
 use log::{error, info, warn};
-use simplelog;
+use std::collections::HashMap;
 use std::io;
+use std::io::Write;
 use std::path;
+use std::time;
 
+mod aggregator;
 mod cli_utils;
+mod compression;
 mod file_processor;
 mod geo_finder;
+mod json_input;
+mod output_writer;
+mod parquet_input;
+mod partition_writer;
 
 use chrono::offset::Local;
 
-#[derive(Debug, Fail)]
-pub enum MainError {
-    #[fail(display = "Index Deserialization Error")]
-    IndexDeserializationError,
-}
-
-fn save_finder(finder: &geo_finder::PolygonFinder, output_file: &path::Path) {
-    let file_writer = std::fs::File::create(output_file).unwrap();
-    let buf_writer = std::io::BufWriter::new(file_writer);
-    bincode::serialize_into(buf_writer, &finder).unwrap();
-}
-
-
-
-fn load_polygons_finder(input_path: &path::Path) -> geo_finder::PolygonFinder {
-    // TODO: error handling.
+fn load_polygons_finder(input_path: &path::Path) -> Result<Box<dyn geo_finder::GeoFinder>, Error> {
     let progress_bar = cli_utils::create_progress_bar_count(false, "Loading index...", None);
     progress_bar.enable_steady_tick(200);
 
-    let file_reader = std::fs::File::open(input_path).unwrap();
-    let buf_reader = std::io::BufReader::new(file_reader);
-    let result = bincode::deserialize_from(buf_reader).unwrap();
-
+    let load_result = geo_finder::open_index(input_path, false);
     progress_bar.finish();
-    result
+
+    let (metadata, finder) = load_result?;
+    info!(
+        "{} index with {} features, built on {} by version {}",
+        finder.layout().as_str(),
+        metadata.feature_count,
+        metadata.build_time,
+        metadata.generator_version
+    );
+    Ok(finder)
 }
 
 
-fn create_polygons_geo_index<P: AsRef<path::Path>>(
-    dest_path: P,
-    geojson_path: P,
+/**
+ * The files matching each pattern (sorted), or the path itself when it is not a pattern. Without duplicates.
+ */
+fn expand_paths<'a, I: Iterator<Item = &'a str>>(patterns: I) -> Result<Vec<path::PathBuf>, Error> {
+    let mut paths: Vec<path::PathBuf> = Vec::new();
+    for pattern in patterns {
+        let mut pattern_paths = match pattern.contains(&['*', '?', '['][..]) {
+            true => glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?,
+            false => vec![path::PathBuf::from(pattern)],
+        };
+        if pattern_paths.is_empty() {
+            return Err(failure::format_err!("No file matches '{}'", pattern));
+        }
+        pattern_paths.sort();
+
+        for pattern_path in pattern_paths {
+            if !paths.contains(&pattern_path) {
+                paths.push(pattern_path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+fn create_polygons_geo_index(
+    dest_path: &path::Path,
+    geojson_paths: &[path::PathBuf],
+    format: &geo_finder::SourceFormat,
+    layout: geo_finder::IndexLayout,
+    tag_source: bool,
     force: bool,
 ) -> Result<(), Error> {
-    info!("Generating index from geojson {:?} ...", geojson_path.as_ref());
+    info!("Generating index from {} files: {:?} ...", geojson_paths.len(), geojson_paths);
 
-    let mut dest_file_buffer = dest_path.as_ref().to_path_buf();
-    if dest_path.as_ref().is_dir() {
+    let mut dest_file_buffer = dest_path.to_path_buf();
+    if dest_path.is_dir() {
         dest_file_buffer.set_file_name("geo.idx.bin");
     }
     let dest_file: &path::Path = dest_file_buffer.as_path();
@@ -69,22 +92,202 @@ fn create_polygons_geo_index<P: AsRef<path::Path>>(
 
     info!("Generating index into {} ...", dest_file.display());
 
-    let finder_result = geo_finder::PolygonFinder::new(geojson_path);
+    let finder = geo_finder::PolygonFinder::new_from_files(geojson_paths, format, tag_source)?;
 
-    match finder_result {
-        Ok(finder) => {
-            info!("Saving index information into {}", dest_file.display());
-            save_finder(&finder, dest_file);
-        }
-        Err(error) => {
-            error!("Error creating geo index: {}", error);
+    // Every file read, so the sidecar files of the shapefiles are checked too.
+    let sources = geojson_paths
+        .iter()
+        .flat_map(|path| format.files_read(path))
+        .map(geo_finder::IndexSource::from_file)
+        .collect::<Result<Vec<_>, _>>()?;
+    let metadata = geo_finder::IndexMetadata::new(&finder, sources);
+    info!("Saving index information into {}", dest_file.display());
+    geo_finder::save_index(&finder, &metadata, layout, dest_file)?;
+
+    Ok(())
+}
+
+
+
+#[derive(serde::Serialize)]
+struct IndexDescription<'a> {
+    path: String,
+    disk_size: u64,
+    layout: geo_finder::IndexLayout,
+    format_version: u32,
+    metadata: &'a geo_finder::IndexMetadata,
+    stats: &'a geo_finder::IndexStats,
+}
+
+/// Where `run --report` writes the summary, and how many features it lists.
+struct ReportOptions<'a> {
+    path: &'a path::Path,
+    top_features: usize,
+}
+
+/// Summary of a join, written with `run --report`.
+#[derive(serde::Serialize)]
+struct RunReport {
+    index: String,
+    input_bytes: Option<u64>,
+    rows: RowCounts,
+    /// [min_x, min_y, max_x, max_y] of the input points.
+    bbox: Option<[f64; 4]>,
+    /// The features with the most points, with their joined properties.
+    top_features: Vec<FeatureHits>,
+    timings: Timings,
+    rows_per_second: f64,
+}
+
+#[derive(serde::Serialize)]
+struct RowCounts {
+    read: u32,
+    matched: u32,
+    not_found: u32,
+    invalid_coordinates: u32,
+    missing_property: u32,
+    parse_errors: u32,
+    /// Not joined, for any of the reasons above.
+    errors: u32,
+}
+
+#[derive(serde::Serialize)]
+struct FeatureHits {
+    feature: usize,
+    points: u64,
+    properties: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(serde::Serialize)]
+struct Timings {
+    index_load_seconds: f64,
+    join_seconds: f64,
+    total_seconds: f64,
+}
+
+fn write_run_report(
+    report_options: &ReportOptions,
+    index_file_path: &path::Path,
+    geo_index: &dyn geo_finder::GeoFinder,
+    options: &file_processor::JoinOptions,
+    stats: &file_processor::ProcessStats,
+    file_size: Option<u64>,
+    timings: Timings,
+) -> Result<(), Error> {
+    let top = stats.top_features(report_options.top_features);
+    let mut properties: HashMap<usize, serde_json::Map<String, serde_json::Value>> = HashMap::new();
+    if !top.is_empty() {
+        for feature in geo_index.indexed_features() {
+            let feature = feature?;
+            if top.iter().any(|(id, _)| *id == feature.id) {
+                let values = options
+                    .properties
+                    .iter()
+                    .filter_map(|property| Some((property.to_string(), serde_json::Value::from(feature.props.get(*property)?))));
+                properties.insert(feature.id, values.collect());
+            }
         }
+    }
+
+    let report = RunReport {
+        index: index_file_path.display().to_string(),
+        input_bytes: file_size,
+        rows: RowCounts {
+            read: stats.total_lines,
+            matched: stats.matched_lines,
+            not_found: stats.not_found_lines,
+            invalid_coordinates: stats.invalid_coordinates_lines,
+            missing_property: stats.missing_property_lines,
+            parse_errors: stats.parse_error_lines,
+            errors: stats.error_lines,
+        },
+        bbox: stats.bbox,
+        top_features: top
+            .into_iter()
+            .map(|(feature, points)| FeatureHits {
+                feature,
+                points,
+                properties: properties.remove(&feature).unwrap_or_default(),
+            })
+            .collect(),
+        rows_per_second: match timings.join_seconds > 0.0 {
+            true => stats.total_lines as f64 / timings.join_seconds,
+            false => 0.0,
+        },
+        timings,
     };
 
+    info!("Writing the report to {}.", report_options.path.display());
+    let mut report_file = io::BufWriter::new(std::fs::File::create(report_options.path)?);
+    serde_json::to_writer_pretty(&mut report_file, &report)?;
+    report_file.flush()?;
     Ok(())
 }
 
+fn inspect_index(index_path: &path::Path, max_samples: usize, json: bool) -> Result<(), Error> {
+    let disk_size = std::fs::metadata(index_path)?.len();
+    let (metadata, finder) = geo_finder::open_index(index_path, true)?;
+    let layout = finder.layout();
+    let stats = finder.stats(max_samples)?;
+
+    if json {
+        let description = IndexDescription {
+            path: index_path.display().to_string(),
+            disk_size,
+            layout,
+            format_version: layout.format_version(),
+            metadata: &metadata,
+            stats: &stats,
+        };
+        println!("{}", serde_json::to_string_pretty(&description)?);
+        return Ok(());
+    }
+
+    println!("Index: {}", index_path.display());
+    println!(
+        "Format version {} ({} layout), built on {} by version {}",
+        layout.format_version(),
+        layout.as_str(),
+        metadata.build_time,
+        metadata.generator_version
+    );
+    for source in &metadata.sources {
+        println!("Source: {} (CRC32 {:08x})", source.path, source.crc32);
+    }
+    println!(
+        "Features: {} (Polygon: {}, MultiPolygon: {}, Point: {}). {} vertices, {} prepared geometries",
+        stats.feature_count, stats.polygons, stats.multi_polygons, stats.points, stats.vertices, stats.prepared
+    );
+    match stats.bbox {
+        Some([min_x, min_y, max_x, max_y]) => println!("Bounding box: ({}, {}) - ({}, {})", min_x, min_y, max_x, max_y),
+        None => println!("Bounding box: none"),
+    }
+    println!("R-tree depth: {}", stats.tree_depth);
+    match layout {
+        geo_finder::IndexLayout::Bincode => println!(
+            "Size: {} on disk, ~{} in memory",
+            indicatif::HumanBytes(disk_size),
+            indicatif::HumanBytes(stats.memory_size as u64)
+        ),
+        geo_finder::IndexLayout::Mapped => {
+            println!("Size: {} on disk, memory mapped (shared between processes)", indicatif::HumanBytes(disk_size))
+        }
+    }
+    println!("Properties:");
+    for property in &stats.properties {
+        println!(
+            "  {} ({}): {}{} distinct values, missing in {} features. Samples: {}",
+            property.name,
+            property.types.join(", "),
+            if property.cardinality_capped { ">" } else { "" },
+            property.cardinality,
+            property.missing,
+            property.samples.join(" | ")
+        );
+    }
 
+    Ok(())
+}
 
 fn main() {
     let local_time = Local::now();
@@ -93,7 +296,7 @@ fn main() {
     simplelog::TermLogger::init(
         simplelog::LevelFilter::Info,
         simplelog::Config {
-            offset: time_offset.clone(),
+            offset: *time_offset,
             ..simplelog::Config::default()
         },
         simplelog::TerminalMode::Stderr,
@@ -109,127 +312,110 @@ fn main() {
     };
 }
 
-// fn run_states_clasiffier(
-//     index_directory: &path::Path,
-//     input_file: &mut io::Read,
-//     file_size: Option<u64>,
-//     output_dir: &path::Path,
-//     char_delimiter: u8,
-//     latitude_idx: usize,
-//     longitude_idx: usize,
-// ) -> Result<(), Error> {
-//     let dest_dir_path_buf = index_directory.join("states.idx.bin");
-//     let index_file_path = dest_dir_path_buf.as_path();
-
-//     info!("Loading index from {}.", index_file_path.display());
-//     let geo_index: geo_finder::PolygonFinder = load_finder_polygon(index_file_path);
-//     info!("Index from {} loaded.", index_file_path.display());
-
-//     fs::create_dir_all(output_dir)?;
-
-//     let process_result = file_processor::states_classifier_run(
-//         geo_index,
-//         input_file,
-//         file_size,
-//         output_dir,
-//         char_delimiter,
-//         latitude_idx,
-//         longitude_idx,
-//     );
-
-//     match process_result {
-//         Ok(stats) => {
-//             info!("Stats: {:?}", stats);
-//             return Ok(());
-//         }
-//         Err(err) => return Err(Error::from(err)),
-//     }
-// }
-
 fn run_polygons_classifier(
     index_file_path: &path::Path,
-    input_file: &mut io::Read,
+    input: file_processor::JoinInput,
     file_size: Option<u64>,
-    output_file: &mut io::Write,
-    char_delimiter: u8,
-    latitude_idx: usize,
-    longitude_idx: usize,
-    properties: Vec<&str>,
-    no_header: bool,
+    mut output_file: compression::CompressedWriter<Box<dyn io::Write + Send>>,
+    mut rejects_file: Option<compression::CompressedWriter<std::fs::File>>,
+    options: &file_processor::JoinOptions,
+    report_options: Option<&ReportOptions>,
 ) -> Result<(), Error> {
+    let start_instant = time::Instant::now();
     info!("Loading index from '{}'.", index_file_path.display());
-    let geo_index = load_polygons_finder(&index_file_path);
+    let geo_index = load_polygons_finder(index_file_path)?;
     info!("Index from '{}' loaded.", index_file_path.display());
+    let index_load_seconds = start_instant.elapsed().as_secs_f64();
+
+    for (property, count) in geo_index.missing_properties(&options.properties) {
+        warn!(
+            "Property '{}' is missing in {} of {} features",
+            property,
+            count,
+            geo_index.feature_count()
+        );
+    }
 
     let process_result = file_processor::spatial_polygons_join(
-        geo_index,
-        input_file,
+        geo_index.as_ref(),
+        input,
         file_size,
-        output_file,
-        char_delimiter,
-        latitude_idx,
-        longitude_idx,
-        properties,
-        no_header,
+        &mut output_file,
+        rejects_file.as_mut().map(|file| file as &mut (dyn io::Write + Send)),
+        options,
     );
 
     match process_result {
         Ok(stats) => {
-            info!("Stats: {:?}", stats);
-            return Ok(());
+            // Before the report, which is only written when the whole output was.
+            if let Some(rejects_file) = rejects_file {
+                rejects_file.finish()?;
+            }
+            // A closed pipe already stopped the join.
+            match output_file.finish() {
+                Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(Error::from(err)),
+                _ => {}
+            }
+
+            info!(
+                "Stats: {} rows, {} matched, {} not found, {} with invalid coordinates, {} missing a property, {} unreadable",
+                stats.total_lines,
+                stats.matched_lines,
+                stats.not_found_lines,
+                stats.invalid_coordinates_lines,
+                stats.missing_property_lines,
+                stats.parse_error_lines
+            );
+            if let Some(report_options) = report_options {
+                let timings = Timings {
+                    index_load_seconds,
+                    join_seconds: stats.elapsed_secs,
+                    total_seconds: start_instant.elapsed().as_secs_f64(),
+                };
+                write_run_report(report_options, index_file_path, geo_index.as_ref(), options, &stats, file_size, timings)?;
+            }
+            Ok(())
         }
-        Err(err) => return Err(Error::from(err)),
+        Err(err) => Err(Error::from(err)),
     }
 }
 
-// fn run_state(
-//     state_number: i32,
-//     index_directory: &path::Path,
-//     input_file: &mut io::Read,
-//     file_size: Option<u64>,
-//     output_dir: &path::Path,
-//     char_delimiter: u8,
-//     latitude_idx: usize,
-//     longitude_idx: usize,
-//     no_header: bool,
-// ) -> Result<(), Error> {
-//     let state_code = format!("{:02}", state_number);
-
-//     let index_file_path_buf = get_state_index_path(&index_directory, &state_code);
-//     let index_file_path = index_file_path_buf.as_path();
-
-//     info!("Loading index from {}.", index_file_path.display());
-//     let geo_index = load_finder_by_state(&index_file_path)?;
-//     info!("Index from {} loaded.", index_file_path.display());
-
-//     fs::create_dir_all(output_dir)?;
-
-//     let output_file_path = output_dir.join(format!("{}.output.txt", state_code));
-
-//     info!("Writing to file {}.", output_file_path.display());
-//     let mut output_file: Box<io::Write> = Box::new(std::fs::File::create(output_file_path)?);
-
-//     let process_result = file_processor::cvegeo_classifier_run(
-//         geo_index,
-//         input_file,
-//         file_size,
-//         &mut output_file,
-//         char_delimiter,
-//         latitude_idx,
-//         longitude_idx,
-//         vec!["CVEGEO"],
-//         vec!["polygon_type"],
-//         no_header,
-//     );
-
-//     match process_result {
-//         Ok(stats) => {
-//             info!("Stats: {:?}", stats);
-//             return Ok(());
-//         }
-//         Err(err) => return Err(Error::from(err)),
-//     }
-// }
+/**
+ * Column given by number (1 based), by header name or, if none, detected from the header.
+ */
+fn column_selector<'a>(
+    matches: &'a clap::ArgMatches,
+    index_arg: &str,
+    name_arg: &str,
+) -> file_processor::ColumnSelector<'a> {
+    if matches.is_present(index_arg) {
+        let column_number = value_t!(matches, index_arg, usize).unwrap_or_else(|e| e.exit());
+        if column_number == 0 {
+            clap::Error::value_validation_auto(format!("--{} is 1 based", index_arg)).exit();
+        }
+        return file_processor::ColumnSelector::Index(column_number - 1);
+    }
+
+    match matches.value_of(name_arg) {
+        Some(name) => file_processor::ColumnSelector::Name(name),
+        None => file_processor::ColumnSelector::Auto,
+    }
+}
+
+/**
+ * The delimiter of the CSV fields: a single byte character, `\t` for tabs.
+ */
+fn delimiter(matches: &clap::ArgMatches, arg: &str) -> u8 {
+    let delimiter = matches.value_of(arg).unwrap_or_default().replace("\\t", "\t");
+    match delimiter.as_bytes() {
+        [delimiter] => *delimiter,
+        _ => clap::Error::value_validation_auto(format!(
+            "--{} must be a single byte character, not '{}'",
+            arg, delimiter
+        ))
+        .exit(),
+    }
+}
 
 fn do_main() -> Result<(), Error> {
     let matches = App::new("locate_points")
@@ -253,9 +439,65 @@ fn do_main() -> Result<(), Error> {
                             )
                             .arg(Arg::with_name("geojson")
                                 .short("g")
-                                .required(true)
-                                .help("Path for the geojson file")
+                                .required_unless("csv")
+                                .conflicts_with("csv")
+                                .help("Paths or glob patterns ('states/*.json') of the geojson files (a FeatureCollection, a Feature or a Geometry, or newline-delimited ones: GeoJSONSeq) shapefiles (the .shp file, next to its .dbf, .prj and .cpg), FlatGeobuf files (.fgb) or GeoPackages (.gpkg). The features of every file are merged in one index.")
+                                .takes_value(true)
+                                .multiple(true)
+                            )
+                            .arg(Arg::with_name("csv")
+                                .long("csv")
+                                .help("Paths or glob patterns of CSV files with a header and a geometry column, as WKT or hex encoded WKB. The other columns are the properties.")
+                                .takes_value(true)
+                                .multiple(true)
+                            )
+                            .arg(Arg::with_name("geometry-col")
+                                .long("geometry-col")
+                                .help("Name of the geometry column of the CSV files")
+                                .takes_value(true)
+                                .default_value("wkt")
+                            )
+                            .arg(Arg::with_name("delimiter")
+                                .short("d")
+                                .long("delimiter")
+                                .help("Delimiter for the fields of the CSV files")
+                                .takes_value(true)
+                                .default_value(",")
+                            )
+                            .arg(Arg::with_name("layer")
+                                .long("layer")
+                                .help("Layer to index from the GeoPackages. Not needed when they have only one")
+                                .takes_value(true)
+                            )
+                            .arg(Arg::with_name("tag-source")
+                                .long("tag-source")
+                                .help("Add a '_source' property to each feature, with the name of its file")
+                            )
+                            .arg(Arg::with_name("layout")
+                                .long("layout")
+                                .help("How the index is stored: compact (bincode) or memory mapped, which loads instantly and is shared between concurrent processes")
+                                .takes_value(true)
+                                .possible_values(&geo_finder::IndexLayout::VALUES)
+                                .default_value("bincode")
+                            )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("inspect")
+                            .about("Describe the contents of an index file")
+                            .arg(Arg::with_name("index")
+                                .help("Index file to describe")
+                                .default_value("geo.idx.bin")
+                                .index(1)
+                            )
+                            .arg(Arg::with_name("json")
+                                .long("json")
+                                .help("Print the description as JSON")
+                            )
+                            .arg(Arg::with_name("samples")
+                                .long("samples")
+                                .help("Sample values shown for each property")
                                 .takes_value(true)
+                                .default_value("5")
                             )
                     )
     
@@ -265,10 +507,16 @@ fn do_main() -> Result<(), Error> {
                             .arg(Arg::with_name("output")
                                     .short("o")
                                     .long("output")
-                                    .help("Sets the output file to create.")
+                                    .help("Sets the output file to create. Compressed when its extension is .gz, .zst, .bz2 or .xz.")
                                     .takes_value(true)
                                     .required(false)
                             )
+                            .arg(Arg::with_name("compress")
+                                .long("compress")
+                                .help("Compresses the output, also to stdout. By default, as told by the extension of the output file.")
+                                .takes_value(true)
+                                .possible_values(&compression::Compression::VALUES)
+                            )
                             .arg(Arg::with_name("index")
                                 .short("x")
                                 .long("index")
@@ -280,8 +528,27 @@ fn do_main() -> Result<(), Error> {
                             .arg(Arg::with_name("input")
                                 .short("i")
                                 .long("input")
-                                .help("Sets the input file to use (must have 'latitude' and 'longitude' fields). If omitted, stdin will be used.")
+                                .help("Sets the input file to use (must have 'latitude' and 'longitude' fields). If omitted, stdin will be used (not for Parquet). Can be compressed with gzip, zstd, bzip2 or xz.")
+                                .takes_value(true)
+                            )
+                            .arg(Arg::with_name("input-format")
+                                .long("input-format")
+                                .help("How the input is read: delimited text, a JSON object per line or GeoJSON features (a FeatureCollection or one feature per line), or Apache Parquet, keeping the type of every column. The JSON records are written back with the joined columns, in the properties of the features.")
+                                .takes_value(true)
+                                .possible_values(&file_processor::InputFormat::VALUES)
+                                .default_value("csv")
+                            )
+                            .arg(Arg::with_name("latitude-pointer")
+                                .long("latitude-pointer")
+                                .help("JSON pointer to the latitude of the JSON records ('/location/lat' or 'location.lat'). By default, the coordinates of Point features or a member named like 'lat', 'latitude' or 'y'.")
+                                .takes_value(true)
+                                .conflicts_with_all(&["latitude", "latitude-col"])
+                            )
+                            .arg(Arg::with_name("longitude-pointer")
+                                .long("longitude-pointer")
+                                .help("JSON pointer to the longitude of the JSON records ('/location/lon' or 'location.lon'). By default, the coordinates of Point features or a member named like 'lon', 'lng', 'long', 'longitude' or 'x'.")
                                 .takes_value(true)
+                                .conflicts_with_all(&["longitude", "longitude-col"])
                             )
                             .arg(Arg::with_name("delimiter")
                                 .short("d")
@@ -293,15 +560,27 @@ fn do_main() -> Result<(), Error> {
                             )
                             .arg(Arg::with_name("latitude")
                                 .long("latitude")
-                                .help("Sets the column number that contains the latitude. 1 based.")
+                                .help("Sets the column number that contains the latitude. 1 based. If neither this nor '--latitude-col' is given, it is looked up in the header (lat, latitude, y).")
                                 .takes_value(true)
-                                .required(true)
+                                .conflicts_with("latitude-col")
                             )
                             .arg(Arg::with_name("longitude")
                                 .long("longitude")
-                                .help("Sets the column number that contains the longitude. 1 based.")
+                                .help("Sets the column number that contains the longitude. 1 based. If neither this nor '--longitude-col' is given, it is looked up in the header (lon, lng, long, longitude, x).")
                                 .takes_value(true)
-                                .required(true)
+                                .conflicts_with("longitude-col")
+                            )
+                            .arg(Arg::with_name("latitude-col")
+                                .long("latitude-col")
+                                .help("Sets the name of the header column that contains the latitude")
+                                .takes_value(true)
+                                .conflicts_with("no-header")
+                            )
+                            .arg(Arg::with_name("longitude-col")
+                                .long("longitude-col")
+                                .help("Sets the name of the header column that contains the longitude")
+                                .takes_value(true)
+                                .conflicts_with("no-header")
                             )
                             .arg(Arg::with_name("no-header")
                                  .long("no-header")
@@ -314,144 +593,310 @@ fn do_main() -> Result<(), Error> {
                                 .long("properties")
                                 .short("p")
                             )
+                            .arg(Arg::with_name("match")
+                                .long("match")
+                                .help("Which of the features containing a point are joined: the first found, all of them, the smallest or the largest")
+                                .takes_value(true)
+                                .possible_values(&file_processor::MatchMode::VALUES)
+                                .default_value("first")
+                            )
+                            .arg(Arg::with_name("match-separator")
+                                .long("match-separator")
+                                .help("With '--match all', join the property values of every match with this separator instead of writing one row per match")
+                                .takes_value(true)
+                                .required(false)
+                            )
+                            .arg(Arg::with_name("max-distance")
+                                .long("max-distance")
+                                .help("When a point is not inside any feature, join the nearest one within this distance (in meters). Adds the 'match_type' and 'distance_m' columns.")
+                                .takes_value(true)
+                                .required(false)
+                            )
+                            .arg(Arg::with_name("threads")
+                                .short("t")
+                                .long("threads")
+                                .help("Number of threads doing the lookups. 0 uses every core.")
+                                .takes_value(true)
+                                .default_value("1")
+                            )
+                            .arg(Arg::with_name("missing-property")
+                                .long("missing-property")
+                                .help("What to do when a matched feature does not have a requested property: leave it empty, write the whole record as an error (even when its other matches have the property) or stop")
+                                .takes_value(true)
+                                .possible_values(&file_processor::MissingPropertyPolicy::VALUES)
+                                .default_value("error")
+                            )
+                            .arg(Arg::with_name("null-value")
+                                .long("null-value")
+                                .help("Text written for null properties")
+                                .takes_value(true)
+                                .default_value("")
+                            )
+                            .arg(Arg::with_name("nested-values")
+                                .long("nested-values")
+                                .help("How array and object properties are written: as JSON or empty")
+                                .takes_value(true)
+                                .possible_values(&file_processor::NestedValuePolicy::VALUES)
+                                .default_value("json")
+                            )
+                            .arg(Arg::with_name("aggregate")
+                                .long("aggregate")
+                                .help("Instead of the records, writes a row per feature of the index (also the ones without points) with the properties, the number of points in it and the sum, mean, min and max of the '--aggregate-columns'. As CSV by default, or as GeoJSON features with their geometry.")
+                            )
+                            .arg(Arg::with_name("aggregate-columns")
+                                .long("aggregate-columns")
+                                .help("Numeric input columns summarized with '--aggregate' (JSON pointers for JSON records)")
+                                .takes_value(true)
+                                .multiple(true)
+                                .use_delimiter(true)
+                                .requires("aggregate")
+                            )
+                            .arg(Arg::with_name("rejects")
+                                .long("rejects")
+                                .help("Writes the rows which could not be joined to this file instead of the output, in the output format with a 'reason' column: INVALID_COORDINATES, COORDINATES_NOT_FOUND (no feature at the point), or MISSING_PROPERTY with '--missing-property error'. Compressed like the output by its extension.")
+                                .takes_value(true)
+                                .conflicts_with_all(&["drop-unmatched", "aggregate"])
+                            )
+                            .arg(Arg::with_name("drop-unmatched")
+                                .long("drop-unmatched")
+                                .help("Leaves out the rows which could not be joined, so the output has only the matched ones")
+                                .conflicts_with("aggregate")
+                            )
+                            .arg(Arg::with_name("partition-by")
+                                .long("partition-by")
+                                .help("Writes a file per value of this property of the matched feature (it doesn't need to be joined) in '--output-dir', named by the value (with a '-2', '-3'... suffix when values only differ in case or in characters not allowed in file names). The rows which could not be joined go to '_unmatched', and the ones of features without the property to '_empty'.")
+                                .takes_value(true)
+                                .requires("output-dir")
+                                .conflicts_with_all(&["output", "aggregate"])
+                            )
+                            .arg(Arg::with_name("output-dir")
+                                .long("output-dir")
+                                .help("Directory of the '--partition-by' files. Created when missing.")
+                                .takes_value(true)
+                                .requires("partition-by")
+                            )
+                            .arg(Arg::with_name("max-open-files")
+                                .long("max-open-files")
+                                .help("Partition files open at once. The one written the longest ago is closed (and reopened when needed) to write to another.")
+                                .takes_value(true)
+                                .default_value("64")
+                            )
+                            .arg(Arg::with_name("report")
+                                .long("report")
+                                .help("Writes a JSON summary of the run to this file: the rows read, matched and not joined (by reason), the bounding box of the points, the features with the most points, and the time spent loading the index and joining.")
+                                .takes_value(true)
+                            )
+                            .arg(Arg::with_name("report-top-features")
+                                .long("report-top-features")
+                                .help("Features with the most points in the '--report'")
+                                .takes_value(true)
+                                .default_value("10")
+                            )
+                            .arg(Arg::with_name("output-format")
+                                .long("output-format")
+                                .help("How the records are written: delimited text, a GeoJSON FeatureCollection, one GeoJSON feature per line (GeoJSONSeq), one JSON object per line or Apache Parquet (of Parquet input, with the joined columns as text). The GeoJSON features of the rows are the points, with every column as a property. By default, in the format of the input (JSON and Parquet records can only be written in their own format).")
+                                .takes_value(true)
+                                .possible_values(&output_writer::OutputFormat::VALUES)
+                            )
                     )
                     .get_matches();
 
     if let Some(generate_matches) = matches.subcommand_matches("generate_index") {
+        let (paths, format) = match generate_matches.values_of("csv") {
+            Some(csv_paths) => (
+                expand_paths(csv_paths)?,
+                geo_finder::SourceFormat::Csv {
+                    delimiter: delimiter(generate_matches, "delimiter"),
+                    geometry_column: generate_matches.value_of("geometry-col").unwrap_or_default(),
+                },
+            ),
+            None => (
+                expand_paths(generate_matches.values_of("geojson").unwrap())?,
+                geo_finder::SourceFormat::Features { layer: generate_matches.value_of("layer") },
+            ),
+        };
         return create_polygons_geo_index(
-            generate_matches.value_of("output").unwrap_or_default(),
-            generate_matches.value_of("geojson").unwrap_or_default(),
+            path::Path::new(generate_matches.value_of("output").unwrap_or_default()),
+            &paths,
+            &format,
+            value_t!(generate_matches, "layout", geo_finder::IndexLayout).unwrap_or_else(|e| e.exit()),
+            generate_matches.is_present("tag-source"),
             generate_matches.is_present("force"),
         );
     }
 
+    if let Some(inspect_matches) = matches.subcommand_matches("inspect") {
+        return inspect_index(
+            path::Path::new(inspect_matches.value_of("index").unwrap_or_default()),
+            value_t!(inspect_matches, "samples", usize).unwrap_or_else(|e| e.exit()),
+            inspect_matches.is_present("json"),
+        );
+    }
+
     if let Some(run_matches) = matches.subcommand_matches("run") {
         let properties: Vec<_> = run_matches.values_of("properties").unwrap().collect();
         let input_file_path = run_matches.value_of("input");
         let index_path = run_matches.value_of("index").unwrap_or_default();
 
-        // 1 based.
-        let latitude_idx = value_t!(run_matches, "latitude", usize).unwrap() - 1;
-        let longitude_idx = value_t!(run_matches, "longitude", usize).unwrap() - 1;
+        let latitude = column_selector(run_matches, "latitude", "latitude-col");
+        let longitude = column_selector(run_matches, "longitude", "longitude-col");
 
-        // Parse the delimiter. Should be exactly one character.
-        let delimiter = run_matches
-            .value_of("delimiter")
-            .unwrap_or_default()
-            .replace("\\t", "\t");
-        let char_delimiter: u8 = delimiter.as_bytes()[0];
+        let char_delimiter = delimiter(run_matches, "delimiter");
         info!("Using the following delimiter: {:?}", char_delimiter);
 
         let no_header = run_matches.is_present("no-header");
+        let match_mode = value_t!(run_matches, "match", file_processor::MatchMode).unwrap_or_else(|e| e.exit());
+        let match_separator = run_matches.value_of("match-separator");
+        let max_distance = run_matches
+            .value_of("max-distance")
+            .map(|_| value_t!(run_matches, "max-distance", f64).unwrap_or_else(|e| e.exit()));
+        if let Some(max_distance) = max_distance.filter(|distance| !(distance.is_finite() && *distance >= 0.0)) {
+            clap::Error::value_validation_auto(format!(
+                "--max-distance must be a finite distance of 0 or more meters, not {}",
+                max_distance
+            ))
+            .exit();
+        }
+        let threads = value_t!(run_matches, "threads", usize).unwrap_or_else(|e| e.exit());
+        let missing_property =
+            value_t!(run_matches, "missing-property", file_processor::MissingPropertyPolicy).unwrap_or_else(|e| e.exit());
+        let null_value = run_matches.value_of("null-value").unwrap_or_default();
+        let nested_values =
+            value_t!(run_matches, "nested-values", file_processor::NestedValuePolicy).unwrap_or_else(|e| e.exit());
+        let input_format = value_t!(run_matches, "input-format", file_processor::InputFormat).unwrap_or_else(|e| e.exit());
+        let aggregate = run_matches.is_present("aggregate");
+        let output_format = match run_matches.value_of("output-format") {
+            Some(_) => value_t!(run_matches, "output-format", output_writer::OutputFormat).unwrap_or_else(|e| e.exit()),
+            None if aggregate => output_writer::OutputFormat::Csv,
+            None => input_format.default_output_format(),
+        };
+        // Found out before the join, instead of after it.
+        if aggregate {
+            output_writer::check_feature_rows_format(output_format)?;
+        }
+
+        let output_file_path = run_matches.value_of("output");
+        let output_compression = match run_matches.value_of("compress") {
+            Some(_) => value_t!(run_matches, "compress", compression::Compression).unwrap_or_else(|e| e.exit()),
+            None => output_file_path
+                .and_then(|path| compression::Compression::from_path(path::Path::new(path)))
+                .unwrap_or(compression::Compression::None),
+        };
+        if output_format == output_writer::OutputFormat::Parquet && output_compression != compression::Compression::None {
+            return Err(failure::format_err!(
+                "Parquet output can't be {} compressed, its columns are compressed by themselves",
+                output_compression.as_str()
+            ));
+        }
+
+        let partition = run_matches.value_of("partition-by").map(|property| partition_writer::PartitionOptions {
+            property,
+            directory: path::Path::new(run_matches.value_of("output-dir").unwrap_or_default()),
+            compression: output_compression,
+            max_open_files: value_t!(run_matches, "max-open-files", usize).unwrap_or_else(|e| e.exit()),
+        });
+
+        let rejects_file_path = run_matches.value_of("rejects");
+        let rejects_compression = rejects_file_path
+            .and_then(|path| compression::Compression::from_path(path::Path::new(path)))
+            .unwrap_or(compression::Compression::None);
+        if output_format == output_writer::OutputFormat::Parquet && rejects_compression != compression::Compression::None {
+            return Err(failure::format_err!(
+                "Parquet rejects can't be {} compressed, its columns are compressed by themselves",
+                rejects_compression.as_str()
+            ));
+        }
+        let unmatched = match (rejects_file_path, run_matches.is_present("drop-unmatched")) {
+            (Some(_), _) => file_processor::UnmatchedPolicy::Reject,
+            (None, true) => file_processor::UnmatchedPolicy::Drop,
+            (None, false) => file_processor::UnmatchedPolicy::Keep,
+        };
 
         let stdin = io::stdin();
-        let (mut input_file, input_file_size): (Box<io::Read>, Option<u64>) = match input_file_path
+        let mut stdin_lock = stdin.lock();
+        let (input, input_file_size) = match input_file_path
         {
             Some(path) => {
                 let input_file = std::fs::File::open(path)?;
                 let file_size = input_file.metadata()?.len();
-                // let estimated_size = estimate_row_count(&mut input_file)?;
-                (Box::new(input_file), Some(file_size))
+                (file_processor::JoinInput::File(input_file), Some(file_size))
             }
             None => {
                 info!("Reading from stdin");
-                (Box::new(stdin.lock()), None)
+                (file_processor::JoinInput::Stream(&mut stdin_lock), None)
             }
         };
 
 
-        let output_file_path = run_matches.value_of("output");
-
-        let stdout = io::stdout();
-        let mut output_file: Box<io::Write> = match output_file_path
+        let output_file: Box<dyn io::Write + Send> = match output_file_path
         {
             Some(path) => {
                 info!("Writing to file {}.", path);
 
-                let output_file: Box<io::Write> = Box::new(std::fs::File::create(path)?);
-                Box::new(output_file)
+                Box::new(std::fs::File::create(path)?)
             }
+            None if partition.is_some() => Box::new(io::sink()),
             None => {
-                info!("Reading from stdin");
-                Box::new(stdout.lock())
+                info!("Writing to stdout");
+                Box::new(io::stdout())
             }
         };
+        if output_compression != compression::Compression::None {
+            info!("Writing {} compressed output", output_compression.as_str());
+        }
+        let output_file = match partition {
+            // Each partition file is compressed by itself.
+            Some(_) => compression::CompressedWriter::new(output_file, compression::Compression::None)?,
+            None => compression::CompressedWriter::new(output_file, output_compression)?,
+        };
 
+        let rejects_file = match rejects_file_path {
+            Some(path) => {
+                info!("Writing the rejected rows to file {}.", path);
+                Some(compression::CompressedWriter::new(std::fs::File::create(path)?, rejects_compression)?)
+            }
+            None => None,
+        };
 
+        let report_options = run_matches.value_of("report").map(|report_path| ReportOptions {
+            path: path::Path::new(report_path),
+            top_features: value_t!(run_matches, "report-top-features", usize).unwrap_or_else(|e| e.exit()),
+        });
+
+        let options = file_processor::JoinOptions {
+            delimiter: char_delimiter,
+            latitude,
+            longitude,
+            properties,
+            no_header,
+            match_mode,
+            match_separator,
+            max_distance,
+            threads,
+            missing_property,
+            null_value,
+            nested_values,
+            input_format,
+            latitude_pointer: run_matches.value_of("latitude-pointer"),
+            longitude_pointer: run_matches.value_of("longitude-pointer"),
+            output_format,
+            input_compression: input_file_path.and_then(|path| compression::Compression::from_path(path::Path::new(path))),
+            unmatched,
+            partition,
+            aggregate,
+            aggregate_columns: run_matches.values_of("aggregate-columns").map(Iterator::collect).unwrap_or_default(),
+        };
 
         return run_polygons_classifier(
                 path::Path::new(index_path),
-                input_file.as_mut(),
+                input,
                 input_file_size,
-                output_file.as_mut(),
-                char_delimiter,
-                latitude_idx,
-                longitude_idx,
-                properties,
-                no_header,
+                output_file,
+                rejects_file,
+                &options,
+                report_options.as_ref(),
             );
-
-        // if let Some(_) = run_matches.subcommand_matches("states") {
-        //     let index_directory_path = index_path;
-        //     return run_states_clasiffier(
-        //         path::Path::new(index_directory_path),
-        //         input_file.as_mut(),
-        //         input_file_size,
-        //         path::Path::new(output_file_path),
-        //         char_delimiter,
-        //         latitude_idx,
-        //         longitude_idx,
-        //     );
-        // }
-        // if let Some(single_state_matches) = run_matches.subcommand_matches("single_state") {
-        //     let index_directory_path = index_path;
-        //     let state_number = value_t!(single_state_matches, "state_code", i32).unwrap();
-
-        //     return run_state(
-        //         state_number,
-        //         path::Path::new(index_directory_path),
-        //         input_file.as_mut(),
-        //         input_file_size,
-        //         path::Path::new(output_file_path),
-        //         char_delimiter,
-        //         latitude_idx,
-        //         longitude_idx,
-        //         no_header
-        //     );
-        // } else
-        /*if let Some(_) = run_matches.subcommand_matches("singleton")*/
-        // {
-        //     return run_polygons_classifier(
-        //         path::Path::new(index_path),
-        //         input_file.as_mut(),
-        //         input_file_size,
-        //         path::Path::new(output_file_path),
-        //         char_delimiter,
-        //         latitude_idx,
-        //         longitude_idx,
-        //         no_header,
-        //     );
-        // }
-
-        // let stdout = io::stdout();
-        // let mut output_file: Box<io::Write> = match output_file_path {
-        //     Some(path) => {
-        //         info!("Outputting to {}", path);
-        //         Box::new(std::fs::File::create(path)?)
-        //     }
-        //     None => {
-        //         info!("Outputting to stdout");
-        //         Box::new(stdout.lock())
-        //     }
-        // };
-
-        // let mut props_names: Vec<&str> = Vec::new();
-        // if let Some(props) = run_matches.values_of("props") {
-        //     for prop_reference in props {
-        //         props_names.push(prop_reference);
-        //     }
-        // }
     }
 
-    return Ok(());
+    Ok(())
 }
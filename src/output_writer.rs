@@ -0,0 +1,673 @@
+use super::file_processor::{render_value, FileProcessorError, JoinOptions};
+use super::geo_finder::PropertyValue;
+use super::json_input::JsonRecord;
+use super::parquet_input::ParquetRecord;
+
+use arrow_array::builder::StringBuilder;
+use arrow_array::{ArrayRef, RecordBatch, UInt32Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use std::borrow::Cow;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// How the joined rows are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Delimited text, with the same delimiter as the input.
+    Csv,
+    /// A GeoJSON FeatureCollection: the input features, or a Point feature per row.
+    GeoJson,
+    /// The same features, one per line (newline-delimited GeoJSON).
+    GeoJsonSeq,
+    /// A JSON object per line, with a member per column.
+    NdJson,
+    /// Apache Parquet, with the joined columns as text.
+    Parquet,
+}
+
+impl OutputFormat {
+    pub const VALUES: [&'static str; 5] = ["csv", "geojson", "geojsonseq", "ndjson", "parquet"];
+
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "CSV",
+            OutputFormat::GeoJson | OutputFormat::GeoJsonSeq => "GeoJSON",
+            OutputFormat::NdJson => "NDJSON",
+            OutputFormat::Parquet => "Parquet",
+        }
+    }
+
+    /// Of the files written in this format. Tab delimited text is `tsv`.
+    pub fn extension(self, delimiter: u8) -> &'static str {
+        match self {
+            OutputFormat::Csv if delimiter == b'\t' => "tsv",
+            OutputFormat::Csv => "csv",
+            OutputFormat::GeoJson => "geojson",
+            OutputFormat::GeoJsonSeq => "geojsons",
+            OutputFormat::NdJson => "ndjson",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<OutputFormat, String> {
+        match value {
+            "csv" => Ok(OutputFormat::Csv),
+            "geojson" => Ok(OutputFormat::GeoJson),
+            "geojsonseq" => Ok(OutputFormat::GeoJsonSeq),
+            "ndjson" => Ok(OutputFormat::NdJson),
+            "parquet" => Ok(OutputFormat::Parquet),
+            other => Err(format!("Invalid output format: {}", other)),
+        }
+    }
+}
+
+/**
+ * Writes the joined records: the input ones with the joined columns.
+ */
+pub trait RecordWriter<R> {
+    /// The columns of the input, when it has a header, and the joined columns appended to every row.
+    fn write_header(
+        &mut self,
+        input_columns: Option<&csv::StringRecord>,
+        joined_columns: &[String],
+    ) -> Result<(), FileProcessorError>;
+
+    /// A joined record and the (latitude, longitude) of its point, when they are valid.
+    fn write_record(
+        &mut self,
+        record: &R,
+        coordinates: Option<(f64, f64)>,
+    ) -> Result<(), FileProcessorError>;
+
+    /// Writes whatever is pending. Nothing can be written afterwards.
+    fn finish(&mut self) -> Result<(), FileProcessorError>;
+}
+
+fn unsupported_output(input: &str, format: OutputFormat) -> FileProcessorError {
+    FileProcessorError::UnsupportedOutput(input.to_owned(), format.name().to_owned())
+}
+
+/**
+ * Writer of the rows of a delimited input. They can't be written as Parquet.
+ */
+pub fn create_record_writer<'w>(
+    format: OutputFormat,
+    delimiter: u8,
+    output: Box<dyn io::Write + Send + 'w>,
+) -> Result<Box<dyn RecordWriter<csv::StringRecord> + 'w>, FileProcessorError> {
+    match format {
+        OutputFormat::Csv => Ok(Box::new(CsvRecordWriter {
+            writer: csv::WriterBuilder::new().delimiter(delimiter).flexible(true).from_writer(output),
+        })),
+        OutputFormat::Parquet => Err(unsupported_output("CSV", format)),
+        _ => Ok(Box::new(ColumnsJsonWriter {
+            output: JsonOutput::new(format, output),
+            names: ColumnNames { input_columns: Vec::new(), joined_columns: Vec::new() },
+        })),
+    }
+}
+
+/**
+ * Writer of the records of a JSON input. They can't be written as CSV or Parquet.
+ */
+pub fn create_json_record_writer<'w>(
+    format: OutputFormat,
+    output: Box<dyn io::Write + Send + 'w>,
+) -> Result<Box<dyn RecordWriter<JsonRecord> + 'w>, FileProcessorError> {
+    match format {
+        OutputFormat::Csv | OutputFormat::Parquet => Err(unsupported_output("JSON", format)),
+        _ => Ok(Box::new(JsonRecordWriter { output: JsonOutput::new(format, output) })),
+    }
+}
+
+/**
+ * Writer of the rows of a Parquet input, with the columns of `input_schema` and the joined ones as text. Only as
+ * Parquet, in row groups of up to `row_group_size` rows.
+ */
+pub fn create_parquet_record_writer<'w>(
+    format: OutputFormat,
+    input_schema: &Schema,
+    joined_columns: &[String],
+    row_group_size: usize,
+    output: Box<dyn io::Write + Send + 'w>,
+) -> Result<Box<dyn RecordWriter<ParquetRecord> + 'w>, FileProcessorError> {
+    if format != OutputFormat::Parquet {
+        return Err(unsupported_output("Parquet", format));
+    }
+
+    let fields = input_schema
+        .fields()
+        .iter()
+        .cloned()
+        .chain(joined_columns.iter().map(|column| Arc::new(Field::new(column, DataType::Utf8, true))));
+    let schema = Arc::new(Schema::new_with_metadata(fields.collect::<Vec<_>>(), input_schema.metadata().clone()));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(row_group_size)
+        .build();
+    let writer = ArrowWriter::try_new(output, schema.clone(), Some(properties)).map_err(FileProcessorError::Parquet)?;
+
+    Ok(Box::new(ParquetRecordWriter {
+        writer: Some(writer),
+        schema,
+        batch: None,
+        rows: Vec::new(),
+        joined: joined_columns.iter().map(|_| StringBuilder::new()).collect(),
+    }))
+}
+
+/// Fails for the formats `write_feature_rows` can't write, so it is found out before reading the input.
+pub fn check_feature_rows_format(format: OutputFormat) -> Result<(), FileProcessorError> {
+    match format {
+        OutputFormat::Parquet => Err(unsupported_output("Aggregated", format)),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * Writes rows of values of the features of the index (like their aggregates) named by `columns`: as delimited
+ * text, or as features with their geometry (objects without it for NDJSON). Not as Parquet. The geometry of a
+ * row is only built when written.
+ */
+pub fn write_feature_rows<I, G>(
+    format: OutputFormat,
+    options: &JoinOptions,
+    columns: &[String],
+    rows: I,
+    output: &mut (dyn io::Write + Send),
+) -> Result<(), FileProcessorError>
+where
+    I: Iterator<Item = (G, Vec<PropertyValue>)>,
+    G: FnOnce() -> Result<geo_types::Geometry<f64>, FileProcessorError>,
+{
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new().delimiter(options.delimiter).from_writer(output);
+            writer.write_record(columns).map_err(FileProcessorError::Csv)?;
+            for (_, values) in rows {
+                for value in &values {
+                    writer.write_field(render_value(value, options).as_bytes()).map_err(FileProcessorError::Csv)?;
+                }
+                writer.write_record(None::<&[u8]>).map_err(FileProcessorError::Csv)?;
+            }
+            writer.flush().map_err(FileProcessorError::Io)
+        }
+        OutputFormat::Parquet => Err(unsupported_output("Aggregated", format)),
+        _ => {
+            let mut output = JsonOutput::new(format, output);
+            output.begin()?;
+            for (geometry, values) in rows {
+                let members = ValueMembers { columns, values: &values };
+                match output.layout {
+                    JsonLayout::ObjectPerLine => output.write(&members)?,
+                    _ => output.write(&Feature {
+                        feature_type: "Feature",
+                        geometry: geojson::Geometry::new(geojson::Value::from(&geometry()?)),
+                        properties: members,
+                    })?,
+                }
+            }
+            output.finish()
+        }
+    }
+}
+
+struct CsvRecordWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> RecordWriter<csv::StringRecord> for CsvRecordWriter<W> {
+    fn write_header(
+        &mut self,
+        input_columns: Option<&csv::StringRecord>,
+        joined_columns: &[String],
+    ) -> Result<(), FileProcessorError> {
+        // Without a header in the input, the output does not have one either.
+        if let Some(input_columns) = input_columns {
+            let header = input_columns.iter().chain(joined_columns.iter().map(String::as_str));
+            self.writer.write_record(header).map_err(FileProcessorError::Csv)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        record: &csv::StringRecord,
+        _coordinates: Option<(f64, f64)>,
+    ) -> Result<(), FileProcessorError> {
+        self.writer.write_record(record).map_err(FileProcessorError::Csv)
+    }
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        self.writer.flush().map_err(FileProcessorError::Io)
+    }
+}
+
+/// How the JSON values are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonLayout {
+    /// Features, in a FeatureCollection.
+    Collection,
+    /// Features, one per line.
+    FeaturePerLine,
+    /// Objects, one per line.
+    ObjectPerLine,
+}
+
+/// The JSON values written, laid out for the output format.
+struct JsonOutput<W: io::Write> {
+    writer: io::BufWriter<W>,
+    layout: JsonLayout,
+    written: usize,
+}
+
+impl<W: io::Write> JsonOutput<W> {
+    fn new(format: OutputFormat, output: W) -> JsonOutput<W> {
+        let layout = match format {
+            OutputFormat::GeoJson => JsonLayout::Collection,
+            OutputFormat::GeoJsonSeq => JsonLayout::FeaturePerLine,
+            _ => JsonLayout::ObjectPerLine,
+        };
+        JsonOutput { writer: io::BufWriter::new(output), layout, written: 0 }
+    }
+
+    fn begin(&mut self) -> Result<(), FileProcessorError> {
+        if self.layout == JsonLayout::Collection {
+            self.writer
+                .write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")
+                .map_err(FileProcessorError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn write<T: serde::Serialize>(&mut self, value: &T) -> Result<(), FileProcessorError> {
+        if self.layout == JsonLayout::Collection && self.written > 0 {
+            self.writer.write_all(b",\n").map_err(FileProcessorError::Io)?;
+        }
+        serde_json::to_writer(&mut self.writer, value).map_err(|err| FileProcessorError::Io(err.into()))?;
+        if self.layout != JsonLayout::Collection {
+            self.writer.write_all(b"\n").map_err(FileProcessorError::Io)?;
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        if self.layout == JsonLayout::Collection {
+            let end: &[u8] = match self.written {
+                0 => b"]}\n",
+                _ => b"\n]}\n",
+            };
+            self.writer.write_all(end).map_err(FileProcessorError::Io)?;
+        }
+        self.writer.flush().map_err(FileProcessorError::Io)
+    }
+}
+
+/// Column names, in the order of the row.
+struct ColumnNames {
+    input_columns: Vec<String>,
+    joined_columns: Vec<String>,
+}
+
+impl ColumnNames {
+    /// The name of each field of a row. Input columns missing from the header (or all of them, when the
+    /// input does not have one) are named `column_<n>`, 1 based.
+    fn of<'r>(&'r self, record: &'r csv::StringRecord) -> impl Iterator<Item = (Cow<'r, str>, &'r str)> {
+        let input_count = record.len().saturating_sub(self.joined_columns.len());
+        record.iter().enumerate().map(move |(index, value)| {
+            let name = match index.checked_sub(input_count) {
+                Some(joined_index) => Cow::Borrowed(self.joined_columns[joined_index].as_str()),
+                None => match self.input_columns.get(index) {
+                    Some(name) => Cow::Borrowed(name.as_str()),
+                    None => Cow::Owned(format!("column_{}", index + 1)),
+                },
+            };
+            (name, value)
+        })
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PointGeometry {
+    #[serde(rename = "type")]
+    geometry_type: &'static str,
+    coordinates: [f64; 2],
+}
+
+#[derive(serde::Serialize)]
+struct PointFeature<P> {
+    #[serde(rename = "type")]
+    feature_type: &'static str,
+    geometry: Option<PointGeometry>,
+    properties: P,
+}
+
+impl<P> PointFeature<P> {
+    fn new(coordinates: Option<(f64, f64)>, properties: P) -> PointFeature<P> {
+        PointFeature {
+            feature_type: "Feature",
+            geometry: coordinates.map(|(latitude, longitude)| PointGeometry {
+                geometry_type: "Point",
+                coordinates: [longitude, latitude],
+            }),
+            properties,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Feature<P> {
+    #[serde(rename = "type")]
+    feature_type: &'static str,
+    geometry: geojson::Geometry,
+    properties: P,
+}
+
+/// Values of the features, serialized in the order of the columns.
+struct ValueMembers<'r> {
+    columns: &'r [String],
+    values: &'r [PropertyValue],
+}
+
+impl<'r> serde::Serialize for ValueMembers<'r> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = self.values.iter().map(serde_json::Value::from);
+        serializer.collect_map(self.columns.iter().zip(values))
+    }
+}
+
+/// The fields of a row, serialized in the order of the columns.
+struct RowMembers<'r> {
+    names: &'r ColumnNames,
+    record: &'r csv::StringRecord,
+}
+
+impl<'r> serde::Serialize for RowMembers<'r> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.names.of(self.record))
+    }
+}
+
+/// The rows of a delimited input as JSON: Point features, or objects with a member per column.
+struct ColumnsJsonWriter<W: io::Write> {
+    output: JsonOutput<W>,
+    names: ColumnNames,
+}
+
+impl<W: io::Write> RecordWriter<csv::StringRecord> for ColumnsJsonWriter<W> {
+    fn write_header(
+        &mut self,
+        input_columns: Option<&csv::StringRecord>,
+        joined_columns: &[String],
+    ) -> Result<(), FileProcessorError> {
+        self.names = ColumnNames {
+            input_columns: input_columns.into_iter().flatten().map(String::from).collect(),
+            joined_columns: joined_columns.to_vec(),
+        };
+        self.output.begin()
+    }
+
+    fn write_record(
+        &mut self,
+        record: &csv::StringRecord,
+        coordinates: Option<(f64, f64)>,
+    ) -> Result<(), FileProcessorError> {
+        let members = RowMembers { names: &self.names, record };
+        match self.output.layout {
+            JsonLayout::ObjectPerLine => self.output.write(&members),
+            _ => self.output.write(&PointFeature::new(coordinates, members)),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        self.output.finish()
+    }
+}
+
+/// The records of a JSON input. Objects which are not features are the properties of a Point feature when
+/// features are written.
+struct JsonRecordWriter<W: io::Write> {
+    output: JsonOutput<W>,
+}
+
+impl<W: io::Write> RecordWriter<JsonRecord> for JsonRecordWriter<W> {
+    fn write_header(
+        &mut self,
+        _input_columns: Option<&csv::StringRecord>,
+        _joined_columns: &[String],
+    ) -> Result<(), FileProcessorError> {
+        self.output.begin()
+    }
+
+    fn write_record(&mut self, record: &JsonRecord, coordinates: Option<(f64, f64)>) -> Result<(), FileProcessorError> {
+        match (self.output.layout, record.is_feature) {
+            (JsonLayout::ObjectPerLine, _) | (_, true) => self.output.write(&record.object),
+            _ => self.output.write(&PointFeature::new(coordinates, &record.object)),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        self.output.finish()
+    }
+}
+
+/// The rows of a Parquet input. The rows of each input batch are written together, in a batch with the joined
+/// columns.
+struct ParquetRecordWriter<W: io::Write + Send> {
+    /// Until finished.
+    writer: Option<ArrowWriter<W>>,
+    schema: SchemaRef,
+    /// The input batch of the rows being written.
+    batch: Option<Arc<RecordBatch>>,
+    rows: Vec<u32>,
+    joined: Vec<StringBuilder>,
+}
+
+impl<W: io::Write + Send> ParquetRecordWriter<W> {
+    fn write_batch(&mut self) -> Result<(), FileProcessorError> {
+        let (batch, writer) = match (self.batch.take(), self.writer.as_mut()) {
+            (Some(batch), Some(writer)) => (batch, writer),
+            _ => return Ok(()),
+        };
+
+        let rows = UInt32Array::from(std::mem::take(&mut self.rows));
+        let mut columns = arrow_select::take::take_record_batch(&batch, &rows)
+            .map_err(FileProcessorError::Arrow)?
+            .columns()
+            .to_vec();
+        columns.extend(self.joined.iter_mut().map(|builder| Arc::new(builder.finish()) as ArrayRef));
+
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(FileProcessorError::Arrow)?;
+        writer.write(&batch).map_err(FileProcessorError::Parquet)
+    }
+}
+
+impl<W: io::Write + Send> RecordWriter<ParquetRecord> for ParquetRecordWriter<W> {
+    fn write_header(
+        &mut self,
+        _input_columns: Option<&csv::StringRecord>,
+        _joined_columns: &[String],
+    ) -> Result<(), FileProcessorError> {
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &ParquetRecord, _coordinates: Option<(f64, f64)>) -> Result<(), FileProcessorError> {
+        let same_batch = self.batch.as_ref().is_some_and(|batch| Arc::ptr_eq(batch, &record.batch));
+        if !same_batch {
+            self.write_batch()?;
+            self.batch = Some(record.batch.clone());
+        }
+
+        self.rows.push(record.row as u32);
+        for (builder, value) in self.joined.iter_mut().zip(&record.joined) {
+            builder.append_option(value.as_deref());
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), FileProcessorError> {
+        self.write_batch()?;
+        match self.writer.take() {
+            Some(writer) => {
+                let mut output = writer.into_inner().map_err(FileProcessorError::Parquet)?;
+                output.flush().map_err(FileProcessorError::Io)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fields of a joined row and its coordinates.
+    type Row<'a> = (&'a [&'a str], Option<(f64, f64)>);
+
+    fn write(
+        format: OutputFormat,
+        input_columns: Option<&[&str]>,
+        rows: &[Row],
+    ) -> String {
+        let mut output = Vec::new();
+        {
+            let mut writer = create_record_writer(format, b',', Box::new(&mut output)).unwrap();
+            let joined_columns = vec!["cve_ent".to_owned(), "status".to_owned(), "error_message".to_owned()];
+            let input_columns = input_columns.map(|columns| csv::StringRecord::from(columns.to_vec()));
+            writer.write_header(input_columns.as_ref(), &joined_columns).unwrap();
+            for (fields, coordinates) in rows {
+                writer.write_record(&csv::StringRecord::from(fields.to_vec()), *coordinates).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn it_should_only_build_the_geometries_of_the_features_written() {
+        use std::cell::Cell;
+
+        let options = JoinOptions { aggregate: true, ..Default::default() };
+        let columns = vec!["point_count".to_owned()];
+        let built = Cell::new(0);
+        let rows = || {
+            (0..3).map(|count| {
+                let geometry = || {
+                    built.set(built.get() + 1);
+                    Ok(geo_types::Geometry::Point(geo_types::Point::new(1.0, 2.0)))
+                };
+                (geometry, vec![PropertyValue::Integer(count)])
+            })
+        };
+
+        for format in &[OutputFormat::Csv, OutputFormat::NdJson] {
+            let mut output = Vec::new();
+            write_feature_rows(*format, &options, &columns, rows(), &mut output).unwrap();
+        }
+        assert_eq!(built.get(), 0);
+
+        let mut output = Vec::new();
+        write_feature_rows(OutputFormat::GeoJsonSeq, &options, &columns, rows(), &mut output).unwrap();
+        assert_eq!(built.get(), 3);
+
+        match write_feature_rows(OutputFormat::Parquet, &options, &columns, rows(), &mut Vec::new()) {
+            Err(FileProcessorError::UnsupportedOutput(_, format)) => assert_eq!(format, "Parquet"),
+            _ => panic!("Wrong Error"),
+        }
+        assert!(check_feature_rows_format(OutputFormat::Parquet).is_err());
+        assert!(check_feature_rows_format(OutputFormat::GeoJson).is_ok());
+    }
+
+    #[test]
+    fn it_should_write_a_feature_collection_of_points() {
+        let output = write(
+            OutputFormat::GeoJson,
+            Some(&["id", "lat", "lon"]),
+            &[
+                (&["1", "19.43", "-99.13", "09", "success", ""], Some((19.43, -99.13))),
+                (&["2", "", "-99.13", "", "error", "INVALID_COORDINATES"], None),
+            ],
+        );
+
+        let collection: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(collection["type"], "FeatureCollection");
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["geometry"]["coordinates"], serde_json::json!([-99.13, 19.43]));
+        assert_eq!(
+            features[0]["properties"],
+            serde_json::json!({
+                "id": "1", "lat": "19.43", "lon": "-99.13", "cve_ent": "09", "status": "success", "error_message": ""
+            })
+        );
+        assert!(features[1]["geometry"].is_null());
+        assert_eq!(features[1]["properties"]["status"], "error");
+        // In the order of the columns.
+        assert!(output.contains(r#""properties":{"id":"1","lat":"19.43","lon":"-99.13","cve_ent":"09","#));
+    }
+
+    #[test]
+    fn it_should_write_a_feature_per_line_naming_the_columns_without_header() {
+        let output = write(
+            OutputFormat::GeoJsonSeq,
+            None,
+            &[
+                (&["19.43", "-99.13", "09", "success", ""], Some((19.43, -99.13))),
+                (&["20.1", "-98.7", "extra", "13", "success", ""], Some((20.1, -98.7))),
+            ],
+        );
+
+        let features: Vec<serde_json::Value> =
+            output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["properties"]["column_1"], "19.43");
+        assert_eq!(features[0]["properties"]["cve_ent"], "09");
+        assert_eq!(features[1]["properties"]["column_3"], "extra");
+        assert_eq!(features[1]["properties"]["cve_ent"], "13");
+    }
+
+    #[test]
+    fn it_should_write_an_empty_feature_collection() {
+        let output = write(OutputFormat::GeoJson, Some(&["id", "lat", "lon"]), &[]);
+
+        let collection: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(collection["features"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn it_should_write_json_records_as_point_features_unless_they_are_features() {
+        let object = |json: &str| match serde_json::from_str(json).unwrap() {
+            serde_json::Value::Object(object) => JsonRecord::new(object),
+            _ => panic!("Not an object"),
+        };
+        let mut output = Vec::new();
+        {
+            let mut writer = create_json_record_writer(OutputFormat::GeoJsonSeq, Box::new(&mut output)).unwrap();
+            writer.write_header(None, &[]).unwrap();
+            writer.write_record(&object(r#"{"id": 1, "lat": 19.43, "lon": -99.13}"#), Some((19.43, -99.13))).unwrap();
+            writer.write_record(&object(r#"{"type": "Feature", "geometry": null, "properties": {"id": 2}}"#), None).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let features: Vec<serde_json::Value> =
+            String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(features[0]["geometry"]["coordinates"], serde_json::json!([-99.13, 19.43]));
+        assert_eq!(features[0]["properties"]["id"], 1);
+        assert_eq!(features[1], serde_json::json!({"type": "Feature", "geometry": null, "properties": {"id": 2}}));
+        match create_json_record_writer(OutputFormat::Csv, Box::new(Vec::new())) {
+            Err(FileProcessorError::UnsupportedOutput(input, output)) => assert_eq!((input.as_str(), output.as_str()), ("JSON", "CSV")),
+            _ => panic!("Wrong Error"),
+        }
+    }
+}
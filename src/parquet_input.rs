@@ -0,0 +1,103 @@
+use super::file_processor::{render_value, FileProcessorError, InputRecord, JoinOptions, JoinRow, JoinedValue};
+use super::geo_finder;
+
+use arrow_array::{Array, Float64Array, RecordBatch};
+use arrow_schema::{DataType, Schema};
+
+use std::sync::Arc;
+
+/**
+ * A row of a Parquet input. The batch is shared by its rows, which are written back in the same batch with the
+ * joined columns.
+ */
+#[derive(Debug, Clone)]
+pub struct ParquetRecord {
+    pub batch: Arc<RecordBatch>,
+    pub row: usize,
+    /// As text, null when nothing is joined.
+    pub joined: Vec<Option<String>>,
+}
+
+impl JoinRow for ParquetRecord {
+    fn push_joined(&mut self, _column: &str, value: JoinedValue, options: &JoinOptions) {
+        let value = match value {
+            JoinedValue::Empty | JoinedValue::Property(geo_finder::PropertyValue::Null) => None,
+            JoinedValue::Text(text) => Some(text.into_owned()),
+            JoinedValue::Property(value) => Some(render_value(value, options).into_owned()),
+            JoinedValue::Distance(distance) => Some(format!("{:.1}", distance)),
+        };
+        self.joined.push(value);
+    }
+}
+
+/**
+ * The column names, to find the coordinates like in a delimited input.
+ */
+pub fn header(schema: &Schema) -> csv::StringRecord {
+    schema.fields().iter().map(|field| field.name().as_str()).collect()
+}
+
+/**
+ * The rows of a batch, each `record_size` bytes. The coordinate and aggregated (`value_idxs`) columns can be
+ * numbers or numeric text.
+ */
+pub fn records(
+    batch: RecordBatch,
+    latitude_idx: usize,
+    longitude_idx: usize,
+    value_idxs: &[usize],
+    record_size: u64,
+) -> Result<Vec<InputRecord<ParquetRecord>>, FileProcessorError> {
+    let numbers = |idx: usize| -> Result<Float64Array, FileProcessorError> {
+        let column = batch.columns().get(idx).ok_or_else(|| {
+            FileProcessorError::ColumnNotFound(format!("number {}", idx + 1), format!("{:?}", header(&batch.schema())))
+        })?;
+        // Text which is not a number is null.
+        let column = arrow_cast::cast(column, &DataType::Float64).map_err(FileProcessorError::Arrow)?;
+        Ok(column.as_any().downcast_ref::<Float64Array>().unwrap().clone())
+    };
+    let latitudes = numbers(latitude_idx)?;
+    let longitudes = numbers(longitude_idx)?;
+    let values = value_idxs.iter().map(|idx| numbers(*idx)).collect::<Result<Vec<_>, _>>()?;
+    let number = |column: &Float64Array, row: usize| column.is_valid(row).then(|| column.value(row));
+
+    let batch = Arc::new(batch);
+    Ok((0..batch.num_rows())
+        .map(|row| InputRecord {
+            record: ParquetRecord { batch: batch.clone(), row, joined: Vec::new() },
+            coordinates: (number(&latitudes, row), number(&longitudes, row)),
+            size: record_size,
+            values: values.iter().map(|column| number(column, row)).collect(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Int32Array, StringArray};
+    use arrow_schema::Field;
+
+    #[test]
+    fn it_should_read_numeric_and_text_coordinates() {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("lat", DataType::Utf8, true),
+            Field::new("lon", DataType::Float64, true),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(vec![1, 2, 3])),
+            Arc::new(StringArray::from(vec![Some("19.43"), Some("north"), None])),
+            Arc::new(Float64Array::from(vec![Some(-99.13), Some(-99.0), Some(-98.0)])),
+        ];
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+
+        let records = records(batch, 1, 2, &[0], 10).unwrap();
+
+        assert_eq!(header(&records[0].record.batch.schema()), csv::StringRecord::from(vec!["id", "lat", "lon"]));
+        let coordinates: Vec<_> = records.iter().map(|input| input.coordinates).collect();
+        assert_eq!(coordinates, vec![(Some(19.43), Some(-99.13)), (None, Some(-99.0)), (None, Some(-98.0))]);
+        assert_eq!(records[2].record.row, 2);
+        assert_eq!(records[1].values, vec![Some(2.0)]);
+    }
+}
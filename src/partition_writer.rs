@@ -0,0 +1,284 @@
+use super::compression::{CompressedWriter, Compression};
+use super::file_processor::{FileProcessorError, JoinOptions};
+use super::output_writer::RecordWriter;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::sync::{Arc, Mutex};
+
+/// File of the rows without a matched feature.
+pub const UNMATCHED_NAME: &str = "_unmatched";
+/// File of the rows whose feature does not have a value for the property.
+pub const EMPTY_NAME: &str = "_empty";
+
+/// How the output is split in a file per value of a property of the matched features.
+#[derive(Debug, Clone)]
+pub struct PartitionOptions<'a> {
+    /// Of the matched feature (the first one, with several matches in a row). It does not need to be joined.
+    pub property: &'a str,
+    /// Where the files are written, named by the value.
+    pub directory: &'a path::Path,
+    pub compression: Compression,
+    /// Files open at once. The one written the longest ago is closed to write to another.
+    pub max_open_files: usize,
+}
+
+/// Makes a record writer for an output, writing the header with the joined columns.
+pub type MakeRecordWriter<'w, R> = dyn Fn(Box<dyn io::Write + Send + 'w>, &[String]) -> Result<Box<dyn RecordWriter<R> + 'w>, FileProcessorError>
+    + 'w;
+
+/**
+ * The name of the file of a value, without extension. Anything but letters, digits, `-`, `_` and `.` is replaced
+ * with `_`, so the files are always in the directory. Names starting with `_` or `.` get another `_`, so they are
+ * not hidden nor taken for `UNMATCHED_NAME` or `EMPTY_NAME`.
+ */
+fn file_stem(value: &str) -> String {
+    if value.is_empty() {
+        return EMPTY_NAME.to_owned();
+    }
+
+    let stem: String = value
+        .chars()
+        .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    match stem.starts_with('.') || stem.starts_with('_') {
+        true => format!("_{}", stem),
+        false => stem,
+    }
+}
+
+/**
+ * `stem`, or `stem-2`, `stem-3`... when another value already has its file (ignoring case, as some file systems
+ * do). `used` are the stems taken, in lowercase.
+ */
+fn unique_stem(used: &mut HashSet<String>, stem: String) -> String {
+    let mut unique = stem.clone();
+    let mut number = 1;
+    while !used.insert(unique.to_lowercase()) {
+        number += 1;
+        unique = format!("{}-{}", stem, number);
+    }
+    unique
+}
+
+/**
+ * The files of the partitions, up to `max_open` of them open at once. A compressed file is finished when closed,
+ * and another compressed stream is appended to it when opened again (read as one, like `cat a.gz b.gz`).
+ */
+struct OpenFiles {
+    compression: Compression,
+    max_open: usize,
+    paths: Vec<path::PathBuf>,
+    /// Index in `paths` and writer of the open files. The one written the longest ago first.
+    open: VecDeque<(usize, CompressedWriter<io::BufWriter<fs::File>>)>,
+}
+
+impl OpenFiles {
+    /// Creates the file, empty. It is not open until written.
+    fn add(&mut self, path: path::PathBuf) -> io::Result<usize> {
+        fs::File::create(&path)?;
+        self.paths.push(path);
+        Ok(self.paths.len() - 1)
+    }
+
+    fn writer(&mut self, file: usize) -> io::Result<&mut CompressedWriter<io::BufWriter<fs::File>>> {
+        match self.open.iter().position(|(open, _)| *open == file) {
+            Some(position) => {
+                let entry = self.open.remove(position).unwrap();
+                self.open.push_back(entry);
+            }
+            None => {
+                if self.open.len() >= self.max_open {
+                    if let Some((_, writer)) = self.open.pop_front() {
+                        writer.finish()?;
+                    }
+                }
+                let output = fs::OpenOptions::new().append(true).open(&self.paths[file])?;
+                let writer = CompressedWriter::new(io::BufWriter::new(output), self.compression)?;
+                self.open.push_back((file, writer));
+            }
+        }
+        Ok(&mut self.open.back_mut().unwrap().1)
+    }
+
+    fn flush(&mut self, file: usize) -> io::Result<()> {
+        match self.open.iter_mut().find(|(open, _)| *open == file) {
+            Some((_, writer)) => writer.flush(),
+            // Everything was written when closed.
+            None => Ok(()),
+        }
+    }
+
+    fn close_all(&mut self) -> io::Result<()> {
+        while let Some((_, writer)) = self.open.pop_front() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output of a record writer: one of the `OpenFiles`.
+struct PartitionFile {
+    files: Arc<Mutex<OpenFiles>>,
+    file: usize,
+}
+
+impl io::Write for PartitionFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.files.lock().unwrap().writer(self.file)?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.lock().unwrap().flush(self.file)
+    }
+}
+
+/**
+ * Writes the joined records to a file per value of the partition property, and the unmatched ones to their own
+ * file. Every file has the header.
+ */
+pub struct PartitionWriter<'w, R> {
+    directory: path::PathBuf,
+    extension: String,
+    joined_columns: Vec<String>,
+    files: Arc<Mutex<OpenFiles>>,
+    /// By value of the partition, none for the unmatched records.
+    writers: HashMap<Option<String>, Box<dyn RecordWriter<R> + 'w>>,
+    /// The names of the files, in lowercase.
+    stems: HashSet<String>,
+    make_writer: Box<MakeRecordWriter<'w, R>>,
+}
+
+impl<'w, R> PartitionWriter<'w, R> {
+    /// Creates the directory. The files are created as the values are found.
+    pub fn new(
+        partition: &PartitionOptions,
+        options: &JoinOptions,
+        joined_columns: Vec<String>,
+        make_writer: Box<MakeRecordWriter<'w, R>>,
+    ) -> Result<PartitionWriter<'w, R>, FileProcessorError> {
+        fs::create_dir_all(partition.directory).map_err(FileProcessorError::Io)?;
+
+        let mut extension = options.output_format.extension(options.delimiter).to_owned();
+        if let Some(compression) = partition.compression.extension() {
+            extension = format!("{}.{}", extension, compression);
+        }
+
+        let files = OpenFiles {
+            compression: partition.compression,
+            max_open: partition.max_open_files.max(1),
+            paths: Vec::new(),
+            open: VecDeque::new(),
+        };
+        Ok(PartitionWriter {
+            directory: partition.directory.to_owned(),
+            extension,
+            joined_columns,
+            files: Arc::new(Mutex::new(files)),
+            writers: HashMap::new(),
+            stems: HashSet::new(),
+            make_writer,
+        })
+    }
+
+    /// A record with the `partition` value of its feature, none when it is not matched.
+    pub fn write_record(
+        &mut self,
+        partition: Option<&str>,
+        record: &R,
+        coordinates: Option<(f64, f64)>,
+    ) -> Result<(), FileProcessorError> {
+        let key = partition.map(str::to_owned);
+        if !self.writers.contains_key(&key) {
+            let stem = match partition {
+                Some(value) => file_stem(value),
+                None => UNMATCHED_NAME.to_owned(),
+            };
+            let stem = unique_stem(&mut self.stems, stem);
+            let path = self.directory.join(format!("{}.{}", stem, self.extension));
+            let file = self.files.lock().unwrap().add(path).map_err(FileProcessorError::Io)?;
+            let output = PartitionFile { files: self.files.clone(), file };
+            let writer = (self.make_writer)(Box::new(output), &self.joined_columns)?;
+            self.writers.insert(key.clone(), writer);
+        }
+        self.writers.get_mut(&key).unwrap().write_record(record, coordinates)
+    }
+
+    /// Finishes every file, returning the first error.
+    pub fn finish(&mut self) -> Result<(), FileProcessorError> {
+        let mut result = Ok(());
+        for (_, mut writer) in self.writers.drain() {
+            let finished = writer.finish();
+            // Anything still buffered by the writer is written when dropped, before the files are closed.
+            drop(writer);
+            result = result.and(finished);
+        }
+        let closed = self.files.lock().unwrap().close_all().map_err(FileProcessorError::Io);
+        result.and(closed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compression::decompress;
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn it_should_name_the_files_by_the_values() {
+        assert_eq!(file_stem("09"), "09");
+        assert_eq!(file_stem("Ciudad de México"), "Ciudad_de_México");
+        assert_eq!(file_stem("../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(file_stem(UNMATCHED_NAME), "__unmatched");
+        assert_eq!(file_stem(""), EMPTY_NAME);
+    }
+
+    #[test]
+    fn it_should_give_a_file_of_its_own_to_the_values_with_the_same_name() {
+        let mut used = HashSet::new();
+        let stems: Vec<_> = ["x/y", "x_y", "X_Y", "x_y-2", "x y"]
+            .iter()
+            .map(|value| unique_stem(&mut used, file_stem(value)))
+            .collect();
+
+        assert_eq!(stems, vec!["x_y", "x_y-2", "X_Y-3", "x_y-2-2", "x_y-4"]);
+    }
+
+    #[test]
+    fn it_should_reopen_the_files_closed_to_write_others() {
+        let directory = std::env::temp_dir().join(format!("fsj-partitions-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let files = OpenFiles { compression: Compression::Gzip, max_open: 1, paths: Vec::new(), open: VecDeque::new() };
+        let files = Arc::new(Mutex::new(files));
+
+        let mut outputs: Vec<PartitionFile> = ["a.csv.gz", "b.csv.gz"]
+            .iter()
+            .map(|name| {
+                let file = files.lock().unwrap().add(directory.join(name)).unwrap();
+                PartitionFile { files: files.clone(), file }
+            })
+            .collect();
+        for line in &["1\n", "2\n", "3\n"] {
+            for output in outputs.iter_mut() {
+                output.write_all(line.as_bytes()).unwrap();
+                assert_eq!(files.lock().unwrap().open.len(), 1);
+            }
+        }
+        files.lock().unwrap().close_all().unwrap();
+
+        for name in &["a.csv.gz", "b.csv.gz"] {
+            let file = io::BufReader::new(fs::File::open(directory.join(name)).unwrap());
+            let (_, mut reader) = decompress(file, None).unwrap();
+            let mut text = String::new();
+            reader.read_to_string(&mut text).unwrap();
+            assert_eq!(text, "1\n2\n3\n");
+        }
+        fs::remove_dir_all(&directory).ok();
+    }
+}